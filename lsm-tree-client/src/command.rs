@@ -1,37 +1,89 @@
 use std::{
+    fmt::Display,
     fs::{self, metadata},
-    io::{Read, Write},
+    io::{BufReader, Read, Write},
+    ops::Bound,
     path::PathBuf,
+    str::FromStr,
 };
 
 use bytes::BufMut;
 use relm4::tokio::io;
 
+/// Sent while a response is still coming to have the server stop the command, whose response then
+/// ends with a cancelled error frame. Harmless if the response already ended.
+pub const CANCEL: u8 = 0x18;
+
+/// Opens a connection: this byte, the protocol version and the features wanted, the server
+/// answers "<version> <features>" with those both sides support
+pub const HANDSHAKE: u8 = 0x16;
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const FEATURE_BINARY_RESULTS: u32 = 1;
+pub const FEATURE_TOMBSTONES: u32 = 1 << 1;
+pub const FEATURE_CHANGE_FEED: u32 = 1 << 2;
+pub const CLIENT_FEATURES: u32 = FEATURE_BINARY_RESULTS | FEATURE_TOMBSTONES | FEATURE_CHANGE_FEED;
+
+/// Start and end of a range
+pub type Bounds = (Bound<i32>, Bound<i32>);
+
 #[derive(Clone, Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum Command {
     PUT { key: i32, val: i32 },
+    MERGE { key: i32, delta: i32 },
     GET { key: i32 },
     DELETE { key: i32 },
+    DELETE_RANGE { min_key: i32, max_key: i32 },
     LOAD { file: PathBuf },
-    RANGE { min_key: i32, max_key: i32 },
+    RANGE { bounds: Bounds, tombstones: bool },
+    RANGE_SEEK { key: i32 },
+    RANGE_PAGE { bounds: Bounds, limit: u32 },
+    RANGE_CONT { token: [u8; 9], limit: u32 },
+    EXISTS { key: i32 },
+    MAYBE { key: i32 },
+    COUNT { start: Bound<i32>, end: Bound<i32> },
     STATS,
+    STATS_JSON { histogram: bool },
+    DEBUG_TABLES,
+    FSCK,
+    SIZE,
+    SPLIT_POINTS { level: u8 },
+    BLOCK_INDEX { level: u8 },
+    LEVEL_DUMP { level: u8, limit: u32 },
+    INGEST { level: u8, merge: bool, dir: String },
+    HOT_RANGE { min_key: i32, max_key: i32 },
+    RESET,
+    VERBOSE_METRICS { enabled: bool },
+    SHOW_TOMBSTONES { enabled: bool },
+    RESET_COMPACTION_STATS,
+    SUBSCRIBE { from: u64 },
+    BINARY_RESULTS { enabled: bool },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum CommandType {
     PUT,
     GET,
     DELETE,
     RANGE,
+    LOAD,
+    STATS,
 }
 
 impl Command {
     pub fn to_type(&self) -> Option<CommandType> {
         Some(match self {
-            Self::DELETE { .. } => CommandType::DELETE,
-            Self::PUT { .. } => CommandType::PUT,
-            Self::GET { .. } => CommandType::GET,
-            Self::RANGE { .. } => CommandType::RANGE,
+            Self::DELETE { .. } | Self::DELETE_RANGE { .. } => CommandType::DELETE,
+            Self::PUT { .. } | Self::MERGE { .. } => CommandType::PUT,
+            Self::GET { .. } | Self::EXISTS { .. } | Self::MAYBE { .. } => CommandType::GET,
+            Self::RANGE { .. }
+            | Self::RANGE_SEEK { .. }
+            | Self::RANGE_PAGE { .. }
+            | Self::RANGE_CONT { .. }
+            | Self::COUNT { .. } => CommandType::RANGE,
+            Self::LOAD { .. } => CommandType::LOAD,
+            Self::STATS | Self::STATS_JSON { .. } => CommandType::STATS,
             _ => return None,
         })
     }
@@ -45,6 +97,12 @@ impl Command {
                 slc.put_i32(*val);
                 writer.write_all(&buf)?;
             }
+            Self::MERGE { key, delta } => {
+                slc.put_u8(b'm');
+                slc.put_i32(*key);
+                slc.put_i32(*delta);
+                writer.write_all(&buf)?;
+            }
             Self::GET { key } => {
                 slc.put_u8(b'g');
                 slc.put_i32(*key);
@@ -55,6 +113,12 @@ impl Command {
                 slc.put_i32(*key);
                 writer.write_all(&buf[..5])?;
             }
+            Self::DELETE_RANGE { min_key, max_key } => {
+                slc.put_u8(b'x');
+                slc.put_i32(*min_key);
+                slc.put_i32(*max_key);
+                writer.write_all(&buf)?;
+            }
             Self::LOAD { file } => {
                 slc.put_u8(b'l');
 
@@ -63,56 +127,611 @@ impl Command {
 
                 slc.put_u64(kv_pairs);
                 writer.write_all(&buf)?;
-                std::io::copy(&mut fs::File::open(file).unwrap(), writer)?;
+
+                // LOAD files hold little-endian pairs, everything on the wire is big-endian
+                let mut file = BufReader::new(fs::File::open(file).unwrap());
+                let mut pair = [0_u8; 8];
+                for _ in 0..kv_pairs {
+                    file.read_exact(&mut pair)?;
+                    let key = i32::from_le_bytes(pair[..4].try_into().unwrap());
+                    let val = i32::from_le_bytes(pair[4..].try_into().unwrap());
+                    writer.write_all(&key.to_be_bytes())?;
+                    writer.write_all(&val.to_be_bytes())?;
+                }
+            }
+            Self::RANGE { bounds, tombstones } => {
+                writer.write_all(b"r")?;
+                let flags = if *tombstones { RANGE_TOMBSTONES } else { 0 };
+                write_bounds(writer, &bounds.0, &bounds.1, flags)?;
+            }
+            Self::RANGE_SEEK { key } => {
+                slc.put_u8(b'q');
+                slc.put_i32(*key);
+                writer.write_all(&buf[..5])?;
+            }
+            Self::RANGE_PAGE { bounds, limit } => {
+                writer.write_all(b"b")?;
+                write_bounds(writer, &bounds.0, &bounds.1, 0)?;
+                writer.write_all(&limit.to_be_bytes())?;
+            }
+            Self::RANGE_CONT { token, limit } => {
+                // the token's bytes as the server sent them in hex after NEXT
+                writer.write_all(b"y")?;
+                writer.write_all(token)?;
+                writer.write_all(&limit.to_be_bytes())?;
+            }
+            Self::EXISTS { key } => {
+                slc.put_u8(b'e');
+                slc.put_i32(*key);
+                writer.write_all(&buf[..5])?;
+            }
+            Self::MAYBE { key } => {
+                slc.put_u8(b'M');
+                slc.put_i32(*key);
+                writer.write_all(&buf[..5])?;
+            }
+            Self::COUNT { start, end } => {
+                writer.write_all(b"n")?;
+                write_bounds(writer, start, end, 0)?;
+            }
+            Self::STATS => {
+                slc.put_u8(b's');
+                writer.write_all(&buf[..1])?;
+            }
+            Self::STATS_JSON { histogram } => {
+                slc.put_u8(b'j');
+                slc.put_u8(*histogram as u8);
+                writer.write_all(&buf[..2])?;
+            }
+            Self::DEBUG_TABLES => {
+                slc.put_u8(b't');
+                writer.write_all(&buf[..1])?;
+            }
+            Self::FSCK => {
+                slc.put_u8(b'f');
+                writer.write_all(&buf[..1])?;
+            }
+            Self::SIZE => {
+                slc.put_u8(b'z');
+                writer.write_all(&buf[..1])?;
+            }
+            Self::SPLIT_POINTS { level } => {
+                slc.put_u8(b'k');
+                slc.put_u8(*level);
+                writer.write_all(&buf[..2])?;
+            }
+            Self::BLOCK_INDEX { level } => {
+                slc.put_u8(b'i');
+                slc.put_u8(*level);
+                writer.write_all(&buf[..2])?;
+            }
+            Self::LEVEL_DUMP { level, limit } => {
+                slc.put_u8(b'L');
+                slc.put_u8(*level);
+                slc.put_u32(*limit);
+                writer.write_all(&buf[..6])?;
+            }
+            Self::INGEST { level, merge, dir } => {
+                // the directory is read on the server, so it's sent as given
+                writer.write_all(&[b'I', *level, *merge as u8])?;
+                writer.write_all(&(dir.len() as u16).to_be_bytes())?;
+                writer.write_all(dir.as_bytes())?;
             }
-            Self::RANGE { min_key, max_key } => {
-                slc.put_u8(b'r');
+            Self::HOT_RANGE { min_key, max_key } => {
+                slc.put_u8(b'h');
                 slc.put_i32(*min_key);
                 slc.put_i32(*max_key);
                 writer.write_all(&buf)?;
             }
-            Self::STATS => {
-                slc.put_u8(b's');
+            Self::RESET => {
+                slc.put_u8(b'c');
                 writer.write_all(&buf[..1])?;
             }
+            Self::VERBOSE_METRICS { enabled } => {
+                slc.put_u8(b'v');
+                slc.put_u8(*enabled as u8);
+                writer.write_all(&buf[..2])?;
+            }
+            Self::SHOW_TOMBSTONES { enabled } => {
+                slc.put_u8(b'o');
+                slc.put_u8(*enabled as u8);
+                writer.write_all(&buf[..2])?;
+            }
+            Self::RESET_COMPACTION_STATS => {
+                slc.put_u8(b'w');
+                writer.write_all(&buf[..1])?;
+            }
+            Self::SUBSCRIBE { from } => {
+                slc.put_u8(b'u');
+                slc.put_u64(*from);
+                writer.write_all(&buf)?;
+            }
+            Self::BINARY_RESULTS { enabled } => {
+                slc.put_u8(b'a');
+                slc.put_u8(*enabled as u8);
+                writer.write_all(&buf[..2])?;
+            }
         }
         Ok(())
     }
 
-    pub fn from_input(input: &str) -> Option<Self> {
-        let mut split_iter = input.split(' ');
-        let tag = split_iter.next()?;
+    /// Verbs in any case, with arguments separated by any amount of whitespace
+    pub fn from_input(input: &str) -> Result<Self, ParseError> {
+        let mut args = input.split_whitespace();
+        let verb = args.next().ok_or(ParseError::Empty)?.to_ascii_lowercase();
 
-        match tag {
-            "p" => {
-                let key: i32 = split_iter.next()?.parse().ok()?;
-                let val: i32 = split_iter.next()?.parse().ok()?;
-                Some(Command::PUT { key, val })
-            }
-            "g" => {
-                let key: i32 = split_iter.next()?.parse().ok()?;
-                Some(Command::GET { key })
-            }
-            "d" => {
-                let key: i32 = split_iter.next()?.parse().ok()?;
-                Some(Command::DELETE { key })
-            }
+        let command = match verb.as_str() {
+            "p" => Command::PUT {
+                key: int_arg(&mut args, "key")?,
+                val: int_arg(&mut args, "value")?,
+            },
+            "m" => Command::MERGE {
+                key: int_arg(&mut args, "key")?,
+                delta: int_arg(&mut args, "delta")?,
+            },
+            "g" => Command::GET {
+                key: int_arg(&mut args, "key")?,
+            },
+            "d" => Command::DELETE {
+                key: int_arg(&mut args, "key")?,
+            },
+            "dr" => Command::DELETE_RANGE {
+                min_key: int_arg(&mut args, "min key")?,
+                max_key: int_arg(&mut args, "max key")?,
+            },
             "l" => {
-                let file: PathBuf = split_iter.next()?.parse().ok()?;
+                let file = PathBuf::from(next_arg(&mut args, "file")?);
 
                 if !file.is_file() {
-                    return None;
+                    return Err(ParseError::FileNotFound(file));
                 }
 
-                Some(Command::LOAD { file })
+                Command::LOAD { file }
+            }
+            "r" => Command::RANGE {
+                bounds: (
+                    bound_arg(&mut args, "min key", Bound::Included)?,
+                    bound_arg(&mut args, "max key", Bound::Excluded)?,
+                ),
+                tombstones: match args.next() {
+                    None => false,
+                    Some(arg) if arg.eq_ignore_ascii_case("deleted") => true,
+                    Some(other) => return Err(ParseError::UnexpectedArgument(other.to_string())),
+                },
+            },
+            "seek" => Command::RANGE_SEEK {
+                key: int_arg(&mut args, "key")?,
+            },
+            "rp" => Command::RANGE_PAGE {
+                bounds: (
+                    bound_arg(&mut args, "min key", Bound::Included)?,
+                    bound_arg(&mut args, "max key", Bound::Excluded)?,
+                ),
+                limit: int_arg(&mut args, "limit")?,
+            },
+            "more" => Command::RANGE_CONT {
+                token: token_arg(&mut args)?,
+                limit: int_arg(&mut args, "limit")?,
+            },
+            "exists" => Command::EXISTS {
+                key: int_arg(&mut args, "key")?,
+            },
+            "maybe" => Command::MAYBE {
+                key: int_arg(&mut args, "key")?,
+            },
+            "count" => Command::COUNT {
+                start: bound_arg(&mut args, "min key", Bound::Included)?,
+                end: bound_arg(&mut args, "max key", Bound::Excluded)?,
+            },
+            "s" => Command::STATS,
+            "stats-json" => Command::STATS_JSON {
+                histogram: match args.next() {
+                    None => false,
+                    Some(arg) if arg.eq_ignore_ascii_case("hist") => true,
+                    Some(other) => return Err(ParseError::UnexpectedArgument(other.to_string())),
+                },
+            },
+            "debug-tables" => Command::DEBUG_TABLES,
+            "fsck" => Command::FSCK,
+            "size" => Command::SIZE,
+            "splits" => Command::SPLIT_POINTS {
+                level: int_arg(&mut args, "level")?,
+            },
+            "index" => Command::BLOCK_INDEX {
+                level: int_arg(&mut args, "level")?,
+            },
+            "dump" => Command::LEVEL_DUMP {
+                level: int_arg(&mut args, "level")?,
+                limit: match args.clone().next() {
+                    None => DEFAULT_DUMP_LIMIT,
+                    Some(_) => int_arg(&mut args, "limit")?,
+                },
+            },
+            "ingest" => Command::INGEST {
+                level: int_arg(&mut args, "level")?,
+                dir: next_arg(&mut args, "directory")?.to_string(),
+                merge: match args.next() {
+                    None => false,
+                    Some(arg) if arg.eq_ignore_ascii_case("merge") => true,
+                    Some(other) => return Err(ParseError::UnexpectedArgument(other.to_string())),
+                },
+            },
+            "hot" => Command::HOT_RANGE {
+                min_key: int_arg(&mut args, "min key")?,
+                max_key: int_arg(&mut args, "max key")?,
+            },
+            "reset" => Command::RESET,
+            "reset-compaction-stats" => Command::RESET_COMPACTION_STATS,
+            "subscribe" => Command::SUBSCRIBE {
+                // 0 for the writes from here on
+                from: match args.clone().next() {
+                    None => 0,
+                    Some(_) => int_arg(&mut args, "sequence")?,
+                },
+            },
+            "verbose-metrics" => Command::VERBOSE_METRICS {
+                enabled: on_off_arg(&mut args)?,
+            },
+            "tombstones" => Command::SHOW_TOMBSTONES {
+                enabled: on_off_arg(&mut args)?,
+            },
+            "binary" => Command::BINARY_RESULTS {
+                enabled: on_off_arg(&mut args)?,
+            },
+            _ => return Err(ParseError::UnknownVerb(verb)),
+        };
+        Ok(command)
+    }
+}
+
+/// The commands among a workload's lines, with the type their latency is plotted under. Lines that
+/// don't parse, or whose commands aren't plotted, are counted in `skipped`.
+pub fn workload_commands<'a>(
+    lines: impl Iterator<Item = String> + 'a,
+    skipped: &'a mut usize,
+) -> impl Iterator<Item = (CommandType, Command)> + 'a {
+    lines.filter_map(move |line| {
+        let command = Command::from_input(&line).ok();
+        let typed = command.and_then(|c| Some((c.to_type()?, c)));
+        if typed.is_none() {
+            *skipped += 1;
+        }
+        typed
+    })
+}
+
+pub const VERBS: [&str; 30] = [
+    "p",
+    "m",
+    "g",
+    "d",
+    "dr",
+    "l",
+    "r",
+    "seek",
+    "rp",
+    "more",
+    "exists",
+    "maybe",
+    "count",
+    "s",
+    "stats-json",
+    "debug-tables",
+    "fsck",
+    "size",
+    "splits",
+    "index",
+    "dump",
+    "ingest",
+    "hot",
+    "reset",
+    "reset-compaction-stats",
+    "verbose-metrics",
+    "tombstones",
+    "subscribe",
+    "binary",
+    "help",
+];
+
+pub const HELP: &str = "\
+p <key> <value>        put
+m <key> <delta>        add delta onto the key's value
+g <key>                get
+d <key>                delete
+dr <min key> <max key> delete every key in min..=max
+l <file>               load a file of little-endian (key, value) pairs
+r <min key> <max key>  range over min..max, - for an open end, [5 10] or (5 10) for bound kinds, add deleted to list tombstones
+seek <key>             rest of the last r from key on
+rp <min> <max> <limit> first limit pairs of min..max, NEXT <token> if there are more
+more <token> <limit>   next limit pairs after the page that gave the token
+exists <key>           whether the key has a value
+maybe <key>            whether the key may have a value, from the bloom filters alone
+count <min> <max>      number of keys in min..max, - for an open end
+s                      stats
+stats-json [hist]      per-level summary without the dump, hist adds a key histogram
+debug-tables           list the tables of every level
+fsck                   check tables on disk
+size                   bytes on disk, entries in memory and levels in use
+splits <level>         table boundaries of a level
+index <level>          first and last key of every block, table by table
+dump <level> [limit]   commands stored in a level, tombstones included, 1000 by default
+ingest <level> <dir>   move the tables in a directory on the server into a level, add merge to merge overlapping ones
+hot <min> <max>        idle compaction gets to min..=max first
+reset                  delete everything (server needs --allow-reset)
+reset-compaction-stats zero the per-level compaction counters in stats-json
+verbose-metrics on|off blocks read by each read, sent along with its response
+tombstones on|off      g answers DELETED for deleted keys instead of nothing
+binary on|off          g values and range pairs come back in binary, printed the same
+subscribe [sequence]   print every write from now, or from sequence, while the server is up
+help                   this message";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnknownVerb(String),
+    MissingArgument(&'static str),
+    InvalidInteger { arg: &'static str, value: String },
+    FileNotFound(PathBuf),
+    UnexpectedArgument(String),
+    InvalidToken(String),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty command"),
+            Self::UnknownVerb(verb) => write!(f, "unknown command {verb}, try help"),
+            Self::MissingArgument(arg) => write!(f, "missing {arg}"),
+            Self::InvalidInteger { arg, value } => {
+                write!(f, "expected integer value for {arg}, got {value}")
             }
-            "r" => {
-                let min_key: i32 = split_iter.next()?.parse().ok()?;
-                let max_key: i32 = split_iter.next()?.parse().ok()?;
-                Some(Command::RANGE { min_key, max_key })
+            Self::FileNotFound(file) => write!(f, "no file at {}", file.display()),
+            Self::UnexpectedArgument(arg) => write!(f, "unexpected argument {arg}"),
+            Self::InvalidToken(token) => write!(f, "expected a token from NEXT, got {token}"),
+        }
+    }
+}
+
+fn next_arg<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+    arg: &'static str,
+) -> Result<&'a str, ParseError> {
+    args.next().ok_or(ParseError::MissingArgument(arg))
+}
+
+/// Set in the flags byte of a RANGE to have deleted keys listed
+const RANGE_TOMBSTONES: u8 = 4;
+/// Set in the flags byte of any range for an exclusive min key or an inclusive max key, the other
+/// way around from what the server takes without them
+const MIN_KEY_EXCLUSIVE: u8 = 8;
+const MAX_KEY_INCLUSIVE: u8 = 16;
+
+/// Commands a `dump` without a limit asks for
+const DEFAULT_DUMP_LIMIT: u32 = 1000;
+
+/// Flags byte (bit 0: min key given, bit 1: max key given, along with `extra_flags`), then both
+/// keys. Open ends are sent as 0
+fn write_bounds<W: Write>(
+    writer: &mut W,
+    start: &Bound<i32>,
+    end: &Bound<i32>,
+    extra_flags: u8,
+) -> io::Result<()> {
+    let (min_key, max_key) = (bound_key(start), bound_key(end));
+    let mut flags = (min_key.is_some() as u8) | (max_key.is_some() as u8) << 1 | extra_flags;
+    if let Bound::Excluded(_) = start {
+        flags |= MIN_KEY_EXCLUSIVE;
+    }
+    if let Bound::Included(_) = end {
+        flags |= MAX_KEY_INCLUSIVE;
+    }
+    writer.write_all(&[flags])?;
+    writer.write_all(&min_key.unwrap_or(0).to_be_bytes())?;
+    writer.write_all(&max_key.unwrap_or(0).to_be_bytes())
+}
+
+/// Like `int_arg`, but `-` stands for no bound. A `[` before the key or `]` after it makes the
+/// bound inclusive, `(` or `)` exclusive, otherwise it's `bound`.
+fn bound_arg<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+    arg: &'static str,
+    bound: fn(i32) -> Bound<i32>,
+) -> Result<Bound<i32>, ParseError> {
+    let value = next_arg(args, arg)?;
+    let (value, bound): (&str, fn(i32) -> Bound<i32>) =
+        if let Some(key) = value.strip_prefix('[').or(value.strip_suffix(']')) {
+            (key, Bound::Included)
+        } else if let Some(key) = value.strip_prefix('(').or(value.strip_suffix(')')) {
+            (key, Bound::Excluded)
+        } else {
+            (value, bound)
+        };
+    if value == "-" {
+        return Ok(Bound::Unbounded);
+    }
+    value
+        .parse()
+        .map(bound)
+        .map_err(|_| ParseError::InvalidInteger {
+            arg,
+            value: value.to_string(),
+        })
+}
+
+pub fn bound_key(bound: &Bound<i32>) -> Option<i32> {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => Some(*key),
+        Bound::Unbounded => None,
+    }
+}
+
+fn int_arg<'a, T: FromStr>(
+    args: &mut impl Iterator<Item = &'a str>,
+    arg: &'static str,
+) -> Result<T, ParseError> {
+    let value = next_arg(args, arg)?;
+    value.parse().map_err(|_| ParseError::InvalidInteger {
+        arg,
+        value: value.to_string(),
+    })
+}
+
+/// The 18 hex digits after NEXT in a page
+fn token_arg<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<[u8; 9], ParseError> {
+    let value = next_arg(args, "token")?;
+    let invalid = || ParseError::InvalidToken(value.to_string());
+    if value.len() != 18 || !value.is_ascii() {
+        return Err(invalid());
+    }
+    let mut token = [0; 9];
+    for (i, byte) in token.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(token)
+}
+
+fn on_off_arg<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<bool, ParseError> {
+    match next_arg(args, "on or off")? {
+        arg if arg.eq_ignore_ascii_case("on") => Ok(true),
+        arg if arg.eq_ignore_ascii_case("off") => Ok(false),
+        other => Err(ParseError::UnexpectedArgument(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, ops::Bound::*, path::PathBuf, process};
+
+    use super::{workload_commands, Command, CommandType, ParseError};
+
+    fn temp_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("{name}-{}", process::id()));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_sends_little_endian_files_big_endian() {
+        let pairs = [(1, 2), (-1, 256), (0x01020304, i32::MIN)];
+        let file: Vec<u8> = pairs
+            .iter()
+            .flat_map(|&(key, val): &(i32, i32)| [key.to_le_bytes(), val.to_le_bytes()])
+            .flatten()
+            .collect();
+        let path = temp_file("load", &file);
+
+        let mut sent = vec![];
+        Command::LOAD { file: path.clone() }
+            .serialize(&mut sent)
+            .unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(sent[0], b'l');
+        assert_eq!(sent[1..9], 3_u64.to_be_bytes());
+        let expected: Vec<u8> = pairs
+            .iter()
+            .flat_map(|&(key, val)| [key.to_be_bytes(), val.to_be_bytes()])
+            .flatten()
+            .collect();
+        assert_eq!(sent[9..], expected);
+    }
+
+    #[test]
+    fn ranges_take_open_ends() {
+        let bounds = |input| match Command::from_input(input) {
+            Ok(Command::RANGE { bounds, .. }) => bounds,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(bounds("r 1 5"), (Included(1), Excluded(5)));
+        assert_eq!(bounds("r 1 -"), (Included(1), Unbounded));
+        assert_eq!(bounds("r - 5"), (Unbounded, Excluded(5)));
+        assert_eq!(bounds("r - -"), (Unbounded, Unbounded));
+        assert_eq!(bounds("r (1 5]"), (Excluded(1), Included(5)));
+    }
+
+    #[test]
+    fn whitespace_and_case_leave_the_command_alone() {
+        let parsed = |input| format!("{:?}", Command::from_input(input).unwrap());
+        for (canonical, variants) in [
+            (
+                "p 1 2",
+                &["  p 1 2", "p 1 2  ", "p   1\t2", "\tP 1 2\r"][..],
+            ),
+            (
+                "r 1 5 deleted",
+                &[" R 1  5 DELETED ", "r\t1\t5\tDeleted"][..],
+            ),
+            ("dr -3 7", &["DR  -3   7", " dR -3 7"][..]),
+        ] {
+            for variant in variants {
+                assert_eq!(parsed(variant), parsed(canonical), "{variant:?}");
             }
-            "s" => Some(Command::STATS),
-            _ => None,
         }
     }
+
+    #[test]
+    fn workloads_skip_lines_that_arent_plotted_commands() {
+        let pairs = temp_file("workload-load", &[0; 8]);
+        let lines = [
+            "p 1 2".to_string(),
+            format!("l {}", pairs.display()),
+            "l /no/such/file".to_string(),
+            "not a command".to_string(),
+            "fsck".to_string(),
+            "s".to_string(),
+            "".to_string(),
+            "g 1".to_string(),
+        ];
+        let mut skipped = 0;
+        let types: Vec<CommandType> = workload_commands(lines.into_iter(), &mut skipped)
+            .map(|(command_type, _)| command_type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                CommandType::PUT,
+                CommandType::LOAD,
+                CommandType::STATS,
+                CommandType::GET
+            ]
+        );
+        assert_eq!(skipped, 4);
+        fs::remove_file(pairs).unwrap();
+    }
+
+    #[test]
+    fn parse_errors_say_what_was_wrong() {
+        let error = |input| Command::from_input(input).err();
+        assert_eq!(error("   "), Some(ParseError::Empty));
+        assert_eq!(error("x 1"), Some(ParseError::UnknownVerb("x".into())));
+        assert_eq!(error("p 1"), Some(ParseError::MissingArgument("value")));
+        assert_eq!(
+            error("g five"),
+            Some(ParseError::InvalidInteger {
+                arg: "key",
+                value: "five".into()
+            })
+        );
+        assert_eq!(
+            error("l /no/such/file"),
+            Some(ParseError::FileNotFound("/no/such/file".into()))
+        );
+        assert_eq!(
+            error("r 1 5 everything"),
+            Some(ParseError::UnexpectedArgument("everything".into()))
+        );
+        assert_eq!(
+            error("more 00ff 10"),
+            Some(ParseError::InvalidToken("00ff".into()))
+        );
+        assert_eq!(
+            ParseError::InvalidInteger {
+                arg: "key",
+                value: "five".into()
+            }
+            .to_string(),
+            "expected integer value for key, got five"
+        );
+        assert_eq!(error("G 5"), None);
+    }
 }
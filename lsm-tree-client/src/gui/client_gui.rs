@@ -5,12 +5,12 @@ use relm4::{
     gtk::{
         self,
         glib::subclass::types::ObjectSubclassIsExt,
-        prelude::{ButtonExt, OrientableExt, WidgetExt},
+        prelude::{ButtonExt, CheckButtonExt, OrientableExt, WidgetExt},
     },
     ComponentParts, ComponentSender, Sender, SimpleComponent,
 };
 
-use crate::{command::CommandType, run_gui_client};
+use crate::{run_gui_client, workload::LatencyBatch, ARGS};
 
 use super::{command_panel::CommandPanelOutput, plot};
 
@@ -35,9 +35,10 @@ impl ClientGui {
 #[derive(Debug)]
 pub enum ClientInput {
     FromCommandPanel(CommandPanelOutput),
-    NewData(Box<[f32]>, Box<[CommandType]>),
+    NewData(LatencyBatch),
     CommandCompleted,
     ClearGraph,
+    ShowAverage(bool),
 }
 
 #[derive(Debug)]
@@ -65,6 +66,13 @@ impl SimpleComponent for ClientGui {
                 set_label: "Clear",
                 connect_clicked => ClientInput::ClearGraph
             },
+
+            gtk::CheckButton {
+                set_label: Some("Moving average"),
+                connect_toggled[sender] => move |check| {
+                    sender.input(ClientInput::ShowAverage(check.is_active()))
+                },
+            },
         }
     }
 
@@ -72,6 +80,7 @@ impl SimpleComponent for ClientGui {
         let mut model = ClientGui::with_sender(sender.clone());
         let widgets = view_output!();
 
+        widgets.plot.imp().data.borrow_mut().average_window = ARGS.get().unwrap().average_window;
         model.plot = Some(widgets.plot.clone());
         ComponentParts { model, widgets }
     }
@@ -81,11 +90,11 @@ impl SimpleComponent for ClientGui {
             ClientInput::FromCommandPanel(cpo) => {
                 self.command_channel_sender.emit(cpo);
             }
-            ClientInput::NewData(data, command_types) => {
+            ClientInput::NewData(batch) => {
                 // update and redraw plot
                 let plot_widget = self.plot.as_ref().unwrap();
                 let plot = plot_widget.imp();
-                plot.data.borrow_mut().push(data, command_types);
+                plot.data.borrow_mut().push(batch);
                 plot_widget.queue_draw();
             }
             ClientInput::CommandCompleted => {
@@ -97,6 +106,11 @@ impl SimpleComponent for ClientGui {
                 plot.data.borrow_mut().clear();
                 plot_widget.queue_draw();
             }
+            ClientInput::ShowAverage(show) => {
+                let plot_widget = self.plot.as_ref().unwrap();
+                plot_widget.imp().data.borrow_mut().show_average = show;
+                plot_widget.queue_draw();
+            }
         }
     }
 }
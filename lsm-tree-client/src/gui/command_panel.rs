@@ -31,6 +31,8 @@ pub enum CommandPanelOutput {
         gets_miss_ratio: f64,
         num_ranges: u32,
         num_deletes: u32,
+        connections: u32, // the workload is split between this many connections
+        target_rate: u32, // commands per second sent without waiting on responses, 0 to wait
     },
     RawCommand {
         command: String,
@@ -126,6 +128,18 @@ impl SimpleComponent for CommandPanel {
                             gtk::Label { set_label: "number of deletes" },
                             #[name = "work_deletes"]
                             gtk::SpinButton { set_range: (0.0, 100000000.0), set_increments: (1.0, 10.0) },
+
+                            gtk::Label { set_label: "connections" },
+                            #[name = "work_connections"]
+                            gtk::SpinButton {
+                                set_range: (1.0, 64.0),
+                                set_increments: (1.0, 4.0),
+                                set_value: 1.0
+                            },
+
+                            gtk::Label { set_label: "target ops/s (0 waits on each response)" },
+                            #[name = "work_rate"]
+                            gtk::SpinButton { set_range: (0.0, 10000000.0), set_increments: (100.0, 1000.0) },
                         }
                     }
                 },
@@ -177,6 +191,12 @@ impl SimpleComponent for CommandPanel {
         model
             .spin_button_widgets
             .insert("work_deletes", widgets.work_deletes.clone());
+        model
+            .spin_button_widgets
+            .insert("work_connections", widgets.work_connections.clone());
+        model
+            .spin_button_widgets
+            .insert("work_rate", widgets.work_rate.clone());
         model
             .spin_button_widgets
             .insert("work_gs", widgets.work_gs.clone());
@@ -214,6 +234,8 @@ impl SimpleComponent for CommandPanel {
                             gets_miss_ratio: self.get_spin_float("work_gmr"),
                             num_ranges: self.get_spin_val("work_ranges"),
                             num_deletes: self.get_spin_val("work_deletes"),
+                            connections: self.get_spin_val("work_connections"),
+                            target_rate: self.get_spin_val("work_rate"),
                         });
                     }
                     SelectedCommand::RawCommand => {
@@ -4,12 +4,14 @@ use gtk::subclass::prelude::*;
 use relm4::gtk;
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::error::Error;
+use std::iter;
 
 use plotters::prelude::*;
 use plotters_cairo::CairoBackend;
 
-use crate::command::CommandType;
+use crate::{command::CommandType, response::ServerEvent, workload::LatencyBatch};
 
 #[derive(Default, glib::Properties)]
 #[properties(wrapper_type = super::Plot)]
@@ -24,11 +26,52 @@ pub struct PlotData {
     gets: Vec<(u32, f32)>,
     ranges: Vec<(u32, f32)>,
     deletes: Vec<(u32, f32)>,
+    loads: Vec<(u32, f32)>,
+    stats: Vec<(u32, f32)>,
+    averages: [MovingAverage; 6],     // indexed by command type
+    markers: Vec<(u32, ServerEvent)>, // at the command whose response reported them
+    blocks_read: Vec<(u32, f32)>,     // reads only, when the server sends metrics
+    max_blocks_read: f32,
+    pub show_average: bool,
+    pub average_window: usize,
+    view: Option<(u32, u32)>, // x range shown while zoomed in, everything otherwise
+    pan_start: Option<(u32, u32)>, // view when the current drag began
     min: f32,
     max: f32,
     total: u32,
 }
 
+// Fewest commands the view can be zoomed in to
+const MIN_VIEW_WIDTH: u32 = 10;
+// Points each series is downsampled to for drawing
+const DRAWN_POINTS: usize = 2000;
+
+/// Mean of the last `window` latencies of one command type, taken at every point as it comes in
+#[derive(Debug, Default)]
+struct MovingAverage {
+    recent: VecDeque<f32>,
+    sum: f64,
+    points: Vec<(u32, f32)>,
+}
+
+impl MovingAverage {
+    fn push(&mut self, x: u32, y: f32, window: usize) {
+        self.recent.push_back(y);
+        self.sum += y as f64;
+        while self.recent.len() > window.max(1) {
+            self.sum -= self.recent.pop_front().unwrap() as f64;
+        }
+        let mean = self.sum / self.recent.len() as f64;
+        self.points.push((x, mean as f32));
+    }
+
+    fn clear(&mut self) {
+        self.recent.clear();
+        self.sum = 0.0;
+        self.points.clear();
+    }
+}
+
 impl Default for PlotData {
     fn default() -> Self {
         Self {
@@ -36,6 +79,16 @@ impl Default for PlotData {
             gets: Vec::new(),
             ranges: Vec::new(),
             deletes: Vec::new(),
+            loads: Vec::new(),
+            stats: Vec::new(),
+            averages: Default::default(),
+            markers: Vec::new(),
+            blocks_read: Vec::new(),
+            max_blocks_read: 0.0,
+            show_average: false,
+            average_window: 100,
+            view: None,
+            pan_start: None,
             min: f32::INFINITY,
             max: 0.0,
             total: 0,
@@ -49,34 +102,113 @@ impl PlotData {
         self.gets.clear();
         self.ranges.clear();
         self.deletes.clear();
+        self.loads.clear();
+        self.stats.clear();
+        for average in self.averages.iter_mut() {
+            average.clear();
+        }
+        self.markers.clear();
+        self.blocks_read.clear();
+        self.max_blocks_read = 0.0;
+        self.view = None;
         self.min = f32::INFINITY;
         self.max = 0.0;
         self.total = 0;
     }
 
-    pub fn push(&mut self, data: Box<[f32]>, c_types: Box<[CommandType]>) {
-        for (c_type, y) in c_types.iter().zip(data) {
+    /// Commands from the first to the last shown
+    pub fn view(&self) -> (u32, u32) {
+        self.view.unwrap_or((0, self.total.saturating_sub(1)))
+    }
+
+    /// Scales the view around its middle, a factor over 1 zooms out
+    pub fn zoom(&mut self, factor: f64) {
+        let last = self.total.saturating_sub(1);
+        let (start, end) = self.view();
+        let middle = (start as f64 + end as f64) / 2.0;
+        let half_width = ((end - start) as f64 * factor).max(MIN_VIEW_WIDTH as f64) / 2.0;
+
+        // rounded, truncating would creep the view left a little with every step
+        let start = (middle - half_width).max(0.0).round() as u32;
+        let end = (middle + half_width).min(last as f64).round() as u32;
+        self.view = (start > 0 || end < last).then_some((start, end));
+    }
+
+    /// Zooms by scroll wheel steps, scrolling down zooms out
+    pub fn scroll(&mut self, dy: f64) {
+        self.zoom(1.25_f64.powf(dy));
+    }
+
+    pub fn begin_pan(&mut self) {
+        self.pan_start = Some(self.view());
+    }
+
+    /// Shifts the view from where the pan began by `fraction` of its width
+    pub fn pan(&mut self, fraction: f64) {
+        // the whole run is already in view
+        if self.view.is_none() {
+            return;
+        }
+        let (start, end) = self.pan_start.unwrap_or(self.view());
+        let width = end - start;
+        let shift = (fraction * width as f64) as i64;
+        let max_start = self.total.saturating_sub(1).saturating_sub(width);
+
+        let start = (start as i64 + shift).clamp(0, max_start as i64) as u32;
+        self.view = Some((start, start + width));
+    }
+
+    /// Points of `data` in view, downsampled. Only what's shown gets downsampled, so zooming in
+    /// brings back the detail.
+    fn visible(&self, data: &[(u32, f32)]) -> Vec<(u32, f32)> {
+        let (start, end) = self.view();
+        let from = data.partition_point(|&(x, _)| x < start);
+        let to = data.partition_point(|&(x, _)| x <= end);
+
+        let mut points = data[from..to].to_vec();
+        Self::run_lttb(&mut points, DRAWN_POINTS);
+        points
+    }
+
+    /// Events in view, at the x of the command whose response reported them
+    fn visible_markers(&self) -> impl Iterator<Item = (u32, ServerEvent)> + '_ {
+        let (start, end) = self.view();
+        self.markers
+            .iter()
+            .copied()
+            .filter(move |&(x, _)| start <= x && x <= end)
+    }
+
+    pub fn push(&mut self, batch: LatencyBatch) {
+        let first = self.total;
+        self.markers.extend(
+            batch
+                .events
+                .iter()
+                .map(|&(idx, event)| (first + idx as u32, event)),
+        );
+        for &(idx, blocks) in batch.blocks_read.iter() {
+            self.blocks_read.push((first + idx as u32, blocks as f32));
+            self.max_blocks_read = self.max_blocks_read.max(blocks as f32);
+        }
+
+        for (c_type, y) in batch.command_types.iter().zip(batch.durations) {
             let vec = match *c_type {
                 CommandType::PUT => &mut self.puts,
                 CommandType::GET => &mut self.gets,
                 CommandType::RANGE => &mut self.ranges,
                 CommandType::DELETE => &mut self.deletes,
+                CommandType::LOAD => &mut self.loads,
+                CommandType::STATS => &mut self.stats,
             };
 
             vec.push((self.total, y));
+            self.averages[*c_type as usize].push(self.total, y, self.average_window);
             self.total += 1;
 
             self.max = self.max.max(y);
             self.min = self.min.min(y);
         }
-        self.downsample(2000);
-    }
-
-    fn downsample(&mut self, threshold: usize) {
-        Self::run_lttb(&mut self.gets, threshold);
-        Self::run_lttb(&mut self.puts, threshold);
-        Self::run_lttb(&mut self.ranges, threshold);
-        Self::run_lttb(&mut self.deletes, threshold);
     }
 
     // https://skemman.is/bitstream/1946/15343/3/SS_MSthesis.pdf
@@ -169,6 +301,42 @@ impl ObjectImpl for Plot {
     fn property(&self, id: usize, pspec: &glib::ParamSpec) -> glib::Value {
         Self::derived_property(self, id, pspec)
     }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.obj();
+
+        // scroll to zoom
+        let scroll = gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::VERTICAL);
+        let plot = obj.downgrade();
+        scroll.connect_scroll(move |_, _, dy| {
+            if let Some(plot) = plot.upgrade() {
+                plot.imp().data.borrow_mut().scroll(dy);
+                plot.queue_draw();
+            }
+            glib::Propagation::Stop
+        });
+        obj.add_controller(scroll);
+
+        // drag to pan
+        let drag = gtk::GestureDrag::new();
+        let plot = obj.downgrade();
+        drag.connect_drag_begin(move |_, _, _| {
+            if let Some(plot) = plot.upgrade() {
+                plot.imp().data.borrow_mut().begin_pan();
+            }
+        });
+        let plot = obj.downgrade();
+        drag.connect_drag_update(move |_, offset_x, _| {
+            if let Some(plot) = plot.upgrade() {
+                // the data follows the pointer, so dragging right moves the view left
+                let fraction = -offset_x / plot.width().max(1) as f64;
+                plot.imp().data.borrow_mut().pan(fraction);
+                plot.queue_draw();
+            }
+        });
+        obj.add_controller(drag);
+    }
 }
 
 impl WidgetImpl for Plot {
@@ -196,21 +364,26 @@ impl Plot {
         root.fill(&WHITE)?;
 
         let plot_data = self.data.borrow();
+        let (view_start, view_end) = plot_data.view();
+        let (y_min, y_max) = if plot_data.max == 0.0 {
+            (0f32, 1f32)
+        } else {
+            (plot_data.min / 5.0, plot_data.max * 5.0)
+        };
+
+        let has_blocks_read = !plot_data.blocks_read.is_empty();
 
         let mut cc = ChartBuilder::on(&root)
             .margin(10)
             .caption("Latency", ("sans-serif", 30))
             .x_label_area_size(40)
             .y_label_area_size(50)
-            .build_cartesian_2d(
-                0..plot_data.total.saturating_sub(1),
-                if plot_data.max == 0.0 {
-                    0f32..1f32
-                } else {
-                    plot_data.min/5.0..plot_data.max*5.0
-                }
-                .log_scale(),
-            )?;
+            .right_y_label_area_size(if has_blocks_read { 50 } else { 0 })
+            .build_cartesian_2d(view_start..view_end, (y_min..y_max).log_scale())?
+            .set_secondary_coord(
+                view_start..view_end,
+                0f32..plot_data.max_blocks_read.max(1.0) * 1.1,
+            );
 
         cc.configure_mesh()
             .x_labels(15)
@@ -225,12 +398,50 @@ impl Plot {
             (CommandType::GET, &plot_data.gets, Palette99::pick(1)),
             (CommandType::RANGE, &plot_data.ranges, Palette99::pick(2)),
             (CommandType::DELETE, &plot_data.deletes, Palette99::pick(3)),
+            (CommandType::LOAD, &plot_data.loads, Palette99::pick(4)),
+            (CommandType::STATS, &plot_data.stats, Palette99::pick(5)),
         ];
 
         for (c_type, data, palette) in axes {
-            cc.draw_series(LineSeries::new(data.iter().cloned(), &palette))?
+            let average_style = palette.stroke_width(3);
+            cc.draw_series(LineSeries::new(plot_data.visible(data), &palette))?
                 .label(format!("{:?}", c_type))
                 .legend(move |(x, y)| Rectangle::new([(x - 5, y - 5), (x + 5, y + 5)], &palette));
+
+            if plot_data.show_average {
+                let average = &plot_data.averages[c_type as usize];
+                cc.draw_series(LineSeries::new(
+                    plot_data.visible(&average.points),
+                    average_style,
+                ))?;
+            }
+        }
+
+        if has_blocks_read {
+            cc.configure_secondary_axes()
+                .y_desc("Blocks read")
+                .axis_desc_style(("sans-serif", 15))
+                .draw()?;
+
+            let style = BLACK.mix(0.4);
+            cc.draw_secondary_series(LineSeries::new(
+                plot_data.visible(&plot_data.blocks_read),
+                style,
+            ))?
+            .label("blocks read")
+            .legend(move |(x, y)| Rectangle::new([(x - 5, y - 5), (x + 5, y + 5)], style));
+        }
+
+        // compactions, red where one started and green where one finished
+        for (x, event) in plot_data.visible_markers() {
+            let color = match event {
+                ServerEvent::CompactionStarted => RED,
+                ServerEvent::CompactionFinished => GREEN,
+            };
+            cc.draw_series(iter::once(PathElement::new(
+                vec![(x, y_min), (x, y_max)],
+                color.mix(0.6),
+            )))?;
         }
 
         cc.configure_series_labels()
@@ -242,3 +453,134 @@ impl Plot {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(commands: &[(CommandType, f32)]) -> LatencyBatch {
+        LatencyBatch {
+            durations: commands.iter().map(|&(_, y)| y).collect(),
+            command_types: commands.iter().map(|&(c_type, _)| c_type).collect(),
+            events: vec![],
+            blocks_read: vec![],
+        }
+    }
+
+    #[test]
+    fn loads_and_stats_get_series_of_their_own() {
+        let mut data = PlotData::default();
+        data.push(batch(&[
+            (CommandType::PUT, 1.0),
+            (CommandType::LOAD, 250.0),
+            (CommandType::GET, 2.0),
+            (CommandType::STATS, 40.0),
+        ]));
+        assert_eq!(data.visible(&data.loads), [(1, 250.0)]);
+        assert_eq!(data.visible(&data.stats), [(3, 40.0)]);
+        assert_eq!(data.visible(&data.puts), [(0, 1.0)]);
+        assert_eq!((data.total, data.max), (4, 250.0));
+    }
+
+    #[test]
+    fn moving_average_of_a_constant_is_the_constant() {
+        let mut data = PlotData {
+            average_window: 7,
+            ..Default::default()
+        };
+        for _ in 0..5 {
+            data.push(batch(&[(CommandType::GET, 3.5); 10]));
+        }
+        let average = &data.averages[CommandType::GET as usize].points;
+        assert_eq!(average.len(), 50);
+        assert!(average.iter().all(|&(_, y)| y == 3.5));
+        assert!(data.averages[CommandType::PUT as usize].points.is_empty());
+
+        // and follows a step within a window
+        data.push(batch(&[(CommandType::GET, 10.5); 7]));
+        let &(x, y) = data.averages[CommandType::GET as usize]
+            .points
+            .last()
+            .unwrap();
+        assert_eq!((x, y), (56, 10.5));
+    }
+
+    #[test]
+    fn scrolling_and_dragging_move_the_view() {
+        let mut data = PlotData::default();
+        data.push(batch(&[(CommandType::PUT, 1.0); 1000]));
+        assert_eq!(data.view(), (0, 999));
+
+        data.scroll(-1.0);
+        assert_eq!(data.view(), (100, 899));
+        data.scroll(1.0);
+        assert_eq!(data.view(), (0, 999));
+        assert_eq!(data.view, None);
+
+        for _ in 0..50 {
+            data.scroll(-1.0);
+        }
+        let (start, end) = data.view();
+        // still around the middle of the run
+        assert!((499..=500).contains(&((start + end) / 2)), "{start}..{end}");
+        assert!(end - start <= MIN_VIEW_WIDTH);
+
+        data.scroll(-1.0);
+        data.zoom(20.0);
+        let (start, end) = data.view();
+        let width = end - start;
+        assert_eq!(width, 200);
+        // half a width right, then past either end, each from where the drag began
+        data.begin_pan();
+        data.pan(0.5);
+        assert_eq!(data.view(), (start + width / 2, end + width / 2));
+        data.pan(10.0);
+        assert_eq!(data.view(), (999 - width, 999));
+        data.pan(-10.0);
+        assert_eq!(data.view(), (0, width));
+    }
+
+    #[test]
+    fn compaction_events_mark_the_command_that_reported_them() {
+        let mut data = PlotData::default();
+        data.push(batch(&[(CommandType::PUT, 1.0); 100]));
+        let mut events = batch(&[(CommandType::PUT, 1.0); 100]);
+        events.events = vec![
+            (10, ServerEvent::CompactionStarted),
+            (90, ServerEvent::CompactionFinished),
+        ];
+        data.push(events);
+
+        let markers: Vec<_> = data.visible_markers().collect();
+        assert_eq!(
+            markers,
+            [
+                (110, ServerEvent::CompactionStarted),
+                (190, ServerEvent::CompactionFinished)
+            ]
+        );
+        // only what's in view gets drawn
+        data.view = Some((100, 150));
+        let markers: Vec<_> = data.visible_markers().collect();
+        assert_eq!(markers, [(110, ServerEvent::CompactionStarted)]);
+    }
+
+    #[test]
+    fn blocks_read_plot_at_their_reads() {
+        let mut data = PlotData::default();
+        data.push(batch(&[(CommandType::PUT, 1.0); 10]));
+        let mut reads = batch(&[
+            (CommandType::GET, 2.0),
+            (CommandType::PUT, 1.0),
+            (CommandType::GET, 2.0),
+        ]);
+        reads.blocks_read = vec![(0, 3), (2, 7)];
+        data.push(reads);
+        assert_eq!(data.visible(&data.blocks_read), [(10, 3.0), (12, 7.0)]);
+        assert_eq!(data.max_blocks_read, 7.0);
+
+        data.clear();
+        assert!(data.blocks_read.is_empty());
+        assert_eq!(data.max_blocks_read, 0.0);
+    }
+}
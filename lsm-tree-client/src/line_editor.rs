@@ -0,0 +1,131 @@
+use std::{io, path::PathBuf};
+
+use rustyline::{
+    completion::{Completer, FilenameCompleter, Pair},
+    error::ReadlineError,
+    history::DefaultHistory,
+    Context, Editor, Helper, Highlighter, Hinter, Validator,
+};
+
+use crate::command::VERBS;
+
+const HISTORY_FILE: &str = ".lsm_tree_history";
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct CommandCompleter {
+    files: FilenameCompleter,
+}
+
+impl Completer for CommandCompleter {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        match line[..pos].split_once(' ') {
+            // verb
+            None => Ok((
+                0,
+                VERBS
+                    .iter()
+                    .filter(|verb| verb.starts_with(&line[..pos]))
+                    .map(|verb| Pair {
+                        display: verb.to_string(),
+                        replacement: verb.to_string(),
+                    })
+                    .collect(),
+            )),
+            Some(("l", _)) => self.files.complete(line, pos, ctx),
+            Some(_) => Ok((pos, vec![])),
+        }
+    }
+}
+
+fn history_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(HISTORY_FILE)
+}
+
+fn editor() -> io::Result<Editor<CommandCompleter, DefaultHistory>> {
+    let mut editor = Editor::new().map_err(io::Error::other)?;
+    editor.set_helper(Some(CommandCompleter {
+        files: FilenameCompleter::new(),
+    }));
+    Ok(editor)
+}
+
+/// Reads lines with editing, completion and history kept across sessions, passing each one to
+/// `run_line`. Only meant for when stdin is a terminal.
+pub fn run_line_editor<F: FnMut(&str) -> io::Result<()>>(
+    prompt: &str,
+    mut run_line: F,
+) -> io::Result<()> {
+    let mut editor = editor()?;
+    let history = history_path();
+    let _ = editor.load_history(&history);
+
+    let result = loop {
+        match editor.readline(prompt) {
+            Ok(input) => {
+                // a paste of several lines comes through as one input
+                let res = input
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .try_for_each(|line| {
+                        let _ = editor.add_history_entry(line);
+                        run_line(line)
+                    });
+                if res.is_err() {
+                    break res;
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break Ok(()),
+            Err(err) => break Err(io::Error::other(err)),
+        }
+    };
+
+    let _ = editor.save_history(&history);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use rustyline::{completion::Completer, Context};
+
+    use super::editor;
+
+    #[test]
+    fn history_persists_across_sessions() {
+        let path = env::temp_dir().join(format!("history-{}", process::id()));
+        let mut first = editor().unwrap();
+        first.add_history_entry("p 1 2").unwrap();
+        first.add_history_entry("g 1").unwrap();
+        first.save_history(&path).unwrap();
+
+        let mut second = editor().unwrap();
+        second.load_history(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        let entries: Vec<&str> = second.history().iter().map(|e| e.as_str()).collect();
+        assert_eq!(entries, ["p 1 2", "g 1"]);
+    }
+
+    #[test]
+    fn completes_verbs() {
+        let editor = editor().unwrap();
+        let ctx = Context::new(editor.history());
+        let helper = editor.helper().unwrap();
+        let (start, candidates) = helper.complete("st", 2, &ctx).unwrap();
+        let verbs: Vec<&str> = candidates.iter().map(|c| c.replacement.as_str()).collect();
+        assert_eq!(start, 0);
+        assert_eq!(verbs, ["stats-json"]);
+        assert!(helper.complete("g 1", 3, &ctx).unwrap().1.is_empty());
+    }
+}
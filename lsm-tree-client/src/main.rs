@@ -1,22 +1,42 @@
-use core::str;
 use std::{
-    io::{self, BufRead, BufReader, BufWriter, Write},
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write},
     net::{IpAddr, TcpStream},
+    path::PathBuf,
     process::Stdio,
     sync::OnceLock,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use clap::{command, Parser};
-use command::{Command, CommandType};
+use command::{
+    workload_commands, Command, CommandType, CANCEL, CLIENT_FEATURES, FEATURE_BINARY_RESULTS,
+    FEATURE_TOMBSTONES, HANDSHAKE, HELP, PROTOCOL_VERSION,
+};
 use gui::{
     client_gui::{ClientGui, ClientInput},
     command_panel::CommandPanelOutput,
     App,
 };
+use line_editor::run_line_editor;
 use relm4::{ComponentSender, Receiver, RelmApp};
+use response::{
+    format_response, json_change, json_response, take_trailer, ResponseScanner, Trailer,
+};
+use script::run_script;
+use serde_json::json;
+use timing::{latency_suffix, Timings};
+use workload::{
+    end_response, read_response, read_until_end, run_concurrent_workload, send_command, Connection,
+    DurationBuffer,
+};
 mod command;
 mod gui;
+mod line_editor;
+mod response;
+mod script;
+mod timing;
+mod workload;
 
 static ARGS: OnceLock<Args> = OnceLock::new();
 
@@ -29,8 +49,48 @@ struct Args {
     #[arg(long)]
     cli: bool,
 
+    /// Run the commands in this file, one per line, instead of reading them interactively
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// With --script, stop at the first command that fails
+    #[arg(long)]
+    stop_on_error: bool,
+
+    /// Print how long each command took, and a summary on exit
+    #[arg(long)]
+    timing: bool,
+
+    /// Print one JSON object per command instead of the formatted response
+    #[arg(long)]
+    json: bool,
+
     #[arg(long, default_value = "127.0.0.1")]
     hostname: IpAddr,
+
+    /// Have the server send how many blocks each read went through, plotted in the GUI and shown
+    /// with --timing and --json
+    #[arg(long)]
+    verbose_metrics: bool,
+
+    /// Have gets of a deleted key answer DELETED, or "deleted" with --json, telling them apart
+    /// from keys that were never written
+    #[arg(long)]
+    tombstones: bool,
+
+    /// Have the server send GET values and range pairs in binary instead of decimal text. They're
+    /// printed the same either way.
+    #[arg(long)]
+    binary: bool,
+
+    /// Number of latencies of each command type the GUI's moving average is taken over
+    #[arg(long, default_value_t = 100)]
+    average_window: usize,
+
+    /// Cancel commands still answering after this many milliseconds, keeping what the server sent
+    /// until then
+    #[arg(long)]
+    deadline_ms: Option<u64>,
 }
 
 fn main() {
@@ -41,7 +101,8 @@ fn main() {
     // writes command to server
     // reads back the response from the server
 
-    if ARGS.get().unwrap().cli {
+    let args = ARGS.get().unwrap();
+    if args.cli || args.script.is_some() {
         let _ = run_text_client();
     } else {
         let app = RelmApp::new("relm4.lsm.client").with_args(vec![]);
@@ -50,7 +111,6 @@ fn main() {
 }
 
 fn run_text_client() -> io::Result<()> {
-    let mut input_buf = String::new();
     let mut output_buf = Vec::new();
 
     let args = ARGS.get().unwrap();
@@ -59,28 +119,127 @@ fn run_text_client() -> io::Result<()> {
         let mut read_half = BufReader::new(stream.try_clone()?);
         let mut write_half = BufWriter::new(stream);
 
-        loop {
-            // prompt
-            print!("{}:{}> ", args.hostname, args.port);
-            std::io::stdout().flush()?;
+        let features = configure_connection(&mut write_half, &mut read_half, &mut output_buf)?;
+        if args.tombstones && features & FEATURE_TOMBSTONES == 0 {
+            println!("Server doesn't support --tombstones, deleted keys answer nothing");
+        } else if args.tombstones {
+            let command = Command::SHOW_TOMBSTONES { enabled: true };
+            send_command(&mut write_half, &mut read_half, &command, &mut output_buf)?;
+        }
 
-            // read
-            if std::io::stdin().read_line(&mut input_buf)? == 0 {
-                break;
+        let prompt = format!("{}:{}> ", args.hostname, args.port);
+        let mut timings = Timings::new();
+
+        // Ok with the response to print, or Err with what went wrong
+        let mut run_line = |line: &str| -> io::Result<Result<String, String>> {
+            if line.trim().eq_ignore_ascii_case("help") {
+                return Ok(Ok(HELP.to_string()));
             }
 
-            input_buf.pop(); // \n
-            if let Some(command) = Command::from_input(&input_buf) {
-                // send
-                send_command(&mut write_half, &mut read_half, &command, &mut output_buf)?;
+            let command = match Command::from_input(line) {
+                Ok(command) => command,
+                Err(err) if args.json => {
+                    return Ok(Err(
+                        json!({ "error": format!("invalid command: {err}") }).to_string()
+                    ))
+                }
+                Err(err) => return Ok(Err(format!("Invalid command: {err}"))),
+            };
+
+            // send
+            let (elapsed, trailer) = match args.deadline_ms {
+                Some(deadline_ms) => send_command_with_deadline(
+                    &mut write_half,
+                    &mut read_half,
+                    &command,
+                    &mut output_buf,
+                    Duration::from_millis(deadline_ms),
+                )?,
+                None => send_command(&mut write_half, &mut read_half, &command, &mut output_buf)?,
+            };
+            let latency_ns = (elapsed as f64 * 1e9) as u64;
+
+            if matches!(command, Command::SUBSCRIBE { .. }) && output_buf.starts_with(b"OK") {
+                // a frame per change until the server shuts down, ending with an empty one or with
+                // the connection
+                while read_response(&mut read_half, &mut output_buf).is_ok()
+                    && !output_buf.is_empty()
+                {
+                    let change = String::from_utf8_lossy(&output_buf);
+                    match args.json {
+                        true => println!("{}", json_change(&change)),
+                        false => println!("{change}"),
+                    }
+                }
+                return Ok(Ok("Subscription ended".to_string()));
+            }
+
+            if args.timing {
+                timings.record(latency_ns);
+            }
+
+            if args.json {
+                let mut response = json_response(&command, &output_buf, latency_ns);
+                if let Some(blocks_read) = trailer.blocks_read {
+                    response["blocks_read"] = json!(blocks_read);
+                }
+                let failed = response.get("error").is_some();
+                let response = response.to_string();
+                return Ok(if failed { Err(response) } else { Ok(response) });
+            }
 
-                // print
-                println!("{}", unsafe { str::from_utf8_unchecked(&output_buf) });
+            let mut response = format_response(&command, &output_buf);
+            if args.timing {
+                response.push_str(&latency_suffix(latency_ns, trailer.blocks_read));
+            }
+
+            Ok(if response.starts_with("ERROR") {
+                Err(response)
             } else {
-                println!("Invalid command...");
+                Ok(response)
+            })
+        };
+
+        if let Some(script) = &args.script {
+            let script = BufReader::new(File::open(script)?);
+            // keep JSON output one plain object per line
+            let line_numbers = !args.json;
+            run_script(
+                script,
+                &mut io::stdout(),
+                line_numbers,
+                args.stop_on_error,
+                &mut run_line,
+            )?;
+        } else if io::stdin().is_terminal() {
+            run_line_editor(&prompt, |line| {
+                let (Ok(response) | Err(response)) = run_line(line)?;
+                println!("{response}");
+                Ok(())
+            })?;
+        } else {
+            // piped in, e.g. from the generator
+            let mut input_buf = String::new();
+            loop {
+                // prompt
+                print!("{prompt}");
+                std::io::stdout().flush()?;
+
+                // read
+                if std::io::stdin().read_line(&mut input_buf)? == 0 {
+                    break;
+                }
+
+                input_buf.pop(); // \n
+                let (Ok(response) | Err(response)) = run_line(&input_buf)?;
+                println!("{response}");
+
+                input_buf.clear();
             }
+        }
 
-            input_buf.clear();
+        if let Some(summary) = timings.summary().filter(|_| args.timing) {
+            println!("{summary}");
         }
     } else {
         println!(
@@ -92,46 +251,13 @@ fn run_text_client() -> io::Result<()> {
     Ok(())
 }
 
-struct DurationBuffer<const CAP: usize> {
-    durations: Vec<f32>,
-    command_types: Vec<CommandType>,
-}
-
-impl<const CAP: usize> DurationBuffer<CAP> {
-    fn new() -> Self {
-        Self {
-            durations: Vec::with_capacity(CAP),
-            command_types: Vec::with_capacity(CAP),
-        }
-    }
-
-    fn push(&mut self, val: f32, command_type: CommandType, sender: &ComponentSender<ClientGui>) {
-        self.durations.push(val);
-        self.command_types.push(command_type);
-        if self.durations.len() == CAP {
-            self.send_to_gui(sender);
-        }
-    }
-
-    fn send_to_gui(&mut self, sender: &ComponentSender<ClientGui>) {
-        if self.durations.len() == 0 {
-            return;
-        }
-        sender.input(ClientInput::NewData(
-            self.durations.clone().into_boxed_slice(),
-            self.command_types.clone().into_boxed_slice(),
-        ));
-        self.durations.clear();
-        self.command_types.clear();
-    }
-}
-
 fn run_gui_client(
     sender: ComponentSender<ClientGui>,
     receiver: Receiver<CommandPanelOutput>,
 ) -> io::Result<()> {
     let mut output_buf = Vec::new();
     let mut duration_buf = DurationBuffer::<1000>::new();
+    let send = |batch| sender.input(ClientInput::NewData(batch));
 
     let args = ARGS.get().unwrap();
 
@@ -139,85 +265,124 @@ fn run_gui_client(
         let mut read_half = BufReader::new(stream.try_clone()?);
         let mut write_half = BufWriter::new(stream);
 
+        configure_connection(&mut write_half, &mut read_half, &mut output_buf)?;
+
         while let Some(cpo) = receiver.recv_sync() {
-            match cpo {
-                CommandPanelOutput::GeneratePuts { num_puts } => {
-                    let _ = std::process::Command::new("./generator/generator")
-                        .arg("--external-puts")
-                        .arg("--puts")
-                        .arg(num_puts.to_string())
-                        .output();
-
-                    let command = Command::LOAD {
-                        file: "./0.dat".into(),
-                    };
-
-                    println!("Sending command {command:?}");
-                    send_command(&mut write_half, &mut read_half, &command, &mut output_buf)?;
-                }
-                CommandPanelOutput::GenerateWorkload {
-                    num_puts,
-                    num_gets,
-                    gets_skew,
-                    gets_miss_ratio,
-                    num_ranges,
-                    num_deletes,
-                } => {
-                    let child = std::process::Command::new("./generator/generator")
-                        .arg("--puts")
-                        .arg(num_puts.to_string())
-                        .arg("--gets")
-                        .arg(num_gets.to_string())
-                        .arg("--gets-skewness")
-                        .arg(gets_skew.to_string())
-                        .arg("--gets-misses-ratio")
-                        .arg(gets_miss_ratio.to_string())
-                        .arg("--ranges")
-                        .arg(num_ranges.to_string())
-                        .arg("--deletes")
-                        .arg(num_deletes.to_string())
-                        .stdout(Stdio::piped())
-                        .spawn()
-                        .unwrap();
-
-                    let reader = BufReader::new(child.stdout.unwrap());
-
-                    for line in reader.lines().map(|s| s.unwrap()) {
-                        let command = Command::from_input(&line).unwrap();
-                        duration_buf.push(
-                            send_command(
-                                &mut write_half,
-                                &mut read_half,
-                                &command,
-                                &mut output_buf,
-                            )?,
-                            command.to_type().unwrap(),
-                            &sender,
-                        );
+            // the panel stays busy until it hears back, so it has to even if the command failed
+            let run = || -> io::Result<()> {
+                match cpo {
+                    CommandPanelOutput::GeneratePuts { num_puts } => {
+                        let _ = std::process::Command::new("./generator/generator")
+                            .arg("--external-puts")
+                            .arg("--puts")
+                            .arg(num_puts.to_string())
+                            .output();
+
+                        let command = Command::LOAD {
+                            file: "./0.dat".into(),
+                        };
+
+                        println!("Sending command {command:?}");
+                        let response = send_command(
+                            &mut write_half,
+                            &mut read_half,
+                            &command,
+                            &mut output_buf,
+                        )?;
+                        duration_buf.push(response, CommandType::LOAD, &send);
                     }
-                }
-                CommandPanelOutput::RawCommand { command } => {
-                    println!("{}:{}> {}", args.hostname, args.port, command);
-                    if let Some(command) = Command::from_input(&command) {
-                        if let Some(command_type) = command.to_type() {
-                            duration_buf.push(
-                                send_command(
-                                    &mut write_half,
-                                    &mut read_half,
-                                    &command,
-                                    &mut output_buf,
-                                )?,
-                                command_type,
-                                &sender,
+                    CommandPanelOutput::GenerateWorkload {
+                        num_puts,
+                        num_gets,
+                        gets_skew,
+                        gets_miss_ratio,
+                        num_ranges,
+                        num_deletes,
+                        connections,
+                        target_rate,
+                    } => {
+                        let child = std::process::Command::new("./generator/generator")
+                            .arg("--puts")
+                            .arg(num_puts.to_string())
+                            .arg("--gets")
+                            .arg(num_gets.to_string())
+                            .arg("--gets-skewness")
+                            .arg(gets_skew.to_string())
+                            .arg("--gets-misses-ratio")
+                            .arg(gets_miss_ratio.to_string())
+                            .arg("--ranges")
+                            .arg(num_ranges.to_string())
+                            .arg("--deletes")
+                            .arg(num_deletes.to_string())
+                            .stdout(Stdio::piped())
+                            .spawn()
+                            .unwrap();
+
+                        let reader = BufReader::new(child.stdout.unwrap());
+
+                        let mut skipped = 0;
+                        let commands =
+                            workload_commands(reader.lines().map(|s| s.unwrap()), &mut skipped);
+
+                        if connections <= 1 && target_rate == 0 {
+                            for (command_type, command) in commands {
+                                duration_buf.push(
+                                    send_command(
+                                        &mut write_half,
+                                        &mut read_half,
+                                        &command,
+                                        &mut output_buf,
+                                    )?,
+                                    command_type,
+                                    &send,
+                                );
+                            }
+                        } else {
+                            let commands: Vec<_> = commands.collect();
+                            let connections = connections.max(1);
+                            // split evenly, so the connections add up to the target
+                            let rate =
+                                (target_rate > 0).then(|| target_rate as f64 / connections as f64);
+                            run_concurrent_workload(
+                                &commands,
+                                connections as usize,
+                                rate,
+                                connect,
+                                &send,
+                            )?;
+                        }
+                        if skipped > 0 {
+                            println!(
+                                "Skipped {skipped} generator lines that weren't valid commands"
                             );
-                            println!("{}", unsafe { str::from_utf8_unchecked(&output_buf) });
+                        }
+                    }
+                    CommandPanelOutput::RawCommand { command } => {
+                        println!("{}:{}> {}", args.hostname, args.port, command);
+                        if let Ok(command) = Command::from_input(&command) {
+                            if let Some(command_type) = command.to_type() {
+                                duration_buf.push(
+                                    send_command(
+                                        &mut write_half,
+                                        &mut read_half,
+                                        &command,
+                                        &mut output_buf,
+                                    )?,
+                                    command_type,
+                                    &send,
+                                );
+                                println!("{}", format_response(&command, &output_buf));
+                            }
                         }
                     }
                 }
-            }
+                Ok(())
+            };
+            let result = run();
 
-            duration_buf.send_to_gui(&sender);
+            duration_buf.flush(&send);
             sender.input(ClientInput::CommandCompleted);
+            result?;
         }
     } else {
         println!(
@@ -229,28 +394,114 @@ fn run_gui_client(
     Ok(())
 }
 
-fn send_command<W: Write, R: BufRead>(
-    write: &mut W,
-    read: &mut R,
+/// Like `send_command`, but once `deadline` passes before the response ends, sends `CANCEL` and
+/// reads the rest, which is whatever the server had sent by then plus a cancelled error frame
+fn send_command_with_deadline(
+    write: &mut BufWriter<TcpStream>,
+    read: &mut BufReader<TcpStream>,
     command: &Command,
     output_buf: &mut Vec<u8>,
-) -> io::Result<f32> {
+    deadline: Duration,
+) -> io::Result<(f32, Trailer)> {
     // send
     command.serialize(write)?;
     write.flush()?;
-    output_buf.clear();
 
     let start = Instant::now();
+    output_buf.clear();
+
+    // recv until the delimiter or the deadline, whichever comes first
+    let mut scanner = ResponseScanner::default();
+    let mut done = false;
+    while !done {
+        let remaining = deadline.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        read.get_ref().set_read_timeout(Some(remaining))?;
+        let available = match read.fill_buf() {
+            Ok(available) => available,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() {
+            break; // cut off, reported below
+        }
+        let used;
+        (used, done) = scanner.scan(available);
+        output_buf.extend_from_slice(&available[..used]);
+        read.consume(used);
+    }
+    read.get_ref().set_read_timeout(None)?;
 
-    // recv
-    read.read_until(0x00, output_buf)?;
-    let elapsed = Instant::now().duration_since(start).as_secs_f32();
+    if !done {
+        write.write_all(&[CANCEL])?;
+        write.flush()?;
+        done = read_until_end(read, output_buf, &mut scanner)?;
+    }
+    let elapsed = start.elapsed().as_secs_f32();
 
-    if output_buf.is_empty() || !output_buf.ends_with(b"\0") {
-        // connection was cut off
-        println!("Could not read response from server: Connection dropped");
-        return Err(io::ErrorKind::UnexpectedEof.into());
+    end_response(output_buf, done)?;
+    let trailer = take_trailer(output_buf);
+    Ok((elapsed, trailer))
+}
+
+/// A new connection to the server, configured like the first
+fn connect() -> io::Result<Connection> {
+    let args = ARGS.get().unwrap();
+    let stream = TcpStream::connect((args.hostname, args.port))?;
+    let mut read_half = BufReader::new(stream.try_clone()?);
+    let mut write_half = BufWriter::new(stream);
+    configure_connection(&mut write_half, &mut read_half, &mut Vec::new())?;
+    Ok((write_half, read_half))
+}
+
+/// Handshakes, then turns on what `--verbose-metrics` and `--binary` ask for. Returns the
+/// features the server agreed to.
+fn configure_connection<W: Write, R: BufRead>(
+    write: &mut W,
+    read: &mut R,
+    output_buf: &mut Vec<u8>,
+) -> io::Result<u32> {
+    let args = ARGS.get().unwrap();
+    let features = handshake(write, read, output_buf)?;
+    if args.verbose_metrics {
+        let command = Command::VERBOSE_METRICS { enabled: true };
+        send_command(write, read, &command, output_buf)?;
     }
-    output_buf.pop(); // \0
-    Ok(elapsed)
+    if args.binary && features & FEATURE_BINARY_RESULTS == 0 {
+        println!("Server doesn't support --binary, results come back as text");
+    } else if args.binary {
+        let command = Command::BINARY_RESULTS { enabled: true };
+        send_command(write, read, &command, output_buf)?;
+    }
+    Ok(features)
+}
+
+/// Asks for every feature this client knows, returns those the server has too. A server from
+/// before handshakes answers with an unknown command error, and is taken to have none of them.
+fn handshake<W: Write, R: BufRead>(
+    write: &mut W,
+    read: &mut R,
+    output_buf: &mut Vec<u8>,
+) -> io::Result<u32> {
+    write.write_all(&[HANDSHAKE])?;
+    write.write_all(&PROTOCOL_VERSION.to_be_bytes())?;
+    write.write_all(&CLIENT_FEATURES.to_be_bytes())?;
+    write.flush()?;
+    read_response(read, output_buf)?;
+    take_trailer(output_buf);
+
+    let reply = String::from_utf8_lossy(output_buf);
+    let features = reply
+        .split_once(' ')
+        .and_then(|(_, features)| features.parse::<u32>().ok());
+    Ok(features.unwrap_or(0) & CLIENT_FEATURES)
 }
@@ -0,0 +1,806 @@
+use std::fmt::Write;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::command::{bound_key, Command};
+
+#[derive(Deserialize, Debug)]
+struct TableDebugInfo {
+    file_name: String,
+    min_key: i32,
+    max_key: i32,
+    block_count: usize,
+    file_size: u64,
+    bloom_fp_rate: Option<f64>,
+    overlaps_above: bool,
+    overlaps_below: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct LevelStats {
+    level: u32,
+    tables: usize,
+    size_bytes: usize,
+    entries: usize,
+    utilization: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DatabaseStats {
+    logical_pairs: usize,
+    total_size_bytes: usize,
+    populated_levels: usize,
+    levels: Vec<LevelStats>,
+    fanout: Vec<LevelFanout>,
+    #[serde(default)]
+    compactions: Vec<LevelCompactionStats>,
+    key_histogram: Option<Vec<u64>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LevelCompactionStats {
+    level: u32,
+    runs: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    total_ms: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct LevelFanout {
+    level: u32,
+    avg: f32,
+    max: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct TableIndex {
+    file_name: String,
+    blocks: Vec<(i32, i32)>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LevelDump {
+    commands: Vec<String>,
+    truncated: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct LevelDebugInfo {
+    level: u32,
+    tables: Vec<TableDebugInfo>,
+}
+
+/// Marks an error frame in a response: this byte, a code byte, then the message
+const ERROR_FRAME: u8 = 0x01;
+
+/// Marks an event frame at the end of a response: this byte, then an event code
+const EVENT_FRAME: u8 = 0x02;
+/// Marks a metrics frame at the end of a read's response: this byte, then blocks read in decimal
+const METRICS_FRAME: u8 = 0x03;
+
+/// Marks a GET's value sent in binary: this byte, then the big-endian `i32`
+const BINARY_VALUE: u8 = 0x04;
+/// Marks range pairs sent in binary: this byte, a big-endian `u32` count, then the `(i32, i32)`
+/// pairs packed big-endian
+const BINARY_PAIRS: u8 = 0x05;
+
+/// Finds where a response ends. Binary frames come first and can hold 0x00, so they're stepped
+/// over by their length; after them the first 0x00 is the delimiter.
+#[derive(Default)]
+pub struct ResponseScanner {
+    state: ScanState,
+}
+
+#[derive(Default, Clone, Copy)]
+enum ScanState {
+    #[default]
+    Frames, // at the start, or right after a binary frame
+    Count(u32, usize), // pair count so far and how many of its bytes came
+    Payload(usize),    // bytes of a binary frame still to come
+    Text,
+}
+
+impl ResponseScanner {
+    /// Takes in bytes that arrived, returns how many of them belong to the response and whether
+    /// they end it. The rest are the start of the next response.
+    pub fn scan(&mut self, bytes: &[u8]) -> (usize, bool) {
+        let mut used = 0;
+        while used < bytes.len() {
+            if let ScanState::Payload(left) = self.state {
+                let taken = left.min(bytes.len() - used);
+                used += taken;
+                self.state = match left - taken {
+                    0 => ScanState::Frames,
+                    left => ScanState::Payload(left),
+                };
+                continue;
+            }
+
+            let byte = bytes[used];
+            used += 1;
+            self.state = match self.state {
+                ScanState::Frames if byte == BINARY_VALUE => ScanState::Payload(4),
+                ScanState::Frames if byte == BINARY_PAIRS => ScanState::Count(0, 0),
+                ScanState::Count(count, 3) => match count << 8 | byte as u32 {
+                    0 => ScanState::Frames,
+                    count => ScanState::Payload(count as usize * 8),
+                },
+                ScanState::Count(count, got) => ScanState::Count(count << 8 | byte as u32, got + 1),
+                _ if byte == 0x00 => {
+                    self.state = ScanState::Frames;
+                    return (used, true);
+                }
+                _ => ScanState::Text,
+            };
+        }
+        (used, false)
+    }
+}
+
+/// Rewrites the binary frames at the start of `output` as the text the server sends without
+/// binary results: the value's digits, or "key:value " pairs
+pub fn decode_binary(output: &mut Vec<u8>) {
+    let int_at = |pos: usize| -> Option<i32> {
+        Some(i32::from_be_bytes(
+            output.get(pos..pos + 4)?.try_into().unwrap(),
+        ))
+    };
+    let mut text = String::new();
+    let mut pos = 0;
+    loop {
+        match output.get(pos) {
+            Some(&BINARY_VALUE) => {
+                let Some(val) = int_at(pos + 1) else { break };
+                write!(text, "{val}").unwrap();
+                pos += 5;
+            }
+            Some(&BINARY_PAIRS) => {
+                let Some(count) = int_at(pos + 1) else { break };
+                let end = pos + 5 + count as u32 as usize * 8;
+                if end > output.len() {
+                    break;
+                }
+                for pair in output[pos + 5..end].chunks_exact(8) {
+                    let key = i32::from_be_bytes(pair[..4].try_into().unwrap());
+                    let val = i32::from_be_bytes(pair[4..].try_into().unwrap());
+                    write!(text, "{key}:{val} ").unwrap();
+                }
+                pos = end;
+            }
+            _ => break,
+        }
+    }
+    if pos > 0 {
+        output.splice(..pos, text.into_bytes());
+    }
+}
+
+/// Something the server did since the previous response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerEvent {
+    CompactionStarted,
+    CompactionFinished,
+}
+
+/// What the server sent along with a response
+#[derive(Debug, Default)]
+pub struct Trailer {
+    pub events: Vec<ServerEvent>,
+    pub blocks_read: Option<u64>, // only for reads, with verbose metrics on
+}
+
+/// Removes the metrics and event frames from the end of `output`, leaving the response itself
+pub fn take_trailer(output: &mut Vec<u8>) -> Trailer {
+    let mut trailer = Trailer::default();
+    let is_frame = |b: &u8| *b == EVENT_FRAME || *b == METRICS_FRAME;
+    let mut start = 0;
+    loop {
+        match output.get(start) {
+            None => return trailer,
+            // its code byte could pass for a frame marker
+            Some(&ERROR_FRAME) => start += 2,
+            Some(b) if is_frame(b) => break,
+            Some(_) => start += 1,
+        }
+    }
+
+    let mut frames = &output[start..];
+    while let Some((&marker, rest)) = frames.split_first() {
+        let len = match marker {
+            METRICS_FRAME => {
+                let len = rest.iter().position(is_frame).unwrap_or(rest.len());
+                let digits = String::from_utf8_lossy(&rest[..len]);
+                trailer.blocks_read = digits.parse().ok();
+                len
+            }
+            _ => {
+                match rest.first() {
+                    Some(1) => trailer.events.push(ServerEvent::CompactionStarted),
+                    Some(2) => trailer.events.push(ServerEvent::CompactionFinished),
+                    _ => {}
+                }
+                rest.len().min(1)
+            }
+        };
+        frames = &rest[len..];
+    }
+    output.truncate(start);
+    trailer
+}
+
+/// Code name and message of the error frame in `output`, if the command failed
+pub fn parse_error(output: &[u8]) -> Option<(&'static str, String)> {
+    let start = output.iter().position(|&b| b == ERROR_FRAME)?;
+    let (&code, message) = output[start + 1..].split_first()?;
+    let code = match code {
+        1 => "quota_exceeded",
+        2 => "reset_disabled",
+        3 => "no_such_level",
+        4 => "io",
+        5 => "unknown_command",
+        6 => "cancelled",
+        7 => "out_of_bounds",
+        8 => "read_only",
+        9 => "no_range",
+        10 => "replica",
+        11 => "not_negotiated",
+        12 => "ingest",
+        13 => "pairs_out_of_bounds",
+        _ => "unknown",
+    };
+    Some((code, String::from_utf8_lossy(message).into_owned()))
+}
+
+/// Turns the raw response to `command` into something readable
+pub fn format_response(command: &Command, output: &[u8]) -> String {
+    if let Some((code, message)) = parse_error(output) {
+        return format!("ERROR ({code}): {message}");
+    }
+    let text = String::from_utf8_lossy(output);
+
+    match command {
+        Command::DEBUG_TABLES => match serde_json::from_str::<Vec<LevelDebugInfo>>(&text) {
+            Ok(levels) => format_debug_tables(&levels),
+            Err(_) => text.into_owned(),
+        },
+        Command::STATS_JSON { .. } => match serde_json::from_str::<DatabaseStats>(&text) {
+            Ok(stats) => format_stats(&stats),
+            Err(_) => text.into_owned(),
+        },
+        Command::SIZE => format_size(&text).unwrap_or_else(|| text.into_owned()),
+        Command::BLOCK_INDEX { .. } => match serde_json::from_str::<Vec<TableIndex>>(&text) {
+            Ok(tables) => format_block_index(&tables),
+            Err(_) => text.into_owned(),
+        },
+        Command::LEVEL_DUMP { .. } => match serde_json::from_str::<LevelDump>(&text) {
+            Ok(dump) => format_level_dump(&dump),
+            Err(_) => text.into_owned(),
+        },
+        _ => text.into_owned(),
+    }
+}
+
+/// One JSON object describing the command and its result, for tools driving the client
+pub fn json_response(command: &Command, output: &[u8], latency_ns: u64) -> Value {
+    let text = String::from_utf8_lossy(output);
+
+    let mut obj = match command {
+        Command::PUT { key, val } => json!({ "cmd": "put", "key": key, "value": val }),
+        Command::MERGE { key, delta } => json!({ "cmd": "merge", "key": key, "delta": delta }),
+        Command::GET { key } => json!({ "cmd": "get", "key": key }),
+        Command::DELETE { key } => json!({ "cmd": "delete", "key": key }),
+        Command::DELETE_RANGE { min_key, max_key } => {
+            json!({ "cmd": "delete_range", "min_key": min_key, "max_key": max_key })
+        }
+        Command::LOAD { file } => json!({ "cmd": "load", "file": file }),
+        Command::RANGE { bounds, tombstones } => json!({
+            "cmd": "range",
+            "min_key": bound_key(&bounds.0),
+            "max_key": bound_key(&bounds.1),
+            "tombstones": tombstones
+        }),
+        Command::RANGE_SEEK { key } => json!({ "cmd": "range_seek", "key": key }),
+        Command::RANGE_PAGE { bounds, limit } => json!({
+            "cmd": "range_page",
+            "min_key": bound_key(&bounds.0),
+            "max_key": bound_key(&bounds.1),
+            "limit": limit
+        }),
+        Command::RANGE_CONT { token, limit } => {
+            let token: String = token.iter().map(|b| format!("{b:02x}")).collect();
+            json!({ "cmd": "range_cont", "token": token, "limit": limit })
+        }
+        Command::EXISTS { key } => json!({ "cmd": "exists", "key": key }),
+        Command::MAYBE { key } => json!({ "cmd": "maybe", "key": key }),
+        Command::COUNT { start, end } => {
+            json!({ "cmd": "count", "min_key": bound_key(start), "max_key": bound_key(end) })
+        }
+        Command::STATS => json!({ "cmd": "stats" }),
+        Command::STATS_JSON { histogram } => json!({ "cmd": "stats_json", "histogram": histogram }),
+        Command::DEBUG_TABLES => json!({ "cmd": "debug_tables" }),
+        Command::FSCK => json!({ "cmd": "fsck" }),
+        Command::SIZE => json!({ "cmd": "size" }),
+        Command::SPLIT_POINTS { level } => json!({ "cmd": "split_points", "level": level }),
+        Command::BLOCK_INDEX { level } => json!({ "cmd": "block_index", "level": level }),
+        Command::LEVEL_DUMP { level, limit } => {
+            json!({ "cmd": "level_dump", "level": level, "limit": limit })
+        }
+        Command::INGEST { level, merge, dir } => {
+            json!({ "cmd": "ingest", "level": level, "merge": merge, "directory": dir })
+        }
+        Command::HOT_RANGE { min_key, max_key } => {
+            json!({ "cmd": "hot_range", "min_key": min_key, "max_key": max_key })
+        }
+        Command::RESET => json!({ "cmd": "reset" }),
+        Command::VERBOSE_METRICS { enabled } => {
+            json!({ "cmd": "verbose_metrics", "enabled": enabled })
+        }
+        Command::SHOW_TOMBSTONES { enabled } => {
+            json!({ "cmd": "show_tombstones", "enabled": enabled })
+        }
+        Command::RESET_COMPACTION_STATS => json!({ "cmd": "reset_compaction_stats" }),
+        Command::SUBSCRIBE { from } => json!({ "cmd": "subscribe", "from": from }),
+        Command::BINARY_RESULTS { enabled } => {
+            json!({ "cmd": "binary_results", "enabled": enabled })
+        }
+    };
+
+    if let Some((code, message)) = parse_error(output) {
+        obj["result"] = Value::Null;
+        obj["error"] = json!(message);
+        obj["code"] = json!(code);
+    } else {
+        obj["result"] = json_result(command, &text);
+    }
+    obj["latency_ns"] = json!(latency_ns);
+    obj
+}
+
+fn json_result(command: &Command, text: &str) -> Value {
+    let result = match command {
+        // nothing comes back on a miss
+        Command::GET { .. } if text.is_empty() => Some(Value::Null),
+        // with tombstones shown
+        Command::GET { .. } if text == "DELETED" => Some(json!("deleted")),
+        Command::GET { .. } => text.parse::<i32>().ok().map(|val| json!(val)),
+        Command::RANGE { .. } | Command::RANGE_SEEK { .. } => json_pairs(text),
+        Command::RANGE_PAGE { .. } | Command::RANGE_CONT { .. } => {
+            let (pairs, next) = match text.split_once("NEXT ") {
+                Some((pairs, token)) => (pairs, Some(token.trim())),
+                None => (text, None),
+            };
+            json_pairs(pairs).map(|pairs| json!({ "pairs": pairs, "next": next }))
+        }
+        Command::EXISTS { .. } | Command::MAYBE { .. } => {
+            text.parse::<bool>().ok().map(|exists| json!(exists))
+        }
+        Command::COUNT { .. } => text.parse::<u64>().ok().map(|count| json!(count)),
+        Command::DEBUG_TABLES
+        | Command::STATS_JSON { .. }
+        | Command::BLOCK_INDEX { .. }
+        | Command::LEVEL_DUMP { .. } => serde_json::from_str(text).ok(),
+        Command::SIZE => parse_size(text).map(|(bytes, entries, levels)| {
+            json!({ "disk_bytes": bytes, "mem_entries": entries, "populated_levels": levels })
+        }),
+        Command::SPLIT_POINTS { .. } => text
+            .split_whitespace()
+            .map(|key| key.parse::<i32>().ok())
+            .collect::<Option<Vec<_>>>()
+            .map(|keys| json!(keys)),
+        _ => None,
+    };
+
+    result.unwrap_or_else(|| json!(text))
+}
+
+/// A frame of a SUBSCRIBE feed: "<sequence> <OP> <args>" for a change, "GAP <n>" for `n` changes
+/// the server dropped because the client fell behind
+pub fn json_change(text: &str) -> Value {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let num = |idx: usize| parts.get(idx).and_then(|part| part.parse::<i64>().ok());
+    match parts.as_slice() {
+        ["GAP", ..] => json!({ "gap": num(1) }),
+        [_, "PUT", ..] => json!({ "seq": num(0), "op": "put", "key": num(2), "value": num(3) }),
+        [_, "DELETE", ..] => json!({ "seq": num(0), "op": "delete", "key": num(2) }),
+        [_, "DELETE_RANGE", ..] => json!({
+            "seq": num(0),
+            "op": "delete_range",
+            "min_key": num(2),
+            "max_key": num(3)
+        }),
+        [_, "MERGE", ..] => json!({ "seq": num(0), "op": "merge", "key": num(2), "delta": num(3) }),
+        _ => json!({ "change": text }),
+    }
+}
+
+/// "key:value " pairs as [key, value] arrays, with null for the value of a listed tombstone
+fn json_pairs(text: &str) -> Option<Value> {
+    text.split_whitespace()
+        .map(|pair| {
+            let (key, val) = pair.split_once(':')?;
+            let val = match val {
+                "DELETED" => Value::Null,
+                val => json!(val.parse::<i32>().ok()?),
+            };
+            Some(json!([key.parse::<i32>().ok()?, val]))
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(Value::Array)
+}
+
+/// Response is "<bytes on disk> <entries in memory> <populated disk levels>"
+fn parse_size(text: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = text.split(' ').map(|part| part.parse().ok());
+    Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+fn format_size(text: &str) -> Option<String> {
+    let (bytes, entries, levels) = parse_size(text)?;
+
+    Some(format!(
+        "On disk: {} ({bytes} bytes) across {levels} levels\nIn memory: {entries} entries",
+        human_bytes(bytes)
+    ))
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{size:.2} {unit}")
+}
+
+fn format_stats(stats: &DatabaseStats) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "{} logical pairs, {} on disk across {} levels",
+        stats.logical_pairs,
+        human_bytes(stats.total_size_bytes as u64),
+        stats.populated_levels
+    );
+    let _ = writeln!(
+        out,
+        "  {:<5} {:>7} {:>12} {:>10} {:>11}",
+        "level", "tables", "bytes", "entries", "utilization"
+    );
+    for level in stats.levels.iter() {
+        let _ = writeln!(
+            out,
+            "  {:<5} {:>7} {:>12} {:>10} {:>11}",
+            level.level,
+            level.tables,
+            level.size_bytes,
+            level.entries,
+            level
+                .utilization
+                .map_or("-".to_string(), |u| format!("{:.1}%", u * 100.0))
+        );
+    }
+    for fanout in stats.fanout.iter() {
+        let _ = writeln!(
+            out,
+            "Fanout LVL{}->LVL{}: avg {:.2}, max {}",
+            fanout.level,
+            fanout.level + 1,
+            fanout.avg,
+            fanout.max
+        );
+    }
+    if stats.compactions.iter().any(|c| c.runs > 0) {
+        let _ = writeln!(out, "Compactions into each level:");
+        let _ = writeln!(
+            out,
+            "  {:<5} {:>7} {:>12} {:>12} {:>10}",
+            "level", "runs", "read", "written", "time"
+        );
+        for c in stats.compactions.iter().filter(|c| c.runs > 0) {
+            let _ = writeln!(
+                out,
+                "  {:<5} {:>7} {:>12} {:>12} {:>10}",
+                c.level,
+                c.runs,
+                human_bytes(c.bytes_read),
+                human_bytes(c.bytes_written),
+                format!("{}ms", c.total_ms)
+            );
+        }
+    }
+    if let Some(buckets) = &stats.key_histogram {
+        let _ = writeln!(out, "Blocks by key, {} to {}:", i32::MIN, i32::MAX);
+        let _ = writeln!(out, "  {}", sparkline(buckets));
+    }
+    out.pop(); // trailing newline
+    out
+}
+
+/// One bar per bucket, scaled to the fullest
+fn sparkline(buckets: &[u64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    let max = buckets.iter().copied().max().unwrap_or(0).max(1);
+    buckets
+        .iter()
+        .map(|&count| match count {
+            0 => ' ',
+            _ => BARS[(count * (BARS.len() as u64 - 1) / max) as usize],
+        })
+        .collect()
+}
+
+fn format_block_index(tables: &[TableIndex]) -> String {
+    let mut out = String::new();
+
+    for t in tables {
+        let _ = writeln!(out, "{} ({} blocks)", t.file_name, t.blocks.len());
+        for (i, (min_key, max_key)) in t.blocks.iter().enumerate() {
+            let _ = writeln!(out, "  {i:>5} {min_key:>11} {max_key:>11}");
+        }
+    }
+
+    if out.is_empty() {
+        out.push_str("No tables in this level");
+    }
+    out
+}
+
+fn format_level_dump(dump: &LevelDump) -> String {
+    let mut out = dump.commands.join("\n");
+
+    if dump.commands.is_empty() {
+        out.push_str("No tables in this level");
+    }
+    if dump.truncated {
+        let _ = write!(out, "\n... stopped after {} commands", dump.commands.len());
+    }
+    out
+}
+
+fn format_debug_tables(levels: &[LevelDebugInfo]) -> String {
+    let mut out = String::new();
+
+    for level in levels.iter().filter(|l| !l.tables.is_empty()) {
+        let _ = writeln!(out, "LVL{} ({} tables)", level.level, level.tables.len());
+        let _ = writeln!(
+            out,
+            "  {:<24} {:>11} {:>11} {:>7} {:>10} {:>9} {:>6} {:>6}",
+            "file", "min", "max", "blocks", "bytes", "bloom fp", "above", "below"
+        );
+        for t in level.tables.iter() {
+            let _ = writeln!(
+                out,
+                "  {:<24} {:>11} {:>11} {:>7} {:>10} {:>9} {:>6} {:>6}",
+                t.file_name,
+                t.min_key,
+                t.max_key,
+                t.block_count,
+                t.file_size,
+                t.bloom_fp_rate
+                    .map_or("-".to_string(), |rate| format!("{rate:.4}")),
+                t.overlaps_above,
+                t.overlaps_below
+            );
+        }
+    }
+
+    if out.is_empty() {
+        out.push_str("No tables on disk");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{
+        decode_binary, format_stats, json_response, parse_error, sparkline, take_trailer,
+        DatabaseStats, ResponseScanner, ServerEvent, BINARY_PAIRS, BINARY_VALUE, ERROR_FRAME,
+        EVENT_FRAME, METRICS_FRAME,
+    };
+    use crate::command::Command;
+
+    /// What `--json` prints for `command` answered with `output`, read back
+    fn round_trip(command: &Command, output: &[u8]) -> Value {
+        let printed = json_response(command, output, 1500).to_string();
+        assert!(!printed.contains('\n'));
+        serde_json::from_str(&printed).unwrap()
+    }
+
+    #[test]
+    fn stats_json_formats_each_level() {
+        let stats: DatabaseStats = serde_json::from_value(json!({
+            "logical_pairs": 1005,
+            "total_size_bytes": 2048,
+            "populated_levels": 1,
+            "levels": [
+                { "level": 0, "tables": 0, "size_bytes": 60, "entries": 5, "utilization": 0.25 },
+                { "level": 1, "tables": 2, "size_bytes": 2048, "entries": 1000, "utilization": 0.5 },
+                { "level": 2, "tables": 0, "size_bytes": 0, "entries": 0, "utilization": null },
+            ],
+            "fanout": [],
+            "key_histogram": null,
+        }))
+        .unwrap();
+        let text = format_stats(&stats);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "1005 logical pairs, 2.00 KiB on disk across 1 levels"
+        );
+        assert_eq!(lines.len(), 5);
+        for (line, level) in lines[2..].iter().zip(["0", "1", "2"]) {
+            assert_eq!(line.split_whitespace().next(), Some(level));
+        }
+        assert!(lines[3].ends_with("50.0%") && lines[4].ends_with('-'));
+    }
+
+    #[test]
+    fn compactions_show_for_levels_that_had_any() {
+        let stats: DatabaseStats = serde_json::from_value(json!({
+            "logical_pairs": 0,
+            "total_size_bytes": 0,
+            "populated_levels": 0,
+            "levels": [],
+            "fanout": [],
+            "key_histogram": null,
+            "compactions": [
+                { "level": 1, "runs": 0, "bytes_read": 0, "bytes_written": 0, "total_ms": 0 },
+                { "level": 2, "runs": 3, "bytes_read": 3072, "bytes_written": 2048, "total_ms": 12 },
+            ],
+        }))
+        .unwrap();
+        let text = format_stats(&stats);
+        let table: Vec<&str> = text
+            .lines()
+            .skip_while(|line| !line.starts_with("Compactions"))
+            .collect();
+        assert_eq!(table.len(), 3, "{text}");
+        let row: Vec<&str> = table[2].split_whitespace().collect();
+        assert_eq!(row, ["2", "3", "3.00", "KiB", "2.00", "KiB", "12ms"]);
+
+        // none yet, no table
+        let mut stats = stats;
+        stats.compactions.truncate(1);
+        assert!(!format_stats(&stats).contains("Compactions"));
+    }
+
+    #[test]
+    fn skewed_histograms_show_in_the_sparkline() {
+        let mut buckets = vec![0; 64];
+        buckets[32] = 120;
+        buckets[33] = 15;
+        buckets[0] = 1;
+        let line = sparkline(&buckets);
+        let bars: Vec<char> = line.chars().collect();
+        assert_eq!(bars.len(), 64);
+        assert_eq!((bars[32], bars[33], bars[0]), ('█', '▁', '▁'));
+        assert_eq!(bars.iter().filter(|&&bar| bar == ' ').count(), 61);
+        assert_eq!(sparkline(&[0, 0]), "  ");
+    }
+
+    #[test]
+    fn events_come_off_the_end_of_any_response() {
+        let mut output = b"42".to_vec();
+        output.extend([EVENT_FRAME, 1, EVENT_FRAME, 2]);
+        let trailer = take_trailer(&mut output);
+        assert_eq!(
+            trailer.events,
+            [
+                ServerEvent::CompactionStarted,
+                ServerEvent::CompactionFinished
+            ]
+        );
+        assert_eq!(output, b"42");
+
+        // reset disabled and no such level have codes that look like frame markers
+        for code in [2, 3] {
+            let mut output = vec![ERROR_FRAME, code];
+            output.extend_from_slice(b"failed");
+            let error = output.clone();
+            assert!(take_trailer(&mut output).events.is_empty());
+            assert_eq!(output, error);
+
+            output.extend([EVENT_FRAME, 2]);
+            let trailer = take_trailer(&mut output);
+            assert_eq!(trailer.events, [ServerEvent::CompactionFinished]);
+            assert_eq!(output, error);
+            assert!(parse_error(&output).is_some());
+        }
+    }
+
+    #[test]
+    fn blocks_read_come_off_before_the_events() {
+        let mut output = b"7".to_vec();
+        output.push(METRICS_FRAME);
+        output.extend_from_slice(b"12");
+        output.extend([EVENT_FRAME, 1]);
+        let trailer = take_trailer(&mut output);
+        assert_eq!(trailer.blocks_read, Some(12));
+        assert_eq!(trailer.events, [ServerEvent::CompactionStarted]);
+        assert_eq!(output, b"7");
+
+        // a miss has nothing ahead of its metrics
+        let mut output = vec![METRICS_FRAME, b'0'];
+        assert_eq!(take_trailer(&mut output).blocks_read, Some(0));
+        assert!(output.is_empty());
+
+        let mut output = b"OK".to_vec();
+        assert_eq!(take_trailer(&mut output).blocks_read, None);
+    }
+
+    #[test]
+    fn binary_frames_decode_to_the_text_they_stand_for() {
+        // pairs whose bytes hold the delimiter, then a page token as text, then the next response
+        let mut response = vec![BINARY_PAIRS, 0, 0, 0, 2];
+        for int in [0, 256, -1, i32::MIN] {
+            response.extend_from_slice(&i32::to_be_bytes(int));
+        }
+        response.extend_from_slice(b"NEXT 00\0");
+        let next = [BINARY_VALUE, 0, 0, 1, 0, 0];
+
+        // however the bytes happen to arrive
+        for split in 0..response.len() {
+            let mut scanner = ResponseScanner::default();
+            let (used, done) = scanner.scan(&response[..split]);
+            assert_eq!((used, done), (split, false));
+            let rest = [&response[split..], &next].concat();
+            assert_eq!(scanner.scan(&rest), (response.len() - split, true));
+        }
+
+        let mut output = response[..response.len() - 1].to_vec();
+        decode_binary(&mut output);
+        assert_eq!(output, b"0:256 -1:-2147483648 NEXT 00");
+        let mut output = next[..5].to_vec();
+        assert_eq!(ResponseScanner::default().scan(&next), (6, true));
+        decode_binary(&mut output);
+        assert_eq!(output, b"256");
+    }
+
+    #[test]
+    fn json_results_round_trip() {
+        let get = Command::GET { key: 5 };
+        assert_eq!(
+            round_trip(&get, b"42"),
+            json!({ "cmd": "get", "key": 5, "result": 42, "latency_ns": 1500 })
+        );
+        assert_eq!(round_trip(&get, b"")["result"], Value::Null);
+        assert_eq!(round_trip(&get, b"DELETED")["result"], "deleted");
+
+        let range = Command::from_input("r 1 10 deleted").unwrap();
+        assert_eq!(
+            round_trip(&range, b"1:2 3:DELETED ")["result"],
+            json!([[1, 2], [3, null]])
+        );
+        let split_points = Command::SPLIT_POINTS { level: 1 };
+        assert_eq!(
+            round_trip(&split_points, b"100 200")["result"],
+            json!([100, 200])
+        );
+    }
+
+    #[test]
+    fn json_errors_round_trip() {
+        let mut output = vec![ERROR_FRAME, 3];
+        output.extend_from_slice(b"no such level");
+        let response = round_trip(&Command::SPLIT_POINTS { level: 9 }, &output);
+        assert_eq!(response["result"], Value::Null);
+        assert_eq!(response["error"], "no such level");
+        assert_eq!(response["code"], "no_such_level");
+    }
+}
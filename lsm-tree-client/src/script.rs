@@ -0,0 +1,90 @@
+use std::io::{self, BufRead, Write};
+
+/// Runs the commands in `script`, one per line, and writes each response to `out` after the number
+/// of the line it came from. `run_line` gives Ok with the response or Err with what went wrong,
+/// with `stop_on_error` the first Err ends the run. Blank lines are skipped.
+pub fn run_script<R: BufRead, W: Write>(
+    script: R,
+    out: &mut W,
+    line_numbers: bool,
+    stop_on_error: bool,
+    mut run_line: impl FnMut(&str) -> io::Result<Result<String, String>>,
+) -> io::Result<()> {
+    for (idx, line) in script.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if line_numbers {
+            write!(out, "{}: ", idx + 1)?;
+        }
+        match run_line(&line)? {
+            Ok(response) => writeln!(out, "{response}")?,
+            Err(err) => {
+                writeln!(out, "{err}")?;
+                if stop_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::run_script;
+
+    const SCRIPT: &str = "p 1 2\n\ng 1\nx 3\ng 2\n";
+
+    /// Echoes each line back, failing the ones starting with x
+    fn run(line_numbers: bool, stop_on_error: bool) -> String {
+        let mut out = vec![];
+        run_script(
+            SCRIPT.as_bytes(),
+            &mut out,
+            line_numbers,
+            stop_on_error,
+            |line| {
+                Ok(match line.starts_with('x') {
+                    true => Err(format!("Invalid command: {line}")),
+                    false => Ok(format!("ran {line}")),
+                })
+            },
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn results_follow_their_line_numbers() {
+        assert_eq!(
+            run(true, false),
+            "1: ran p 1 2\n3: ran g 1\n4: Invalid command: x 3\n5: ran g 2\n"
+        );
+        assert_eq!(
+            run(false, false),
+            "ran p 1 2\nran g 1\nInvalid command: x 3\nran g 2\n"
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_error_if_asked() {
+        assert_eq!(
+            run(true, true),
+            "1: ran p 1 2\n3: ran g 1\n4: Invalid command: x 3\n"
+        );
+    }
+
+    #[test]
+    fn connection_errors_end_the_run() {
+        let mut out = vec![];
+        let result = run_script(SCRIPT.as_bytes(), &mut out, true, false, |_| {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        });
+        assert!(result.is_err());
+    }
+}
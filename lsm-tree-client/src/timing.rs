@@ -0,0 +1,70 @@
+use hdrhistogram::Histogram;
+
+/// Latencies of the commands run so far, for `--timing`
+pub struct Timings {
+    latencies_ns: Histogram<u64>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self {
+            latencies_ns: Histogram::new(3).unwrap(),
+        }
+    }
+
+    pub fn record(&mut self, latency_ns: u64) {
+        self.latencies_ns += latency_ns;
+    }
+
+    /// Count, mean, p50 and p99 of every latency recorded, None before the first
+    pub fn summary(&self) -> Option<String> {
+        let latencies_ns = &self.latencies_ns;
+        if latencies_ns.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{} commands: mean {:.1} µs, p50 {:.1} µs, p99 {:.1} µs",
+            latencies_ns.len(),
+            latencies_ns.mean() / 1000.0,
+            latencies_ns.value_at_quantile(0.5) as f64 / 1000.0,
+            latencies_ns.value_at_quantile(0.99) as f64 / 1000.0
+        ))
+    }
+}
+
+/// What follows a response with `--timing`, with the blocks read if the server sent them
+pub fn latency_suffix(latency_ns: u64, blocks_read: Option<u64>) -> String {
+    let micros = latency_ns as f64 / 1000.0;
+    match blocks_read {
+        Some(blocks) => format!("  ({micros:.1} µs, {blocks} blocks)"),
+        None => format!("  ({micros:.1} µs)"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{latency_suffix, Timings};
+
+    #[test]
+    fn latencies_are_reported_with_each_response() {
+        assert_eq!(latency_suffix(12_345, None), "  (12.3 µs)");
+        assert_eq!(latency_suffix(2_000, Some(3)), "  (2.0 µs, 3 blocks)");
+    }
+
+    #[test]
+    fn summary_aggregates_every_latency() {
+        let mut timings = Timings::new();
+        assert_eq!(timings.summary(), None);
+
+        // 98 fast commands and two slow ones, which only the p99 sees
+        for _ in 0..98 {
+            timings.record(10_000);
+        }
+        timings.record(1_000_000);
+        timings.record(1_000_000);
+        assert_eq!(
+            timings.summary().unwrap(),
+            "100 commands: mean 29.8 µs, p50 10.0 µs, p99 1000.4 µs"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    mem,
+    net::TcpStream,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    command::{Command, CommandType},
+    response::{decode_binary, take_trailer, ResponseScanner, ServerEvent, Trailer},
+};
+
+/// Commands finished since the last batch sent to the plot
+#[derive(Debug)]
+pub struct LatencyBatch {
+    pub durations: Vec<f32>,
+    pub command_types: Vec<CommandType>,
+    pub events: Vec<(usize, ServerEvent)>, // index into durations of the command that reported it
+    pub blocks_read: Vec<(usize, u64)>,    // same, for reads with verbose metrics on
+}
+
+/// Both halves of a connection, handshaken and configured
+pub type Connection = (BufWriter<TcpStream>, BufReader<TcpStream>);
+
+pub struct DurationBuffer<const CAP: usize> {
+    durations: Vec<f32>,
+    command_types: Vec<CommandType>,
+    events: Vec<(usize, ServerEvent)>,
+    blocks_read: Vec<(usize, u64)>,
+}
+
+impl<const CAP: usize> DurationBuffer<CAP> {
+    pub fn new() -> Self {
+        Self {
+            durations: Vec::with_capacity(CAP),
+            command_types: Vec::with_capacity(CAP),
+            events: Vec::new(),
+            blocks_read: Vec::new(),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        (val, trailer): (f32, Trailer),
+        command_type: CommandType,
+        send: &impl Fn(LatencyBatch),
+    ) {
+        let index = self.durations.len();
+        self.events
+            .extend(trailer.events.into_iter().map(|event| (index, event)));
+        if let Some(blocks_read) = trailer.blocks_read {
+            self.blocks_read.push((index, blocks_read));
+        }
+        self.durations.push(val);
+        self.command_types.push(command_type);
+        if self.durations.len() == CAP {
+            self.flush(send);
+        }
+    }
+
+    /// Sends what's buffered, if anything
+    pub fn flush(&mut self, send: &impl Fn(LatencyBatch)) {
+        if self.durations.is_empty() {
+            return;
+        }
+        send(LatencyBatch {
+            durations: mem::take(&mut self.durations),
+            command_types: mem::take(&mut self.command_types),
+            events: mem::take(&mut self.events),
+            blocks_read: mem::take(&mut self.blocks_read),
+        });
+    }
+}
+
+/// Deals the workload out round robin to `connections` connections of their own, one thread each.
+/// Every thread sends its latencies as it goes, so they show up interleaved the way the server saw
+/// them. With a `rate`, each connection sends that many commands per second without waiting on
+/// responses.
+pub fn run_concurrent_workload(
+    commands: &[(CommandType, Command)],
+    connections: usize,
+    rate: Option<f64>,
+    connect: impl Fn() -> io::Result<Connection> + Sync,
+    send: &(impl Fn(LatencyBatch) + Sync),
+) -> io::Result<()> {
+    let start = Instant::now();
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..connections)
+            .map(|worker| {
+                let connect = &connect;
+                scope.spawn(move || -> io::Result<()> {
+                    let (mut write_half, mut read_half) = connect()?;
+                    let mut output_buf = Vec::new();
+                    let mut duration_buf = DurationBuffer::<1000>::new();
+
+                    let share = commands.iter().skip(worker).step_by(connections);
+                    if let Some(rate) = rate {
+                        return run_open_loop(write_half, read_half, share, rate, send);
+                    }
+                    for (command_type, command) in share {
+                        let response = send_command(
+                            &mut write_half,
+                            &mut read_half,
+                            command,
+                            &mut output_buf,
+                        )?;
+                        duration_buf.push(response, *command_type, send);
+                    }
+                    duration_buf.flush(send);
+                    Ok(())
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let target = rate.map_or(String::new(), |rate| {
+        format!(" (target {:.0})", rate * connections as f64)
+    });
+    println!(
+        "{} commands over {connections} connections in {elapsed:.2} s, {:.0} ops/s{target}",
+        commands.len(),
+        commands.len() as f64 / elapsed
+    );
+    Ok(())
+}
+
+/// Sends commands on a timer instead of after each response, which the server answers in order.
+/// A command's latency counts from when it was due, so a server falling behind shows up as
+/// queueing rather than as a slower send rate.
+pub fn run_open_loop<'a>(
+    mut write_half: BufWriter<TcpStream>,
+    mut read_half: BufReader<TcpStream>,
+    commands: impl Iterator<Item = &'a (CommandType, Command)>,
+    rate: f64,
+    send: &(impl Fn(LatencyBatch) + Sync),
+) -> io::Result<()> {
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    // due time of every command sent but not answered yet, oldest first
+    let (in_flight, answered) = mpsc::channel::<(Instant, CommandType)>();
+
+    thread::scope(|scope| {
+        let receiver = scope.spawn(move || -> io::Result<()> {
+            let mut output_buf = Vec::new();
+            let mut duration_buf = DurationBuffer::<1000>::new();
+            for (due, command_type) in answered {
+                read_response(&mut read_half, &mut output_buf)?;
+                let latency = due.elapsed().as_secs_f32();
+                let trailer = take_trailer(&mut output_buf);
+                duration_buf.push((latency, trailer), command_type, send);
+            }
+            duration_buf.flush(send);
+            Ok(())
+        });
+
+        let start = Instant::now();
+        for (i, (command_type, command)) in commands.enumerate() {
+            let due = start + interval.mul_f64(i as f64);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+            command.serialize(&mut write_half)?;
+            write_half.flush()?;
+            // only fails once the receiver gave up, which it reports below
+            let _ = in_flight.send((due, *command_type));
+        }
+        drop(in_flight);
+        receiver.join().unwrap()
+    })
+}
+
+pub fn send_command<W: Write, R: BufRead>(
+    write: &mut W,
+    read: &mut R,
+    command: &Command,
+    output_buf: &mut Vec<u8>,
+) -> io::Result<(f32, Trailer)> {
+    // send
+    command.serialize(write)?;
+    write.flush()?;
+
+    let start = Instant::now();
+
+    // recv
+    read_response(read, output_buf)?;
+    let elapsed = Instant::now().duration_since(start).as_secs_f32();
+
+    let trailer = take_trailer(output_buf);
+    Ok((elapsed, trailer))
+}
+
+/// Reads the next response into `output_buf`, without the delimiter
+pub fn read_response<R: BufRead>(read: &mut R, output_buf: &mut Vec<u8>) -> io::Result<()> {
+    output_buf.clear();
+    let done = read_until_end(read, output_buf, &mut ResponseScanner::default())?;
+    end_response(output_buf, done)
+}
+
+/// Reads what's left of a response, the delimiter included. Returns false if the connection was
+/// cut off first.
+pub fn read_until_end<R: BufRead>(
+    read: &mut R,
+    output_buf: &mut Vec<u8>,
+    scanner: &mut ResponseScanner,
+) -> io::Result<bool> {
+    loop {
+        let available = read.fill_buf()?;
+        if available.is_empty() {
+            return Ok(false);
+        }
+        let (used, done) = scanner.scan(available);
+        output_buf.extend_from_slice(&available[..used]);
+        read.consume(used);
+        if done {
+            return Ok(true);
+        }
+    }
+}
+
+/// Drops the delimiter of the response read into `output_buf`, and turns binary frames at its
+/// start into the text the server would have sent
+pub fn end_response(output_buf: &mut Vec<u8>, done: bool) -> io::Result<()> {
+    if !done {
+        // connection was cut off
+        println!("Could not read response from server: Connection dropped");
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    output_buf.pop(); // \0
+    decode_binary(output_buf);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufReader, BufWriter, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{run_concurrent_workload, Connection, LatencyBatch};
+    use crate::command::{Command, CommandType};
+
+    /// A server answering each PUT with OK after `delay`, one thread per connection
+    fn slow_server(delay: Duration) -> impl Fn() -> std::io::Result<Connection> + Sync {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut put = [0; 9];
+                    while stream.read_exact(&mut put).is_ok() {
+                        thread::sleep(delay);
+                        stream.write_all(b"OK\0").unwrap();
+                    }
+                });
+            }
+        });
+        move || {
+            let stream = TcpStream::connect(addr)?;
+            Ok((BufWriter::new(stream.try_clone()?), BufReader::new(stream)))
+        }
+    }
+
+    fn puts(n: i32) -> Vec<(CommandType, Command)> {
+        (0..n)
+            .map(|key| (CommandType::PUT, Command::PUT { key, val: key }))
+            .collect()
+    }
+
+    /// How long the workload took, and every latency it sent
+    fn run(connections: usize, rate: Option<f64>, delay: Duration) -> (Duration, Vec<f32>) {
+        let batches = Mutex::new(vec![]);
+        let start = Instant::now();
+        run_concurrent_workload(
+            &puts(200),
+            connections,
+            rate,
+            slow_server(delay),
+            &|batch: LatencyBatch| batches.lock().unwrap().push(batch),
+        )
+        .unwrap();
+        let elapsed = start.elapsed();
+        let batches = batches.into_inner().unwrap();
+        assert!(batches
+            .iter()
+            .all(|batch| batch.command_types.iter().all(|&t| t == CommandType::PUT)));
+        let latencies = batches.into_iter().flat_map(|batch| batch.durations);
+        (elapsed, latencies.collect())
+    }
+
+    #[test]
+    fn connections_share_the_workload_and_every_latency() {
+        let delay = Duration::from_millis(2);
+        let (alone, latencies) = run(1, None, delay);
+        assert_eq!(latencies.len(), 200);
+        let (shared, latencies) = run(4, None, delay);
+        assert_eq!(latencies.len(), 200);
+        assert!(latencies.iter().all(|&latency| latency >= 0.002));
+        // four at a time against a server that only waits
+        assert!(shared * 2 < alone, "{shared:?} vs {alone:?} alone");
+    }
+
+    #[test]
+    fn open_loop_sends_at_the_target_rate() {
+        for connections in [1, 2] {
+            // 200 commands at 2000 a second
+            let rate = 2000.0 / connections as f64;
+            let (elapsed, latencies) = run(connections, Some(rate), Duration::ZERO);
+            assert_eq!(latencies.len(), 200);
+            assert!(
+                elapsed >= Duration::from_millis(99) && elapsed < Duration::from_millis(300),
+                "{elapsed:?}"
+            );
+        }
+    }
+}
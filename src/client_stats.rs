@@ -3,12 +3,15 @@ use std::net::SocketAddr;
 use chrono::Local;
 use hdrhistogram::Histogram;
 
+use crate::database::ReadMetrics;
+
 pub struct ClientStats {
     start_time: Option<String>, // time of first request
     addr: SocketAddr,
-    database_size: Option<usize>, // at start
-    latencies_ns: Histogram<u64>, // per request
-    blocks_read: Histogram<u64>,  // per request
+    database_size: Option<usize>,   // at start
+    latencies_ns: Histogram<u64>,   // per request
+    blocks_read: Histogram<u64>,    // per request
+    levels_touched: Histogram<u64>, // per read request
     num_requests: u32,
 }
 
@@ -20,10 +23,16 @@ impl ClientStats {
             database_size: None,
             latencies_ns: Histogram::new(3).unwrap(),
             blocks_read: Histogram::new(3).unwrap(),
+            levels_touched: Histogram::new(3).unwrap(),
             num_requests: 0,
         }
     }
 
+    /// Whether the first request came in yet
+    pub fn started(&self) -> bool {
+        self.start_time.is_some()
+    }
+
     pub fn begin(&mut self, database_size: usize) {
         if self.start_time.is_none() {
             self.start_time = Some(Local::now().format("%H__%M__%S%.6f").to_string());
@@ -36,8 +45,9 @@ impl ClientStats {
         self.num_requests += 1;
     }
 
-    pub fn record_blocks_read(&mut self, blocks: u64) {
-        self.blocks_read += blocks;
+    pub fn record_read(&mut self, metrics: ReadMetrics) {
+        self.blocks_read += metrics.blocks_read;
+        self.levels_touched += metrics.levels_touched;
     }
 
     pub fn save_to_file(self) {
@@ -74,6 +84,7 @@ impl ClientStats {
             end_time: String,
             latencies_ns: Percentiles,
             blocks_read: Percentiles,
+            levels_touched: Percentiles,
             database_size: usize,
             num_requests: u32,
         }
@@ -84,6 +95,7 @@ impl ClientStats {
             end_time: Local::now().format("%H:%M:%S%.6f").to_string(),
             latencies_ns: Percentiles::from_histogram(&self.latencies_ns),
             blocks_read: Percentiles::from_histogram(&self.blocks_read),
+            levels_touched: Percentiles::from_histogram(&self.levels_touched),
             num_requests: self.num_requests,
             database_size: self.database_size.unwrap_or_default(),
         };
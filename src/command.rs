@@ -1,66 +1,431 @@
-use std::i32;
+use futures::StreamExt;
+use std::fmt::Display;
+use std::ops::Bound;
+use std::path::Path;
 use tokio::io;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncReadExt;
 
 use crate::connection::Connection;
-use crate::database::Database;
+use crate::database::{Database, IngestError, KeyState, ReadMetrics, WriteError};
+
+/// Written in place of the usual response when a command fails: this byte, a code byte, then the
+/// message. The 0x00 delimiter follows as usual, so the connection stays usable.
+pub const ERROR_FRAME: u8 = 0x01;
+
+/// Appended to a response, before the 0x00 delimiter, for each kind of event that happened on the
+/// server since the connection's last response: this byte, then an event code
+pub const EVENT_FRAME: u8 = 0x02;
+pub const EVENT_COMPACTION_STARTED: u8 = 1;
+pub const EVENT_COMPACTION_FINISHED: u8 = 2;
+
+/// Appended to the response of a read, before any event frames, once the client turned on verbose
+/// metrics: this byte, then the blocks the read went through as decimal text
+pub const METRICS_FRAME: u8 = 0x03;
+
+/// With binary results on, a GET's value is this byte followed by the `i32`, and a range's pairs
+/// are sent in frames of this other byte, a `u32` pair count, then the packed `(i32, i32)` pairs.
+/// Binary frames come first in a response, ahead of any text, and can hold 0x00.
+pub const BINARY_VALUE: u8 = 0x04;
+pub const BINARY_PAIRS: u8 = 0x05;
+
+/// Sent as the first byte of a connection to agree on what it can use: this byte, the client's
+/// protocol version as a `u16`, then the features it wants as `u32` bits. The server answers with
+/// the version both speak and the features both support, as "<version> <features>" text. A
+/// connection opening with anything else gets every feature, as before handshakes existed, and
+/// speaks version 0: a RANGE there is just its min and max key, without a flags byte.
+pub const HANDSHAKE: u8 = 0x16;
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const FEATURE_BINARY_RESULTS: u32 = 1;
+pub const FEATURE_TOMBSTONES: u32 = 1 << 1; // GET answering DELETED, RANGE listing deleted keys
+pub const FEATURE_CHANGE_FEED: u32 = 1 << 2;
+pub const SUPPORTED_FEATURES: u32 =
+    FEATURE_BINARY_RESULTS | FEATURE_TOMBSTONES | FEATURE_CHANGE_FEED;
+
+/// Sent by the client while a response is still coming to stop the command behind it. Only
+/// noticed while no other command of the client is waiting ahead of it; the response then ends
+/// with a cancelled error frame. One arriving after its response ended is skipped.
+pub const CANCEL: u8 = 0x18;
+
+// Most commands a LEVEL_DUMP sends, whatever limit the client asks for
+const MAX_LEVEL_DUMP: u32 = 100_000;
+
+// Memory a key resolved by a range takes until it's sent: its entry in the map levels are resolved
+// into and in the sorted copy, with room for the map's spare capacity
+const RANGE_ENTRY_BYTES: usize = 32;
+
+#[derive(Debug)]
+pub enum CommandError {
+    QuotaExceeded,
+    ResetDisabled,
+    NoSuchLevel,
+    UnknownCommand(u8),
+    Cancelled,
+    OutOfBounds,
+    PairsOutOfBounds(u64), // skipped by a LOAD, the rest were stored
+    ReadOnly,
+    NoRange, // RANGE_SEEK before any RANGE
+    Replica,
+    NotNegotiated,  // needs a feature the handshake left out
+    Ingest(String), // why INGEST turned the tables away
+    Io(io::Error),
+}
+
+impl CommandError {
+    /// Code sent in the error frame, never 0 so it can't be taken for the delimiter
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::QuotaExceeded => 1,
+            Self::ResetDisabled => 2,
+            Self::NoSuchLevel => 3,
+            Self::Io(_) => 4,
+            Self::UnknownCommand(_) => 5,
+            Self::Cancelled => 6,
+            Self::OutOfBounds => 7,
+            Self::ReadOnly => 8,
+            Self::NoRange => 9,
+            Self::Replica => 10,
+            Self::NotNegotiated => 11,
+            Self::Ingest(_) => 12,
+            Self::PairsOutOfBounds(_) => 13,
+        }
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::QuotaExceeded => write!(f, "{}", WriteError::QuotaExceeded),
+            Self::ResetDisabled => write!(f, "{}", WriteError::ResetDisabled),
+            Self::NoSuchLevel => write!(f, "no such level"),
+            Self::UnknownCommand(tag) => write!(f, "unknown command {:?}", *tag as char),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::OutOfBounds => write!(f, "{}", WriteError::OutOfBounds),
+            Self::ReadOnly => write!(f, "{}", WriteError::ReadOnly),
+            Self::Replica => write!(f, "{}", WriteError::Replica),
+            Self::NotNegotiated => write!(f, "feature not negotiated in the handshake"),
+            Self::NoRange => write!(f, "no range to seek in, send a RANGE first"),
+            Self::PairsOutOfBounds(skipped) => {
+                write!(f, "skipped {skipped} pairs outside the configured bounds")
+            }
+            Self::Ingest(reason) => write!(f, "{reason}"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<WriteError> for CommandError {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::QuotaExceeded => Self::QuotaExceeded,
+            WriteError::ResetDisabled => Self::ResetDisabled,
+            WriteError::OutOfBounds => Self::OutOfBounds,
+            WriteError::ReadOnly => Self::ReadOnly,
+            WriteError::Replica => Self::Replica,
+        }
+    }
+}
+
+impl From<IngestError> for CommandError {
+    fn from(err: IngestError) -> Self {
+        match err {
+            IngestError::Write(err) => err.into(),
+            IngestError::NoSuchLevel => Self::NoSuchLevel,
+            IngestError::Io(err) => Self::Io(err),
+            err => Self::Ingest(err.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for CommandError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Io(err.into())
+    }
+}
+
+/// Start and end of a range
+pub type Bounds = (Bound<i32>, Bound<i32>);
 
 #[derive(Clone, Debug)]
+#[allow(non_camel_case_types)]
 pub enum Command {
     PUT { key: i32, val: i32 },
+    MERGE { key: i32, delta: i32 },
     GET { key: i32 },
     DELETE { key: i32 },
+    DELETE_RANGE { min_key: i32, max_key: i32 },
     LOAD { kv_pairs: u64 },
-    RANGE { min_key: i32, max_key: i32 },
+    RANGE { bounds: Bounds, tombstones: bool },
+    RANGE_SEEK { key: i32 },
+    RANGE_PAGE { bounds: Bounds, limit: u32 },
+    RANGE_CONT { token: PageToken, limit: u32 },
+    EXISTS { key: i32 },
+    MAYBE { key: i32 },
+    COUNT { start: Bound<i32>, end: Bound<i32> },
     STATS,
+    STATS_JSON { histogram: bool },
+    DEBUG_TABLES,
+    FSCK,
+    SIZE,
+    SPLIT_POINTS { level: u8 },
+    BLOCK_INDEX { level: u8 },
+    LEVEL_DUMP { level: u8, limit: u32 },
+    INGEST { level: u8, merge: bool, dir: String },
+    HOT_RANGE { min_key: i32, max_key: i32 },
+    RESET,
+    VERBOSE_METRICS { enabled: bool },
+    SHOW_TOMBSTONES { enabled: bool },
+    RESET_COMPACTION_STATS,
+    SUBSCRIBE { from: u64 },
+    BINARY_RESULTS { enabled: bool },
 }
 
 impl Command {
-    pub async fn execute(self, connection: &mut Connection, db: &Database) -> io::Result<()> {
+    pub async fn execute(
+        self,
+        connection: &mut Connection,
+        db: &Database,
+    ) -> Result<(), CommandError> {
+        if self
+            .feature()
+            .is_some_and(|feature| connection.features & feature == 0)
+        {
+            return Err(CommandError::NotNegotiated);
+        }
         match self {
             Self::GET { key } => {
-                if let Some(val) = db.get(key, &mut connection.stats).await {
-                    connection.write_int(val).await?;
+                let (state, metrics) = db.get_state(key).await;
+                connection.record_read(metrics);
+                match state {
+                    KeyState::Value(val) => connection.write_value(val).await?,
+                    KeyState::Deleted if connection.show_tombstones => {
+                        connection.write_str("DELETED").await?
+                    }
+                    KeyState::Deleted | KeyState::Absent => {}
                 }
             }
             Self::DELETE { key } => {
+                db.check_writable()?;
                 db.delete(key).await;
                 connection.write_str("OK").await?;
             }
+            Self::DELETE_RANGE { min_key, max_key } => {
+                db.check_writable()?;
+                db.delete_range(min_key, max_key).await;
+                connection.write_str("OK").await?;
+            }
             Self::PUT { key, val } => {
+                db.check_writable()?;
+                db.check_bounds(key, Some(val))?;
+                db.check_quota().await?;
                 db.insert(key, val).await;
                 connection.write_str("OK").await?;
             }
-            Self::LOAD { kv_pairs } => {
-                db.load(kv_pairs, &mut connection.reader).await?;
+            Self::MERGE { key, delta } => {
+                // the value it ends up adding onto isn't known until read, only the key is checked
+                db.check_writable()?;
+                db.check_bounds(key, None)?;
+                db.check_quota().await?;
+                db.merge(key, delta).await;
                 connection.write_str("OK").await?;
             }
-            Self::RANGE { min_key, max_key } => {
-                if let Some(iter) = db.range(min_key, max_key - 1, &mut connection.stats).await {
-                    for (key, val) in iter {
-                        connection.write_int(key).await?;
-                        connection.write_str(":").await?;
-                        connection.write_int(val).await?;
-                        connection.write_str(" ").await?;
+            Self::LOAD { kv_pairs } => {
+                let allowed = match db.check_writable() {
+                    Ok(()) => db.check_quota().await,
+                    err => err,
+                };
+                match allowed {
+                    Ok(()) => {
+                        let skipped = db.load(kv_pairs, &mut connection.reader).await?;
+                        if skipped > 0 {
+                            return Err(CommandError::PairsOutOfBounds(skipped));
+                        }
+                        connection.write_str("OK").await?;
+                    }
+                    Err(err) => {
+                        // the pairs are still on the wire, skip over them
+                        let mut pairs = (&mut connection.reader).take(kv_pairs.saturating_mul(8));
+                        io::copy(&mut pairs, &mut io::sink()).await?;
+                        return Err(err.into());
                     }
                 }
             }
+            Self::RANGE { bounds, tombstones } => {
+                connection.last_range = Some((bounds, tombstones));
+                write_range(connection, db, bounds.0, bounds.1, tombstones).await?;
+            }
+            Self::RANGE_SEEK { key } => {
+                let Some(((start, end), tombstones)) = connection.last_range else {
+                    return Err(CommandError::NoRange);
+                };
+                // every level locates its first block from the new start, nothing before it is read
+                write_range(connection, db, seek(start, key), end, tombstones).await?;
+            }
+            Self::RANGE_PAGE { bounds, limit } => {
+                write_page(connection, db, bounds.0, bounds.1, limit).await?;
+            }
+            Self::RANGE_CONT { token, limit } => {
+                let start = Bound::Included(token.next_key);
+                write_page(connection, db, start, token.end, limit).await?;
+            }
+            Self::EXISTS { key } => {
+                let (val, metrics) = db.get(key).await;
+                connection.record_read(metrics);
+                connection.write_str(&val.is_some().to_string()).await?;
+            }
+            Self::MAYBE { key } => {
+                let (maybe, metrics) = db.maybe_contains(key).await;
+                connection.record_read(metrics);
+                connection.write_str(&maybe.to_string()).await?;
+            }
+            Self::COUNT { start, end } => {
+                let (iter, metrics) = db.range(start, end, || connection.cancel_requested()).await;
+                connection.record_read(metrics);
+                if connection.cancel_requested() {
+                    return Err(CommandError::Cancelled);
+                }
+                let count = iter.map_or(0, |iter| iter.count());
+                connection.write_str(&count.to_string()).await?;
+            }
             Self::STATS => {
                 db.write_stats(connection).await?;
             }
+            Self::STATS_JSON { histogram } => {
+                let stats = db.stats(histogram).await;
+                connection
+                    .write_str(&serde_json::to_string(&stats)?)
+                    .await?;
+            }
+            Self::DEBUG_TABLES => {
+                let levels = db.debug_tables().await;
+                connection
+                    .write_str(&serde_json::to_string(&levels)?)
+                    .await?;
+            }
+            Self::SIZE => {
+                // "<bytes on disk> <entries in memory> <populated disk levels>"
+                connection
+                    .write_str(&db.disk_size_bytes().await.to_string())
+                    .await?;
+                connection.write_str(" ").await?;
+                connection
+                    .write_str(&db.mem_len().await.to_string())
+                    .await?;
+                connection.write_str(" ").await?;
+                connection
+                    .write_str(&db.populated_levels().await.to_string())
+                    .await?;
+            }
+            Self::SPLIT_POINTS { level } => {
+                let keys = db
+                    .split_points(level as usize)
+                    .await
+                    .ok_or(CommandError::NoSuchLevel)?;
+                let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+                connection.write_str(&keys.join(" ")).await?;
+            }
+            Self::BLOCK_INDEX { level } => {
+                let tables = db
+                    .block_index(level as usize)
+                    .await
+                    .ok_or(CommandError::NoSuchLevel)?;
+                connection
+                    .write_str(&serde_json::to_string(&tables)?)
+                    .await?;
+            }
+            Self::LEVEL_DUMP { level, limit } => {
+                let dump = db
+                    .level_dump(level as usize, limit.min(MAX_LEVEL_DUMP) as usize)
+                    .await
+                    .ok_or(CommandError::NoSuchLevel)?;
+                connection.write_str(&serde_json::to_string(&dump)?).await?;
+            }
+            Self::INGEST { level, merge, dir } => {
+                let ingested = db.ingest(level as usize, Path::new(&dir), merge).await?;
+                connection.write_str(&format!("OK {ingested}")).await?;
+            }
+            Self::HOT_RANGE { min_key, max_key } => {
+                db.add_hot_range(min_key, max_key);
+                connection.write_str("OK").await?;
+            }
+            Self::RESET => {
+                db.reset().await?;
+                connection.write_str("OK").await?;
+            }
+            Self::VERBOSE_METRICS { enabled } => {
+                connection.verbose_metrics = enabled;
+                connection.write_str("OK").await?;
+            }
+            Self::SHOW_TOMBSTONES { enabled } => {
+                connection.show_tombstones = enabled;
+                connection.write_str("OK").await?;
+            }
+            Self::BINARY_RESULTS { enabled } => {
+                connection.binary_results = enabled;
+                connection.write_str("OK").await?;
+            }
+            Self::RESET_COMPACTION_STATS => {
+                db.reset_compaction_stats();
+                connection.write_str("OK").await?;
+            }
+            Self::SUBSCRIBE { from } => {
+                connection.forward_changes(db, from).await?;
+            }
+            Self::FSCK => {
+                let violations = db.check().await;
+                if violations.is_empty() {
+                    connection.write_str("OK").await?;
+                }
+                for violation in violations {
+                    connection.write_str(&violation.to_string()).await?;
+                    connection.write_str("\n").await?;
+                }
+            }
         }
         Ok(())
     }
+
+    /// Feature of the handshake the command needs, turning one off never does
+    fn feature(&self) -> Option<u32> {
+        match self {
+            Self::BINARY_RESULTS { enabled: true } => Some(FEATURE_BINARY_RESULTS),
+            Self::SHOW_TOMBSTONES { enabled: true }
+            | Self::RANGE {
+                tombstones: true, ..
+            } => Some(FEATURE_TOMBSTONES),
+            Self::SUBSCRIBE { .. } => Some(FEATURE_CHANGE_FEED),
+            _ => None,
+        }
+    }
 }
 
-pub async fn read_command<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Result<Command> {
-    Ok(match reader.read_u8().await? {
+/// Reads the next command of a client speaking protocol `version`
+pub async fn read_command<T: AsyncBufReadExt + Unpin>(
+    reader: &mut T,
+    version: u16,
+) -> Result<Command, CommandError> {
+    let mut tag = reader.read_u8().await?;
+    while tag == CANCEL {
+        // too late, the command it was meant for already finished
+        tag = reader.read_u8().await?;
+    }
+    Ok(match tag {
         b'p' => {
             let key = reader.read_i32().await?;
             let val = reader.read_i32().await?;
             Command::PUT { key, val }
         }
+        b'm' => {
+            let key = reader.read_i32().await?;
+            let delta = reader.read_i32().await?;
+            Command::MERGE { key, delta }
+        }
         b'g' => {
             let key = reader.read_i32().await?;
             Command::GET { key }
@@ -69,21 +434,295 @@ pub async fn read_command<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Res
             let key = reader.read_i32().await?;
             Command::DELETE { key }
         }
+        b'x' => {
+            let min_key = reader.read_i32().await?;
+            let max_key = reader.read_i32().await?;
+            Command::DELETE_RANGE { min_key, max_key }
+        }
         b'l' => {
             let kv_pairs = reader.read_u64().await?;
             Command::LOAD { kv_pairs }
         }
-        b'r' => {
+        b'r' if version == 0 => {
             let min_key = reader.read_i32().await?;
             let max_key = reader.read_i32().await?;
-            Command::RANGE { min_key, max_key }
+            Command::RANGE {
+                bounds: (Bound::Included(min_key), Bound::Excluded(max_key)),
+                tombstones: false,
+            }
+        }
+        b'r' => {
+            let (bounds, flags) = read_bounds(reader).await?;
+            Command::RANGE {
+                bounds,
+                tombstones: flags & RANGE_TOMBSTONES != 0,
+            }
+        }
+        b'q' => {
+            let key = reader.read_i32().await?;
+            Command::RANGE_SEEK { key }
+        }
+        b'b' => {
+            let (bounds, _) = read_bounds(reader).await?;
+            let limit = reader.read_u32().await?;
+            Command::RANGE_PAGE { bounds, limit }
+        }
+        b'y' => {
+            let mut token = [0; PageToken::LEN];
+            reader.read_exact(&mut token).await?;
+            let limit = reader.read_u32().await?;
+            Command::RANGE_CONT {
+                token: PageToken::from_bytes(token),
+                limit,
+            }
+        }
+        b'e' => {
+            let key = reader.read_i32().await?;
+            Command::EXISTS { key }
+        }
+        b'M' => {
+            let key = reader.read_i32().await?;
+            Command::MAYBE { key }
+        }
+        b'n' => {
+            let ((start, end), _) = read_bounds(reader).await?;
+            Command::COUNT { start, end }
         }
         b's' => Command::STATS,
-        _ => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid incoming command!",
-            ))
+        b'j' => {
+            let histogram = reader.read_u8().await? != 0;
+            Command::STATS_JSON { histogram }
+        }
+        b't' => Command::DEBUG_TABLES,
+        b'f' => Command::FSCK,
+        b'z' => Command::SIZE,
+        b'c' => Command::RESET,
+        b'w' => Command::RESET_COMPACTION_STATS,
+        b'u' => {
+            let from = reader.read_u64().await?;
+            Command::SUBSCRIBE { from }
+        }
+        b'k' => {
+            let level = reader.read_u8().await?;
+            Command::SPLIT_POINTS { level }
+        }
+        b'i' => {
+            let level = reader.read_u8().await?;
+            Command::BLOCK_INDEX { level }
+        }
+        b'L' => {
+            let level = reader.read_u8().await?;
+            let limit = reader.read_u32().await?;
+            Command::LEVEL_DUMP { level, limit }
+        }
+        b'I' => {
+            let level = reader.read_u8().await?;
+            let merge = reader.read_u8().await? != 0;
+            let len = reader.read_u16().await?;
+            let mut dir = vec![0; len as usize];
+            reader.read_exact(&mut dir).await?;
+            Command::INGEST {
+                level,
+                merge,
+                dir: String::from_utf8_lossy(&dir).into_owned(),
+            }
+        }
+        b'h' => {
+            let min_key = reader.read_i32().await?;
+            let max_key = reader.read_i32().await?;
+            Command::HOT_RANGE { min_key, max_key }
+        }
+        b'v' => {
+            let enabled = reader.read_u8().await? != 0;
+            Command::VERBOSE_METRICS { enabled }
+        }
+        b'o' => {
+            let enabled = reader.read_u8().await? != 0;
+            Command::SHOW_TOMBSTONES { enabled }
+        }
+        b'a' => {
+            let enabled = reader.read_u8().await? != 0;
+            Command::BINARY_RESULTS { enabled }
         }
+        tag => return Err(CommandError::UnknownCommand(tag)),
     })
 }
+
+/// Pairs of `start..end` as "key:value " text, sorted by key, or packed with binary results on.
+/// With `tombstones`, deleted keys come along as "key:DELETED ", always as text.
+async fn write_range(
+    connection: &mut Connection,
+    db: &Database,
+    start: Bound<i32>,
+    end: Bound<i32>,
+    tombstones: bool,
+) -> Result<(), CommandError> {
+    if let Some(budget) = connection.range_budget {
+        return write_range_in_parts(connection, db, start, end, tombstones, budget).await;
+    }
+    let mut entries = db.range_stream(start, end).await;
+    while let Some((key, val)) = entries.next().await {
+        if connection.cancel_requested() {
+            connection.record_read(entries.metrics());
+            return Err(CommandError::Cancelled);
+        }
+        match (val, tombstones) {
+            (Some(val), false) => connection.write_pair(key, val).await?,
+            (val, true) => connection.write_text_pair(key, val).await?,
+            (None, false) => {}
+        }
+    }
+    connection.record_read(entries.metrics());
+    connection.finish_pairs().await?;
+    Ok(())
+}
+
+/// `write_range` resolving only as many keys as fit in `budget` bytes at a time, each part sent
+/// before the next is read. Parts are separate reads, like pages.
+async fn write_range_in_parts(
+    connection: &mut Connection,
+    db: &Database,
+    mut start: Bound<i32>,
+    end: Bound<i32>,
+    tombstones: bool,
+    budget: usize,
+) -> Result<(), CommandError> {
+    let limit = budget / RANGE_ENTRY_BYTES;
+    let mut metrics = ReadMetrics::default();
+    loop {
+        let (entries, next_key, part_metrics) = db
+            .range_page_with_tombstones(start, end, limit, || connection.cancel_requested())
+            .await;
+        metrics.blocks_read += part_metrics.blocks_read;
+        metrics.levels_touched = metrics.levels_touched.max(part_metrics.levels_touched);
+
+        for (key, val) in entries {
+            if connection.cancel_requested() {
+                connection.record_read(metrics);
+                return Err(CommandError::Cancelled);
+            }
+            match (val, tombstones) {
+                (Some(val), false) => connection.write_pair(key, val).await?,
+                (val, true) => connection.write_text_pair(key, val).await?,
+                (None, false) => {}
+            }
+        }
+        // waits on the client, so the next part isn't read before it takes this one
+        connection.flush_partial().await?;
+
+        match next_key {
+            Some(next_key) => start = Bound::Included(next_key),
+            None => break,
+        }
+    }
+    connection.record_read(metrics);
+    if connection.cancel_requested() {
+        return Err(CommandError::Cancelled);
+    }
+    Ok(())
+}
+
+/// Up to `limit` pairs like `write_range`, then "NEXT <token>" if the range goes on past them
+async fn write_page(
+    connection: &mut Connection,
+    db: &Database,
+    start: Bound<i32>,
+    end: Bound<i32>,
+    limit: u32,
+) -> Result<(), CommandError> {
+    let (pairs, next_key, metrics) = db
+        .range_page(start, end, limit as usize, || connection.cancel_requested())
+        .await;
+    connection.record_read(metrics);
+    if connection.cancel_requested() {
+        return Err(CommandError::Cancelled);
+    }
+    for (key, val) in pairs {
+        connection.write_pair(key, val).await?;
+    }
+    connection.finish_pairs().await?;
+    if let Some(next_key) = next_key {
+        let token = PageToken { next_key, end };
+        connection
+            .write_str(&format!("NEXT {}", token.to_hex()))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Where a paged range picks up: the key its next page starts at, and the range's end. Clients
+/// get it as hex text and send the bytes back with RANGE_CONT, the server keeps nothing.
+#[derive(Clone, Copy, Debug)]
+pub struct PageToken {
+    next_key: i32,
+    end: Bound<i32>,
+}
+
+impl PageToken {
+    /// Next key, end kind (1 inclusive, 2 exclusive, anything else open), end key
+    const LEN: usize = 9;
+
+    fn to_bytes(self) -> [u8; Self::LEN] {
+        let (kind, end_key) = match self.end {
+            Bound::Included(key) => (1, key),
+            Bound::Excluded(key) => (2, key),
+            Bound::Unbounded => (0, 0),
+        };
+        let mut bytes = [0; Self::LEN];
+        bytes[..4].copy_from_slice(&self.next_key.to_be_bytes());
+        bytes[4] = kind;
+        bytes[5..].copy_from_slice(&end_key.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        let next_key = i32::from_be_bytes(bytes[..4].try_into().unwrap());
+        let end_key = i32::from_be_bytes(bytes[5..].try_into().unwrap());
+        let end = match bytes[4] {
+            1 => Bound::Included(end_key),
+            2 => Bound::Excluded(end_key),
+            _ => Bound::Unbounded,
+        };
+        Self { next_key, end }
+    }
+
+    fn to_hex(self) -> String {
+        self.to_bytes().iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+/// Start bound of a range moved up to `key`, never below where it started
+fn seek(start: Bound<i32>, key: i32) -> Bound<i32> {
+    match start {
+        Bound::Included(min_key) if min_key > key => start,
+        Bound::Excluded(min_key) if min_key >= key => start,
+        _ => Bound::Included(key),
+    }
+}
+
+/// Set in the flags byte of a RANGE to have deleted keys listed
+const RANGE_TOMBSTONES: u8 = 4;
+/// Set in the flags byte of any range to leave the min key out
+const MIN_KEY_EXCLUSIVE: u8 = 8;
+/// Set in the flags byte of any range to take the max key in
+const MAX_KEY_INCLUSIVE: u8 = 16;
+
+/// Flags byte (bit 0: min key given, bit 1: max key given, bits 3 and 4: how, others up to the
+/// command) followed by min key and max key. Without bits 3 and 4, the min key is inclusive and
+/// the max key exclusive. Returns the flags along with the bounds.
+async fn read_bounds<T: AsyncBufReadExt + Unpin>(reader: &mut T) -> io::Result<(Bounds, u8)> {
+    let flags = reader.read_u8().await?;
+    let min_key = reader.read_i32().await?;
+    let max_key = reader.read_i32().await?;
+    let start = match (flags & 1 != 0, flags & MIN_KEY_EXCLUSIVE != 0) {
+        (false, _) => Bound::Unbounded,
+        (true, false) => Bound::Included(min_key),
+        (true, true) => Bound::Excluded(min_key),
+    };
+    let end = match (flags & 2 != 0, flags & MAX_KEY_INCLUSIVE != 0) {
+        (false, _) => Bound::Unbounded,
+        (true, false) => Bound::Excluded(max_key),
+        (true, true) => Bound::Included(max_key),
+    };
+    Ok(((start, end), flags))
+}
@@ -1,51 +1,323 @@
-use std::{env::args, path::PathBuf};
+use std::{env::args, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+#[cfg(feature = "fault-injection")]
+use crate::database::storage::FaultPlan;
+use crate::database::table::TableOptions;
 
 // Size of block for fence pointers
 pub const BLOCK_SIZE_BYTES: usize = 4096;
+// The first block of every table file holds the format header, data blocks start right after it
+pub const TABLE_HEADER_BYTES: usize = BLOCK_SIZE_BYTES;
+// Each block starts with how many commands it holds, as a u16. Whatever follows them is padding.
+pub const BLOCK_HEADER_BYTES: usize = 2;
 
 // 466033 (number of key-values per file) * 4(5^5) (total number of possible files) > 2^32 ==> the final level can fit all possible key-value pairs
-// Maximum number of files that can be in level one before compaction 
+// Maximum number of files that can be in level one before compaction
 pub const LEVEL1_FILE_CAPACITY: usize = 4;
-// (Maximum number of files in level n + 1) = SIZE_MULTIPLIER * (Maximum number of files in level n) 
+// (Maximum number of files in level n + 1) = SIZE_MULTIPLIER * (Maximum number of files in level n)
 pub const SIZE_MULTIPLIER: usize = 5;
 pub const NUM_LEVELS: usize = 6;
 
 pub const MAX_FILE_SIZE_BYTES: usize = 1 << 22; // 4 MB
 pub const MAX_FILE_SIZE_BLOCKS: usize = MAX_FILE_SIZE_BYTES / BLOCK_SIZE_BYTES;
 
-// Worst case (all puts, each taking 9 bytes) upper bound on number of entries in the memory level that can serialize into a single file
-pub const MEM_CAPACITY: u32 = (MAX_FILE_SIZE_BLOCKS * BLOCK_SIZE_BYTES / 9) as u32;
+// Serialized size at which the memory level gets flushed. A command never straddles blocks, so besides its header
+// up to 8 bytes of each block are padding, and one command can grow the level by 18 bytes (a put plus the range
+// delete split it causes)
+pub const MEM_CAPACITY_BYTES: usize =
+    MAX_FILE_SIZE_BLOCKS * (BLOCK_SIZE_BYTES - BLOCK_HEADER_BYTES - 8) - 18;
+// Smallest bloom filter, a table's or a whole level's
 pub const BLOOM_CAPACITY: usize = 1 << 16;
+// Bits a table's bloom filter gets per key. With its single hash function, that's a false positive rate of about
+// 1 - e^(-1/10) = 9.5% however many keys the table holds, less while a filter rounded up to a power of two has room.
+pub const BLOOM_BITS_PER_KEY: usize = 10;
+// Equal slices of the i32 keyspace the STATS key histogram counts blocks in
+pub const HISTOGRAM_BUCKETS: usize = 64;
+
+const DEFAULT_DATABASE_DIRECTORY: &str = "/Users/noahr/dev/rust/lsm-tree/database";
+
+/// What to do once the tables on disk take up more than the quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    RejectWrites,
+    EvictBottomLevel,
+}
+
+/// When responses buffered in a connection get sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    EveryCommand,
+    // only once the client has no more commands waiting, so pipelined responses go out together
+    Batched,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
 
-const DEFAULT_DATABASE_DIRECTORY: &'static str = "/Users/noahr/dev/rust/lsm-tree/database";
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(Self::EveryCommand),
+            "batched" => Ok(Self::Batched),
+            _ => Err(format!(
+                "Unknown flush policy {s}, expected command or batched"
+            )),
+        }
+    }
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = String;
 
-#[derive(Debug)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::RejectWrites),
+            "evict" => Ok(Self::EvictBottomLevel),
+            _ => Err(format!(
+                "Unknown quota policy {s}, expected reject or evict"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub data_dir: PathBuf,
     pub port: u16,
+    pub disk_quota_bytes: Option<u64>,
+    pub quota_policy: QuotaPolicy,
+    pub scan_only_levels: Vec<u32>, // levels whose tables skip bloom filters
+    pub bloom_cutoff_level: Option<u32>, // this level and the ones below it skip bloom filters
+    pub allow_reset: bool,          // lets clients wipe the database, for tests/benchmarks
+    pub idle_compaction: Option<Duration>, // compact under-full levels after this long without commands
+    pub sweep_interval: Option<Duration>,  // how often files no table owns get removed
+    pub max_mem_age: Option<Duration>, // longest the memory level goes without a flush while it holds writes
+    pub compaction_concurrency: Option<usize>, // compactions below level 1 run in the background, this many at once
+    pub small_table_threshold: Option<f32>, // adjacent tables filled below this get rewritten together
+    pub tcp_nodelay: bool,
+    pub flush_policy: FlushPolicy,
+    pub in_memory: bool,         // tables kept in RAM, nothing survives a restart
+    pub recovery_threads: usize, // tables scanned at once on startup
+    pub key_bounds: Option<(i32, i32)>, // inclusive, writes to keys outside are rejected
+    pub value_bounds: Option<(i32, i32)>, // same for put values
+    pub read_only: bool,         // serve what's on disk without ever changing it
+    pub wal: bool,               // log writes to the memory level so they survive a crash
+    pub change_buffer: usize,    // changes a subscriber can fall behind before it misses some
+    pub replica_of: Option<SocketAddr>, // primary to apply the writes of, clients can't write
+    pub key_index_every: usize,  // commands per sampled offset within a block, 0 for none
+    pub write_buffer_bytes: usize, // what flushes and merges gather before writing to a table file
+    pub range_budget: Option<usize>, // bytes a RANGE resolves and buffers at once, None for no cap
+    pub warm_levels: usize, // levels from 1 on read through once more after startup, to be cached
+    pub max_runs_per_level: Option<usize>, // flushes land in level 1 as runs of their own, merged past this many
+    #[cfg(feature = "fault-injection")]
+    pub faults: FaultPlan,
 }
 
 impl Config {
     pub fn parse_from_args() -> Self {
+        Self::parse(args())
+    }
+
+    /// Reads `--flag value` pairs, anything not starting with `--` (like the program name) is skipped
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
         let mut data_dir = DEFAULT_DATABASE_DIRECTORY.parse().unwrap();
         let mut port = 1234;
+        let mut disk_quota_bytes = None;
+        let mut quota_policy = QuotaPolicy::RejectWrites;
+        let mut scan_only_levels = vec![];
+        let mut bloom_cutoff_level = None;
+        let mut allow_reset = false;
+        let mut idle_compaction = None;
+        let mut sweep_interval = None;
+        let mut max_mem_age = None;
+        let mut compaction_concurrency = None;
+        let mut small_table_threshold = None;
+        let mut tcp_nodelay = false;
+        let mut flush_policy = FlushPolicy::EveryCommand;
+        let mut in_memory = false;
+        let mut recovery_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut key_bounds = None;
+        let mut value_bounds = None;
+        let mut read_only = false;
+        let mut wal = false;
+        let mut change_buffer = 4096;
+        let mut replica_of = None;
+        let mut key_index_every = 0;
+        let mut write_buffer_bytes = 0;
+        let mut range_budget = None;
+        let mut warm_levels = 0;
+        let mut max_runs_per_level = None;
+        #[cfg(feature = "fault-injection")]
+        let mut faults = FaultPlan::default();
 
-        let mut args = args();
+        let mut args = args.into_iter();
 
         while let Some(arg) = args.next() {
-            if arg.starts_with("--") {
-                match &arg[2..] {
+            if let Some(flag) = arg.strip_prefix("--") {
+                match flag {
                     "data-dir" => {
                         data_dir = args.next().map(|d| d.parse().unwrap()).unwrap();
                     }
                     "port" => {
                         port = args.next().map(|d| d.parse().unwrap()).unwrap();
                     }
+                    "disk-quota" => {
+                        disk_quota_bytes = args.next().map(|d| d.parse().unwrap());
+                    }
+                    "quota-policy" => {
+                        quota_policy = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    "scan-only-levels" => {
+                        scan_only_levels = args
+                            .next()
+                            .unwrap()
+                            .split(',')
+                            .map(|l| l.parse().unwrap())
+                            .collect();
+                    }
+                    "bloom-cutoff-level" => {
+                        bloom_cutoff_level = args.next().map(|d| d.parse().unwrap());
+                    }
+                    "allow-reset" => {
+                        allow_reset = true;
+                    }
+                    "idle-compaction-ms" => {
+                        idle_compaction = args
+                            .next()
+                            .map(|d| Duration::from_millis(d.parse().unwrap()));
+                    }
+                    "sweep-interval-ms" => {
+                        sweep_interval = args
+                            .next()
+                            .map(|d| Duration::from_millis(d.parse().unwrap()));
+                    }
+                    "max-mem-age-ms" => {
+                        max_mem_age = args
+                            .next()
+                            .map(|d| Duration::from_millis(d.parse().unwrap()));
+                    }
+                    "compaction-concurrency" => {
+                        compaction_concurrency = args.next().map(|d| d.parse().unwrap());
+                    }
+                    "small-table-threshold" => {
+                        small_table_threshold = args.next().map(|d| d.parse().unwrap());
+                    }
+                    "tcp-nodelay" => {
+                        tcp_nodelay = true;
+                    }
+                    "flush-policy" => {
+                        flush_policy = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    "in-memory" => {
+                        in_memory = true;
+                    }
+                    "recovery-threads" => {
+                        recovery_threads = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    "key-bounds" => {
+                        key_bounds = args.next().map(|d| parse_bounds(&d));
+                    }
+                    "value-bounds" => {
+                        value_bounds = args.next().map(|d| parse_bounds(&d));
+                    }
+                    "read-only" => {
+                        read_only = true;
+                    }
+                    "wal" => {
+                        wal = true;
+                    }
+                    "change-buffer" => {
+                        change_buffer = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    "replica-of" => {
+                        replica_of = args.next().map(|d| d.parse().unwrap());
+                    }
+                    "key-index-every" => {
+                        key_index_every = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    "write-buffer-bytes" => {
+                        write_buffer_bytes = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    "range-budget-bytes" => {
+                        range_budget = args.next().map(|d| d.parse().unwrap());
+                    }
+                    "warm-levels" => {
+                        warm_levels = args.next().map(|d| d.parse().unwrap()).unwrap();
+                    }
+                    "max-runs-per-level" => {
+                        max_runs_per_level = args.next().map(|d| d.parse().unwrap());
+                    }
+                    #[cfg(feature = "fault-injection")]
+                    "fail-at-op" => {
+                        faults.fail_at_op = args.next().map(|d| d.parse().unwrap());
+                    }
+                    #[cfg(feature = "fault-injection")]
+                    "crash-at-op" => {
+                        faults.crash_at_op = args.next().map(|d| d.parse().unwrap());
+                    }
                     _ => unimplemented!(),
                 }
             }
         }
 
-        Config { data_dir, port }
+        Config {
+            data_dir,
+            port,
+            disk_quota_bytes,
+            quota_policy,
+            scan_only_levels,
+            bloom_cutoff_level,
+            allow_reset,
+            idle_compaction,
+            sweep_interval,
+            max_mem_age,
+            compaction_concurrency,
+            small_table_threshold,
+            tcp_nodelay,
+            flush_policy,
+            in_memory,
+            recovery_threads,
+            key_bounds,
+            value_bounds,
+            read_only,
+            wal,
+            change_buffer,
+            replica_of,
+            key_index_every,
+            write_buffer_bytes,
+            range_budget,
+            warm_levels,
+            max_runs_per_level,
+            #[cfg(feature = "fault-injection")]
+            faults,
+        }
     }
+
+    /// Whether a write of `val` (None for writes without a value) to `key` is within the bounds
+    pub fn accepts(&self, key: i32, val: Option<i32>) -> bool {
+        let within = |bounds: Option<(i32, i32)>, x: i32| {
+            bounds.is_none_or(|(min, max)| (min..=max).contains(&x))
+        };
+        within(self.key_bounds, key) && val.is_none_or(|val| within(self.value_bounds, val))
+    }
+
+    /// Scan-only levels and levels at or past the cutoff don't build bloom filters, gets there read
+    /// the block the fence pointers point to instead
+    pub fn level_has_bloom(&self, level: u32) -> bool {
+        !self.scan_only_levels.contains(&level)
+            && self.bloom_cutoff_level.is_none_or(|cutoff| level < cutoff)
+    }
+
+    pub fn table_options(&self, level: u32) -> TableOptions {
+        TableOptions {
+            bloom: self.level_has_bloom(level),
+            key_index_every: self.key_index_every,
+            write_buffer_bytes: self.write_buffer_bytes,
+        }
+    }
+}
+
+/// "min,max", both inclusive
+fn parse_bounds(s: &str) -> (i32, i32) {
+    let (min, max) = s.split_once(',').expect("Bounds should look like min,max");
+    (min.parse().unwrap(), max.parse().unwrap())
 }
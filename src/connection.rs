@@ -1,39 +1,76 @@
-use std::{
-    io::{Cursor, Write},
-    net::SocketAddr,
-    sync::Arc,
-    time::Instant,
-};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use futures::FutureExt;
 
 use tokio::{
-    io::{self, AsyncWriteExt, BufReader, BufWriter},
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
+    sync::broadcast::error::RecvError,
 };
 use tokio_util::sync::CancellationToken;
 
-use crate::{client_stats::ClientStats, command::read_command, database::Database};
+use crate::{
+    client_stats::ClientStats,
+    command::{
+        read_command, Bounds, CommandError, BINARY_PAIRS, BINARY_VALUE, CANCEL, ERROR_FRAME,
+        EVENT_COMPACTION_FINISHED, EVENT_COMPACTION_STARTED, EVENT_FRAME, HANDSHAKE, METRICS_FRAME,
+        PROTOCOL_VERSION, SUPPORTED_FEATURES,
+    },
+    config::{Config, FlushPolicy},
+    database::{Database, ReadMetrics},
+};
 
 pub struct Connection {
     pub reader: BufReader<OwnedReadHalf>,
     pub writer: BufWriter<OwnedWriteHalf>,
-    addr: SocketAddr,
     cancel_token: CancellationToken,
+    flush_policy: FlushPolicy,
+    pub range_budget: Option<usize>, // bytes of a RANGE resolved and sent at a time
+    compactions_seen: (u64, u64),    // started and finished as of the last response
+    pub verbose_metrics: bool,       // send what each read cost along with its response
+    pub show_tombstones: bool,       // GET answers DELETED for a deleted key instead of nothing
+    pub binary_results: bool,        // GET values and range pairs sent as binary frames
+    pub features: u32,               // agreed on in the handshake, every one without
+    pub version: u16,                // agreed on in the handshake, 0 without
+    pub last_range: Option<(Bounds, bool)>, // bounds and tombstones flag of the last RANGE, for RANGE_SEEK
+    packed_pairs: Vec<u8>, // with binary results, range pairs not sent in a frame yet
+    read_metrics: Option<ReadMetrics>, // of the command being answered, if it was a read
+    cancelled: bool,       // the client gave up on the command being answered
     pub stats: ClientStats,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, addr: SocketAddr, cancel_token: CancellationToken) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        addr: SocketAddr,
+        cancel_token: CancellationToken,
+        config: &Config,
+    ) -> Self {
+        if let Err(err) = stream.set_nodelay(config.tcp_nodelay) {
+            eprintln!("Could not set TCP_NODELAY for {addr:?}: {err}");
+        }
         let (read, write) = stream.into_split();
         let buf_read = BufReader::new(read);
         let buf_write = BufWriter::new(write);
         Self {
             reader: buf_read,
             writer: buf_write,
-            addr,
             cancel_token,
+            flush_policy: config.flush_policy,
+            range_budget: config.range_budget,
+            compactions_seen: (0, 0),
+            verbose_metrics: false,
+            show_tombstones: false,
+            binary_results: false,
+            features: SUPPORTED_FEATURES,
+            version: 0,
+            last_range: None,
+            packed_pairs: Vec::with_capacity(PAIRS_PER_FRAME * 8),
+            read_metrics: None,
+            cancelled: false,
             stats: ClientStats::new(addr),
         }
     }
@@ -42,25 +79,47 @@ impl Connection {
         // repeatedly reads incoming commands from client
         // execute them
         // then writes back the response to client
+        // compactions from before the client connected aren't news to it
+        self.compactions_seen = db.compaction_counts();
+        let cancel_token = self.cancel_token.clone();
+        tokio::select! {
+            negotiated = self.negotiate() => negotiated?,
+            _ = cancel_token.cancelled() => return Ok(()),
+        }
         loop {
             tokio::select! {
-                read_res = read_command(&mut self.reader) => {
-                    let command = if let Ok(command) = read_res {
-                        command
-                    } else {
-                        break Ok(());
+                read_res = read_command(&mut self.reader, self.version) => {
+                    let command = match read_res {
+                        Ok(command) => command,
+                        Err(err @ CommandError::UnknownCommand(_)) => {
+                            // its arguments can't be told apart from the next command, so
+                            // nothing after the tag can be read. The client hears why before
+                            // the connection closes, rather than having pipelined commands
+                            // skipped.
+                            self.write_error(&err).await?;
+                            self.end_response(&db).await?;
+                            break Ok(());
+                        }
+                        Err(_) => break Ok(()),
                     };
 
-                    self.stats.begin(db.size_bytes().await);
+                    db.record_activity();
+                    // the size takes every level's read lock, which a merge can hold up
+                    if !self.stats.started() {
+                        self.stats.begin(db.size_bytes().await);
+                    }
 
-                    // println!("Received command {:?} from {:?}, executing...", command, addr);
                     let start = Instant::now();
-                    command.execute(self, &db).await?;
+                    match command.execute(self, &db).await {
+                        Ok(()) => {}
+                        Err(CommandError::Io(err)) if is_transport_error(&err) => {
+                            break Err(err);
+                        }
+                        Err(err) => self.write_error(&err).await?,
+                    }
                     self.stats.record_latency(start.elapsed().as_nanos() as u64);
 
-                    // delimiter of 0 so the client knows when the response finishes
-                    self.writer.write_u8(0x00).await.unwrap();
-                    self.writer.flush().await.unwrap();
+                    self.end_response(&db).await?;
                 }
                 _ = self.cancel_token.cancelled() => {
                     break Ok(());
@@ -69,16 +128,1430 @@ impl Connection {
         }
     }
 
+    /// Answers the handshake if the client opened with one, settling on the features both sides
+    /// support. Anything else is left for the command loop.
+    async fn negotiate(&mut self) -> io::Result<()> {
+        if self.reader.fill_buf().await?.first() != Some(&HANDSHAKE) {
+            return Ok(());
+        }
+        self.reader.consume(1);
+        self.version = self.reader.read_u16().await?.min(PROTOCOL_VERSION);
+        self.features = self.reader.read_u32().await? & SUPPORTED_FEATURES;
+
+        self.write_str(&format!("{} {}", self.version, self.features))
+            .await?;
+        self.writer.write_u8(0x00).await?;
+        self.writer.flush().await
+    }
+
     pub async fn write_int(&mut self, val: i32) -> io::Result<()> {
-        let mut buf = [0u8; 12];
-        let mut buf = Cursor::new(&mut buf[..]);
-        write!(&mut buf, "{}", val)?;
+        let mut buf = itoa::Buffer::new();
+        self.writer.write_all(buf.format(val).as_bytes()).await
+    }
+
+    /// A GET's value, as decimal text or a binary frame
+    pub async fn write_value(&mut self, val: i32) -> io::Result<()> {
+        if !self.binary_results {
+            return self.write_int(val).await;
+        }
+        self.writer.write_u8(BINARY_VALUE).await?;
+        self.writer.write_i32(val).await
+    }
+
+    /// A pair of a range, as "key:value " text or packed into the next binary frame. Pairs
+    /// packed need `finish_pairs` before any text goes after them.
+    pub async fn write_pair(&mut self, key: i32, val: i32) -> io::Result<()> {
+        if !self.binary_results {
+            return self.write_text_pair(key, Some(val)).await;
+        }
+        self.packed_pairs.extend_from_slice(&key.to_be_bytes());
+        self.packed_pairs.extend_from_slice(&val.to_be_bytes());
+        if self.packed_pairs.len() == PAIRS_PER_FRAME * 8 {
+            self.finish_pairs().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the pairs packed so far in a binary frame
+    pub async fn finish_pairs(&mut self) -> io::Result<()> {
+        if self.packed_pairs.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_u8(BINARY_PAIRS).await?;
+        self.writer
+            .write_u32((self.packed_pairs.len() / 8) as u32)
+            .await?;
+        self.writer.write_all(&self.packed_pairs).await?;
+        self.packed_pairs.clear();
+        Ok(())
+    }
+
+    /// "key:value " (or "key:DELETED " for None) with a single write, whatever the encoding
+    pub async fn write_text_pair(&mut self, key: i32, val: Option<i32>) -> io::Result<()> {
+        let mut pair = [0u8; PAIR_BYTES];
+        let len = encode_pair(&mut pair, key, val);
+        self.writer.write_all(&pair[..len]).await
+    }
 
-        let pos = buf.position() as usize;
-        self.writer.write_all(&buf.get_ref()[..pos]).await
+    pub fn record_read(&mut self, metrics: ReadMetrics) {
+        self.stats.record_read(metrics);
+        self.read_metrics = Some(metrics);
+    }
+
+    /// Whether the client wants the command being answered stopped, because it sent `CANCEL` or
+    /// the connection broke. Only looks at what already arrived, never waits on the socket, and
+    /// keeps saying yes until the response ends.
+    pub fn cancel_requested(&mut self) -> bool {
+        if self.cancelled {
+            return true;
+        }
+        self.cancelled = match self.reader.fill_buf().now_or_never() {
+            Some(Ok(buf)) if buf.first() == Some(&CANCEL) => {
+                self.reader.consume(1);
+                true
+            }
+            Some(Err(_)) => true,
+            // nothing new, another command waiting, or the client done sending
+            _ => false,
+        };
+        self.cancelled
     }
 
     pub async fn write_str(&mut self, str: &str) -> io::Result<()> {
         self.writer.write_all(str.as_bytes()).await
     }
+
+    /// Turns the connection into a feed of every write from sequence number `from` on (0 for from
+    /// here on): an "OK <epoch>" frame once subscribed, then a frame per change, or "GAP <n>" for
+    /// `n` changes that were no longer kept or that it fell too far behind to get. Lasts until the
+    /// client disconnects or the server shuts down, anything the client sends meanwhile is dropped.
+    pub async fn forward_changes(&mut self, db: &Database, from: u64) -> io::Result<()> {
+        let (kept, missed, mut changes) = db.subscribe(from);
+        self.write_str(&format!("OK {}", db.change_epoch())).await?;
+        self.writer.write_u8(0x00).await?;
+        if missed > 0 {
+            self.write_str(&format!("GAP {missed}")).await?;
+            self.writer.write_u8(0x00).await?;
+        }
+        for change in kept {
+            self.write_str(&change.to_string()).await?;
+            self.writer.write_u8(0x00).await?;
+        }
+
+        loop {
+            if self.cancel_token.is_cancelled() {
+                return Ok(());
+            }
+            let received = match changes.recv().now_or_never() {
+                Some(received) => received,
+                None => {
+                    // caught up, send what's buffered before waiting
+                    self.writer.flush().await?;
+                    tokio::select! {
+                        received = changes.recv() => received,
+                        read = self.reader.fill_buf().map(|read| read.map(|buf| buf.len())) => {
+                            match read {
+                                Ok(0) | Err(_) => return Ok(()),
+                                Ok(len) => self.reader.consume(len),
+                            }
+                            continue;
+                        }
+                        _ = self.cancel_token.cancelled() => return Ok(()),
+                    }
+                }
+            };
+            match received {
+                Ok(change) => self.write_str(&change.to_string()).await?,
+                Err(RecvError::Lagged(missed)) => self.write_str(&format!("GAP {missed}")).await?,
+                Err(RecvError::Closed) => return Ok(()),
+            }
+            self.writer.write_u8(0x00).await?;
+        }
+    }
+
+    /// Sends what the response has so far, the rest of it following once it's ready
+    pub async fn flush_partial(&mut self) -> io::Result<()> {
+        self.finish_pairs().await?;
+        self.writer.flush().await
+    }
+
+    /// Delimiter of 0 so the client knows when the response finishes
+    async fn end_response(&mut self, db: &Database) -> io::Result<()> {
+        self.finish_pairs().await?;
+        if let Some(metrics) = self.read_metrics.take() {
+            if self.verbose_metrics {
+                self.writer.write_u8(METRICS_FRAME).await?;
+                self.write_str(&metrics.blocks_read.to_string()).await?;
+            }
+        }
+        self.write_events(db).await?;
+        self.writer.write_u8(0x00).await?;
+        self.cancelled = false;
+        let batching =
+            self.flush_policy == FlushPolicy::Batched && !self.reader.buffer().is_empty();
+        if !batching {
+            self.writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Compactions since the last response, whichever connection set them off
+    async fn write_events(&mut self, db: &Database) -> io::Result<()> {
+        let (started, finished) = db.compaction_counts();
+        let (seen_started, seen_finished) = self.compactions_seen;
+        self.compactions_seen = (started, finished);
+
+        if started > seen_started {
+            self.writer
+                .write_all(&[EVENT_FRAME, EVENT_COMPACTION_STARTED])
+                .await?;
+        }
+        if finished > seen_finished {
+            self.writer
+                .write_all(&[EVENT_FRAME, EVENT_COMPACTION_FINISHED])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Anything the command wrote before failing is left in front, clients read from the marker on
+    async fn write_error(&mut self, err: &CommandError) -> io::Result<()> {
+        self.finish_pairs().await?;
+        self.writer.write_u8(ERROR_FRAME).await?;
+        self.writer.write_u8(err.code()).await?;
+        self.write_str(&err.to_string()).await
+    }
+}
+
+// Packed pairs per binary frame, 4 KiB of them
+const PAIRS_PER_FRAME: usize = 512;
+
+// "-2147483648:-2147483648 ", the longest a pair gets
+const PAIR_BYTES: usize = 24;
+
+/// Same bytes as writing the key, ':', the value or DELETED, and ' ' one after the other
+fn encode_pair(buf: &mut [u8; PAIR_BYTES], key: i32, val: Option<i32>) -> usize {
+    let mut ints = itoa::Buffer::new();
+    let mut len = 0;
+    let mut push = |bytes: &[u8]| {
+        buf[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+    push(ints.format(key).as_bytes());
+    push(b":");
+    match val {
+        Some(val) => push(ints.format(val).as_bytes()),
+        None => push(b"DELETED"),
+    }
+    push(b" ");
+    len
+}
+
+/// The connection itself is gone, so there's no one left to send an error frame to
+fn is_transport_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        fs,
+        io::{Cursor, Write},
+        net::SocketAddr,
+        path::Path,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use super::{encode_pair, Connection, EVENT_FRAME, METRICS_FRAME, PAIR_BYTES};
+    use crate::{
+        command::{
+            CommandError, BINARY_PAIRS, BINARY_VALUE, CANCEL, ERROR_FRAME, FEATURE_TOMBSTONES,
+            HANDSHAKE, PROTOCOL_VERSION, SUPPORTED_FEATURES,
+        },
+        config::{Config, NUM_LEVELS},
+        database::{
+            compaction::build_tables,
+            storage::{FsStorage, Storage},
+            table::{block, tests::table_of, TableOptions},
+            tests::{fill_level, lock_level, scratch_directory},
+            Database,
+        },
+    };
+
+    /// A client talking to its own connection of `db`
+    pub(crate) struct Client {
+        pub stream: BufReader<TcpStream>,
+    }
+
+    impl Client {
+        pub(crate) async fn connect(db: &Arc<Database>, config: &Config) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr: SocketAddr = listener.local_addr().unwrap();
+            let (db, config) = (db.clone(), config.clone());
+            tokio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let mut connection =
+                    Connection::new(stream, addr, CancellationToken::new(), &config);
+                let _ = connection.handle(db).await;
+            });
+            let stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            Self { stream }
+        }
+
+        pub(crate) async fn send(&mut self, bytes: &[u8]) {
+            self.stream.get_mut().write_all(bytes).await.unwrap();
+        }
+
+        /// Everything up to the 0 ending the next response but compaction events, the rest of it
+        /// has to be text
+        pub(crate) async fn response(&mut self) -> String {
+            let mut response = vec![];
+            loop {
+                match self.stream.read_u8().await.unwrap() {
+                    0 => return String::from_utf8(response).unwrap(),
+                    EVENT_FRAME => {
+                        self.stream.read_u8().await.unwrap();
+                    }
+                    // the code after it could pass for a frame marker
+                    ERROR_FRAME => {
+                        response.push(ERROR_FRAME);
+                        response.push(self.stream.read_u8().await.unwrap());
+                    }
+                    byte => response.push(byte),
+                }
+            }
+        }
+
+        pub(crate) async fn request(&mut self, bytes: &[u8]) -> String {
+            self.send(bytes).await;
+            self.response().await
+        }
+
+        /// Agrees on this server's version and every feature, for ranges with a flags byte
+        pub(crate) async fn handshake(&mut self) {
+            let handshake = [
+                &[HANDSHAKE][..],
+                &PROTOCOL_VERSION.to_be_bytes(),
+                &SUPPORTED_FEATURES.to_be_bytes(),
+            ]
+            .concat();
+            let agreed = format!("{PROTOCOL_VERSION} {SUPPORTED_FEATURES}");
+            assert_eq!(self.request(&handshake).await, agreed);
+        }
+    }
+
+    /// A GET of `key` as it goes over the wire
+    pub(crate) fn get(key: i32) -> Vec<u8> {
+        [&[b'g'][..], &key.to_be_bytes()].concat()
+    }
+
+    pub(crate) fn put(key: i32, val: i32) -> Vec<u8> {
+        [&[b'p'][..], &key.to_be_bytes(), &val.to_be_bytes()].concat()
+    }
+
+    pub(crate) fn in_memory_config(args: &[&str]) -> Config {
+        let args = ["--in-memory"]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string());
+        Config::parse(args)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn load_reads_big_endian_pairs() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        let mut client = Client::connect(&db, &config).await;
+
+        // a LOAD file as `generator` writes it, little-endian whatever the host
+        let pairs = [
+            (1, 2),
+            (-1, 256),
+            (0x01020304, -0x01020304),
+            (i32::MIN, i32::MAX),
+        ];
+        let file: Vec<u8> = pairs
+            .iter()
+            .flat_map(|&(key, val): &(i32, i32)| [key.to_le_bytes(), val.to_le_bytes()])
+            .flatten()
+            .collect();
+        // which the client turns around before sending
+        let mut load = vec![b'l'];
+        load.extend_from_slice(&(file.len() as u64 / 8).to_be_bytes());
+        for int in file.chunks(4) {
+            let int = i32::from_le_bytes(int.try_into().unwrap());
+            load.extend_from_slice(&int.to_be_bytes());
+        }
+        assert_eq!(client.request(&load).await, "OK");
+
+        for (key, val) in pairs {
+            assert_eq!(client.request(&get(key)).await, val.to_string());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn gets_go_through_while_a_load_waits_on_its_file() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        db.insert(-1, 1).await;
+        let mut loader = Client::connect(&db, &config).await;
+        let mut reader = Client::connect(&db, &config).await;
+
+        let pairs: i32 = 100_000;
+        let mut load = vec![b'l'];
+        load.extend_from_slice(&(pairs as u64).to_be_bytes());
+        for key in 0..pairs {
+            load.extend_from_slice(&key.to_be_bytes());
+            load.extend_from_slice(&(-key).to_be_bytes());
+        }
+        // the first part of the file, the rest still on its way
+        let (sent, rest) = load.split_at(9 + 8 * 10_000);
+        loader.send(sent).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        for key in [-1, 5, 8_000] {
+            let start = Instant::now();
+            let expected = if key == -1 {
+                "1".into()
+            } else {
+                (-key).to_string()
+            };
+            let request = get(key);
+            let answer = tokio::time::timeout(Duration::from_secs(1), reader.request(&request));
+            let answer = answer.await;
+            assert_eq!(answer.unwrap(), expected);
+            assert!(start.elapsed() < Duration::from_millis(100), "{key}");
+        }
+        // not sent yet
+        assert_eq!(reader.request(&get(50_000)).await, "");
+
+        loader.send(rest).await;
+        assert_eq!(loader.response().await, "OK");
+        assert_eq!(
+            reader.request(&get(pairs - 1)).await,
+            (1 - pairs).to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_commands_answer_error_frames_and_keep_the_connection() {
+        let config = in_memory_config(&["--disk-quota", "1"]);
+        let mut db = Database::new(config.clone());
+        fill_level(&mut db, 0, |storage, dir| {
+            vec![table_of(storage, dir, 0..10)]
+        });
+        let db = Arc::new(db);
+        let mut client = Client::connect(&db, &config).await;
+
+        let frame = |err: CommandError| {
+            let mut frame = String::from_utf8(vec![ERROR_FRAME, err.code()]).unwrap();
+            frame.push_str(&err.to_string());
+            frame
+        };
+        assert_eq!(
+            client.request(&put(1, 1)).await,
+            frame(CommandError::QuotaExceeded)
+        );
+        assert_eq!(
+            client.request(b"c").await,
+            frame(CommandError::ResetDisabled)
+        );
+        assert_eq!(
+            client.request(b"k\x09").await,
+            frame(CommandError::NoSuchLevel)
+        );
+        assert_eq!(client.request(&get(5)).await, "5");
+
+        // more pairs than there are bytes to count, skipped up to the end of the stream
+        let load = [&[b'l'][..], &u64::MAX.to_be_bytes()].concat();
+        client.send(&load).await;
+        client.stream.get_mut().shutdown().await.unwrap();
+        assert_eq!(client.response().await, frame(CommandError::QuotaExceeded));
+
+        let io = CommandError::from(std::io::Error::other("disk gone"));
+        assert_eq!((io.code(), io.to_string()), (4, "disk gone".to_string()));
+    }
+
+    #[tokio::test]
+    async fn writes_outside_the_bounds_are_rejected() {
+        let config = in_memory_config(&["--key-bounds", "-100,100", "--value-bounds", "0,1000"]);
+        let db = Arc::new(Database::new(config.clone()));
+        let mut client = Client::connect(&db, &config).await;
+        let code = CommandError::OutOfBounds.code();
+        let rejected = |response: String| response.as_bytes()[..2] == [ERROR_FRAME, code];
+
+        assert_eq!(client.request(&put(-100, 0)).await, "OK");
+        assert_eq!(client.request(&put(100, 1000)).await, "OK");
+        assert!(rejected(client.request(&put(101, 5)).await));
+        assert!(rejected(client.request(&put(i32::MIN, 5)).await));
+        assert!(rejected(client.request(&put(5, -1)).await));
+        assert!(rejected(client.request(&put(5, 1001)).await));
+        assert_eq!(client.request(&get(-100)).await, "0");
+        assert_eq!(client.request(&get(101)).await, "");
+        assert_eq!(client.request(&get(5)).await, "");
+
+        // a LOAD keeps the pairs within them
+        let mut load = vec![b'l'];
+        load.extend_from_slice(&3u64.to_be_bytes());
+        for (key, val) in [(1, 10), (200, 20), (3, 30)] {
+            load.extend_from_slice(&i32::to_be_bytes(key));
+            load.extend_from_slice(&i32::to_be_bytes(val));
+        }
+        // with a code of its own, the other pairs were stored
+        let response = client.request(&load).await;
+        let partial = CommandError::PairsOutOfBounds(1).code();
+        assert_eq!(response.as_bytes()[..2], [ERROR_FRAME, partial]);
+        assert_ne!(partial, code);
+        assert!(response.ends_with("skipped 1 pairs outside the configured bounds"));
+        assert_eq!(client.request(&get(1)).await, "10");
+        assert_eq!(client.request(&get(3)).await, "30");
+        assert_eq!(client.request(&get(200)).await, "");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn gets_tell_deleted_keys_from_absent_ones_if_asked() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        for key in 0..10 {
+            db.insert(key, key).await;
+        }
+        db.delete(1).await;
+        db.flush_if_stale(Duration::ZERO).await;
+        db.delete(2).await;
+        db.delete_range(5, 6).await;
+        let mut client = Client::connect(&db, &config).await;
+
+        assert_eq!(client.request(&get(1)).await, "");
+        assert_eq!(client.request(b"o\x01").await, "OK");
+        // on disk, in memory, and range deleted
+        for key in [1, 2, 5, 6] {
+            assert_eq!(client.request(&get(key)).await, "DELETED", "{key}");
+        }
+        assert_eq!(client.request(&get(20)).await, "");
+        assert_eq!(client.request(&get(3)).await, "3");
+        // written again after its delete
+        assert_eq!(client.request(&put(2, 22)).await, "OK");
+        assert_eq!(client.request(&get(2)).await, "22");
+
+        assert_eq!(client.request(b"o\x00").await, "OK");
+        assert_eq!(client.request(&get(1)).await, "");
+    }
+
+    #[tokio::test]
+    async fn failed_commands_leave_the_connection_open() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        let mut client = Client::connect(&db, &config).await;
+
+        let response = client.request(b"c").await;
+        let err = CommandError::ResetDisabled;
+        assert_eq!(response.as_bytes()[..2], [ERROR_FRAME, err.code()]);
+        assert_eq!(response[2..], err.to_string());
+
+        assert_eq!(client.request(&put(7, 70)).await, "OK");
+        assert_eq!(client.request(&get(7)).await, "70");
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_close_the_connection_after_an_error() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        let mut client = Client::connect(&db, &config).await;
+
+        // pipelined behind it, a put that can't be told apart from the unknown command's arguments
+        let response = client.request(&[&b"#"[..], &put(7, 70)].concat()).await;
+        let err = CommandError::UnknownCommand(b'#');
+        assert_eq!(response.as_bytes()[..2], [ERROR_FRAME, err.code()]);
+        assert_eq!(response[2..], err.to_string());
+
+        let mut rest = vec![];
+        client.stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(db.get(7).await.0, None);
+    }
+
+    #[tokio::test]
+    async fn count_and_exists_skip_deleted_keys() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        fill_level(&mut db, 0, |storage, dir| {
+            vec![table_of(storage, dir, 0..100)]
+        });
+        let db = Arc::new(db);
+        db.delete(5).await;
+        db.delete_range(10, 19).await;
+        let mut client = Client::connect(&db, &config).await;
+
+        // both ends given, max exclusive
+        let count =
+            |min: i32, max: i32| [&[b'n', 3][..], &min.to_be_bytes(), &max.to_be_bytes()].concat();
+        assert_eq!(client.request(&count(0, 100)).await, "89");
+        assert_eq!(client.request(&count(5, 20)).await, "4");
+        assert_eq!(client.request(&count(200, 300)).await, "0");
+
+        let exists = |key: i32| [&[b'e'][..], &key.to_be_bytes()].concat();
+        assert_eq!(client.request(&exists(4)).await, "true");
+        assert_eq!(client.request(&exists(5)).await, "false");
+        assert_eq!(client.request(&exists(15)).await, "false");
+        assert_eq!(client.request(&exists(100)).await, "false");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancelling_a_large_range_frees_its_locks() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        fill_level(&mut db, 0, |storage, dir| {
+            (0..4)
+                .map(|t| table_of(storage, dir, t * 100_000..(t + 1) * 100_000))
+                .collect()
+        });
+        let db = Arc::new(db);
+        let mut client = Client::connect(&db, &config).await;
+        client.handshake().await;
+        let range_all = [b'r', 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let start = Instant::now();
+        let pairs = client.request(&range_all).await;
+        let uncancelled = start.elapsed();
+        assert_eq!(pairs.matches(':').count(), 400_000);
+
+        client.send(&range_all).await;
+        tokio::time::sleep(uncancelled / 10).await;
+        let start = Instant::now();
+        client.send(&[CANCEL]).await;
+        lock_level(&db, 0).await;
+        let freed = start.elapsed();
+        assert!(
+            freed * 4 < uncancelled,
+            "freed in {freed:?} of the range's {uncancelled:?}"
+        );
+
+        let response = client.response().await;
+        let err = CommandError::Cancelled;
+        let frame = format!("{}{}", ERROR_FRAME as char, err.code() as char);
+        assert!(
+            response.ends_with(&(frame + &err.to_string())),
+            "{response}"
+        );
+        // the connection goes on with the next command
+        assert_eq!(client.request(&get(5)).await, "5");
+    }
+
+    #[tokio::test]
+    async fn stats_of_an_empty_database_are_zeros() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        let mut client = Client::connect(&db, &config).await;
+
+        for histogram in [b"j\0", b"j\x01"] {
+            let stats: Value = serde_json::from_str(&client.request(histogram).await).unwrap();
+            assert_eq!(stats["logical_pairs"], 0);
+            assert_eq!(stats["populated_levels"], 0);
+            assert_eq!(stats["total_size_bytes"], 0);
+            for level in stats["levels"].as_array().unwrap() {
+                assert_eq!(
+                    (&level["tables"], &level["entries"]),
+                    (&0.into(), &0.into())
+                );
+                assert_eq!(level["utilization"].as_f64(), Some(0.0), "{level}");
+            }
+        }
+        let stats = client.request(b"s").await;
+        assert!(!stats.contains("NaN") && !stats.contains("inf"), "{stats}");
+        assert_eq!(client.request(&get(1)).await, "");
+    }
+
+    #[tokio::test]
+    async fn stats_json_holds_every_levels_metrics() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        fill_level(&mut db, 0, |storage, dir| {
+            vec![table_of(storage, dir, 0..100)]
+        });
+        fill_level(&mut db, 1, |storage, dir| {
+            vec![table_of(storage, dir, 0..1000)]
+        });
+        let db = Arc::new(db);
+        for key in 2000..2005 {
+            db.insert(key, key).await;
+        }
+        let mut client = Client::connect(&db, &config).await;
+
+        let stats: Value = serde_json::from_str(&client.request(b"j\0").await).unwrap();
+        assert_eq!(stats["logical_pairs"], 1005);
+        assert_eq!(stats["populated_levels"], 2);
+        let levels = stats["levels"].as_array().unwrap();
+        assert_eq!(levels.len(), NUM_LEVELS + 1);
+        for (i, level) in levels.iter().enumerate() {
+            assert_eq!(level["level"], i);
+            let (tables, entries) = match i {
+                0 => (0, 5),
+                1 => (1, 100),
+                2 => (1, 1000),
+                _ => (0, 0),
+            };
+            assert_eq!(
+                (&level["tables"], &level["entries"]),
+                (&tables.into(), &entries.into())
+            );
+            assert!(level["size_bytes"].is_u64());
+            let utilization = level["utilization"].as_f64().unwrap();
+            assert_eq!(utilization > 0.0, i <= 2, "level{i}");
+        }
+        let on_disk: u64 = levels[1..]
+            .iter()
+            .map(|l| l["size_bytes"].as_u64().unwrap())
+            .sum();
+        assert_eq!(stats["total_size_bytes"], on_disk);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compactions_show_up_as_events_on_the_next_response() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        db.insert(1, 1).await;
+        db.flush_if_stale(Duration::ZERO).await;
+        let mut client = Client::connect(&db, &config).await;
+        let raw_response = async |client: &mut Client| {
+            client.send(&get(1)).await;
+            let mut response = vec![];
+            client.stream.read_until(0, &mut response).await.unwrap();
+            response
+        };
+
+        // the flush before it connected isn't news to it
+        assert_eq!(raw_response(&mut client).await, b"1\0");
+        db.insert(2, 2).await;
+        db.flush_if_stale(Duration::ZERO).await;
+        assert_eq!(
+            raw_response(&mut client).await,
+            [b'1', EVENT_FRAME, 1, EVENT_FRAME, 2, 0]
+        );
+        assert_eq!(raw_response(&mut client).await, b"1\0");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verbose_reads_send_the_blocks_they_read() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        db.insert(1, 1).await;
+        db.flush_if_stale(Duration::ZERO).await;
+        let mut client = Client::connect(&db, &config).await;
+        assert_eq!(client.request(&get(1)).await, "1");
+
+        assert_eq!(client.request(b"v\x01").await, "OK");
+        let metrics = |blocks: u64| format!("{}{blocks}", METRICS_FRAME as char);
+        assert_eq!(client.request(&get(1)).await, format!("1{}", metrics(1)));
+        // a miss still costs what it looked through, and writes carry nothing
+        assert_eq!(client.request(&get(2)).await, metrics(0));
+        assert_eq!(client.request(&put(2, 2)).await, "OK");
+        // nor do hits in memory
+        assert_eq!(client.request(&get(2)).await, format!("2{}", metrics(0)));
+
+        assert_eq!(client.request(b"v\x00").await, "OK");
+        assert_eq!(client.request(&get(1)).await, "1");
+    }
+
+    /// A RANGE of `min..max` as it goes over the wire
+    fn range(min: i32, max: i32) -> Vec<u8> {
+        [&[b'r', 3][..], &min.to_be_bytes(), &max.to_be_bytes()].concat()
+    }
+
+    /// Keys of a verbose range response, and the blocks it read
+    fn keys_and_blocks(response: &str) -> (Vec<i32>, u64) {
+        let (pairs, blocks) = response.split_once(METRICS_FRAME as char).unwrap();
+        let keys = pairs
+            .split_whitespace()
+            .map(|pair| pair.split_once(':').unwrap().0.parse().unwrap())
+            .collect();
+        (keys, blocks.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn seeking_in_a_range_skips_the_blocks_before_the_key() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        fill_level(&mut db, 0, |storage, dir| {
+            vec![table_of(storage, dir, 0..10_000)]
+        });
+        let db = Arc::new(db);
+        let mut client = Client::connect(&db, &config).await;
+        client.handshake().await;
+        let seek = |key: i32| [&[b'q'][..], &key.to_be_bytes()].concat();
+
+        let response = client.request(&seek(5)).await;
+        assert_eq!(
+            response.as_bytes()[..2],
+            [ERROR_FRAME, CommandError::NoRange.code()]
+        );
+
+        assert_eq!(client.request(b"v\x01").await, "OK");
+        let (keys, all_blocks) = keys_and_blocks(&client.request(&range(1_000, 9_000)).await);
+        assert_eq!(keys, (1_000..9_000).collect::<Vec<_>>());
+
+        for key in [4_321, 8_000, 8_999] {
+            let (keys, blocks) = keys_and_blocks(&client.request(&seek(key)).await);
+            assert_eq!(keys, (key..9_000).collect::<Vec<_>>(), "{key}");
+            // the same blocks a range starting there reads, none of those before the key
+            let (_, from_key) = keys_and_blocks(&client.request(&range(key, 9_000)).await);
+            assert_eq!(blocks, from_key, "{key}");
+            assert!(blocks < all_blocks, "{key}");
+        }
+        // never back before where the range started
+        client.request(&range(1_000, 9_000)).await;
+        let (keys, _) = keys_and_blocks(&client.request(&seek(500)).await);
+        assert_eq!(keys, (1_000..9_000).collect::<Vec<_>>());
+        let (keys, blocks) = keys_and_blocks(&client.request(&seek(9_000)).await);
+        assert_eq!((keys, blocks), (vec![], 0));
+    }
+
+    #[tokio::test]
+    async fn ranges_honor_the_kind_of_each_bound() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        for key in [i32::MIN, 0, 1, 2, 3, 4, 5, 6, i32::MAX] {
+            db.insert(key, 1).await;
+        }
+        let mut client = Client::connect(&db, &config).await;
+        client.handshake().await;
+        // bit 3 makes the min key exclusive, bit 4 the max key inclusive
+        let keys = |response: String| -> Vec<i32> {
+            let pairs = response.split_whitespace();
+            pairs
+                .map(|pair| pair.split_once(':').unwrap().0.parse().unwrap())
+                .collect()
+        };
+        let range = |extra: u8, min: i32, max: i32| {
+            [
+                &[b'r', 3 | extra][..],
+                &min.to_be_bytes(),
+                &max.to_be_bytes(),
+            ]
+            .concat()
+        };
+
+        for (extra, expected) in [
+            (0, vec![2, 3, 4]),
+            (16, vec![2, 3, 4, 5]),
+            (8, vec![3, 4]),
+            (8 | 16, vec![3, 4, 5]),
+        ] {
+            assert_eq!(
+                keys(client.request(&range(extra, 2, 5)).await),
+                expected,
+                "{extra}"
+            );
+            let count = [
+                &[b'n', 3 | extra][..],
+                &2i32.to_be_bytes(),
+                &5i32.to_be_bytes(),
+            ]
+            .concat();
+            assert_eq!(client.request(&count).await, expected.len().to_string());
+        }
+        let none: [i32; 0] = [];
+        // empty and single key ranges, and bounds at the ends of the keys that can't wrap
+        assert_eq!(keys(client.request(&range(0, 5, 5)).await), none);
+        assert_eq!(keys(client.request(&range(16, 5, 5)).await), [5]);
+        assert_eq!(keys(client.request(&range(8 | 16, 5, 5)).await), none);
+        assert_eq!(
+            keys(client.request(&range(0, i32::MIN, i32::MIN)).await),
+            none
+        );
+        assert_eq!(
+            keys(client.request(&range(8 | 16, i32::MAX, i32::MAX)).await),
+            none
+        );
+        assert_eq!(
+            keys(client.request(&range(16, i32::MIN, i32::MAX)).await),
+            [i32::MIN, 0, 1, 2, 3, 4, 5, 6, i32::MAX]
+        );
+        assert_eq!(
+            keys(client.request(&range(8, i32::MIN, i32::MAX)).await),
+            [0, 1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ranges_list_deleted_keys_only_if_asked() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        for key in 0..6 {
+            db.insert(key, key * 10).await;
+        }
+        db.delete(1).await;
+        db.flush_if_stale(Duration::ZERO).await;
+        db.delete(3).await;
+        // deleted before it was ever written, still a tombstone
+        db.delete(7).await;
+        let mut client = Client::connect(&db, &config).await;
+        client.handshake().await;
+        let range =
+            |flags: u8| [&[b'r', flags][..], &0i32.to_be_bytes(), &8i32.to_be_bytes()].concat();
+
+        assert_eq!(client.request(&range(3)).await, "0:0 2:20 4:40 5:50 ");
+        assert_eq!(
+            client.request(&range(3 | 4)).await,
+            "0:0 1:DELETED 2:20 3:DELETED 4:40 5:50 7:DELETED "
+        );
+        // RANGE_SEEK keeps to what its RANGE asked for
+        let seek = [&[b'q'][..], &2i32.to_be_bytes()].concat();
+        assert_eq!(
+            client.request(&seek).await,
+            "2:20 3:DELETED 4:40 5:50 7:DELETED "
+        );
+        client.request(&range(3)).await;
+        assert_eq!(client.request(&seek).await, "2:20 4:40 5:50 ");
+    }
+
+    fn subscribe(from: u64) -> Vec<u8> {
+        [&[b'u'][..], &from.to_be_bytes()].concat()
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_every_write_in_order() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        let mut writer = Client::connect(&db, &config).await;
+        let mut subscriber = Client::connect(&db, &config).await;
+        let subscribed = subscriber.request(&subscribe(0)).await;
+        assert_eq!(subscribed, format!("OK {}", db.change_epoch()));
+
+        let mut expected = vec![];
+        for key in 0..50 {
+            assert_eq!(writer.request(&put(key, -key)).await, "OK");
+            expected.push(format!("{} PUT {key} {}", key + 1, -key));
+        }
+        let delete = [&[b'd'][..], &7i32.to_be_bytes()].concat();
+        assert_eq!(writer.request(&delete).await, "OK");
+        expected.push("51 DELETE 7".into());
+        let merge = [&[b'm'][..], &3i32.to_be_bytes(), &10i32.to_be_bytes()].concat();
+        assert_eq!(writer.request(&merge).await, "OK");
+        expected.push("52 MERGE 3 10".into());
+
+        for change in expected {
+            assert_eq!(subscriber.response().await, change);
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_resuming_past_what_was_kept_get_a_gap() {
+        // with a log every write is published, skip list memory level or not
+        let config = in_memory_config(&["--change-buffer", "4", "--wal"]);
+        let db = Arc::new(Database::new(config.clone()));
+        for key in 1..=10 {
+            db.insert(key, key).await;
+        }
+        let mut subscriber = Client::connect(&db, &config).await;
+        assert!(subscriber.request(&subscribe(2)).await.starts_with("OK "));
+        // 2 through 6 were dropped for the last 4
+        assert_eq!(subscriber.response().await, "GAP 5");
+        for sequence in 7..=10 {
+            let change = format!("{sequence} PUT {sequence} {sequence}");
+            assert_eq!(subscriber.response().await, change);
+        }
+        db.delete(1).await;
+        assert_eq!(subscriber.response().await, "11 DELETE 1");
+    }
+
+    /// Pairs of a range response as text, and the token after them if there is one
+    fn pairs_and_token(response: &str) -> (Vec<String>, Option<Vec<u8>>) {
+        let (pairs, token) = match response.split_once("NEXT ") {
+            Some((pairs, token)) => (pairs, Some(token)),
+            None => (response, None),
+        };
+        let token = token.map(|hex| {
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect()
+        });
+        (pairs.split_whitespace().map(String::from).collect(), token)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paging_through_a_range_gets_what_one_range_does() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        fill_level(&mut db, 1, |storage, dir| {
+            vec![table_of(storage, dir, 0..3_000)]
+        });
+        // newer values and deletes above it, on disk and in memory
+        for key in (1_000..2_000).step_by(2) {
+            db.insert(key, -key).await;
+        }
+        db.flush_if_stale(Duration::ZERO).await;
+        db.delete_range(2_000, 2_100).await;
+        db.insert(2_050, 1).await;
+        db.delete(10).await;
+        let db = Arc::new(db);
+        let mut client = Client::connect(&db, &config).await;
+        client.handshake().await;
+
+        // till the end, up to 2999 inclusive, and up to 2999 exclusive
+        for (flags, max_key) in [(1, 0), (2 | 16, 2_999), (3, 2_999)] {
+            let bounds = [
+                &[flags][..],
+                &5i32.to_be_bytes(),
+                &i32::to_be_bytes(max_key),
+            ]
+            .concat();
+            let range = client.request(&[&[b'r'][..], &bounds].concat()).await;
+            let (all, token) = pairs_and_token(&range);
+            assert_eq!(token, None);
+            assert!(all.len() > 2_800, "{flags}");
+
+            for limit in [7u32, 100, 1_000, 5_000] {
+                let mut request = [&[b'b'][..], &bounds, &limit.to_be_bytes()].concat();
+                let mut paged = vec![];
+                loop {
+                    let (pairs, token) = pairs_and_token(&client.request(&request).await);
+                    assert!(pairs.len() <= limit as usize);
+                    paged.extend(pairs);
+                    let Some(token) = token else { break };
+                    request = [&[b'y'][..], &token, &limit.to_be_bytes()].concat();
+                }
+                assert_eq!(paged, all, "{flags} {limit}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_nodelay_follows_the_config() {
+        for (args, nodelay) in [(&[][..], false), (&["--tcp-nodelay"][..], true)] {
+            let config = in_memory_config(args);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let _client = TcpStream::connect(listener.local_addr().unwrap()).await;
+            let (stream, addr) = listener.accept().await.unwrap();
+            let connection = Connection::new(stream, addr, CancellationToken::new(), &config);
+            assert_eq!(
+                connection.writer.get_ref().as_ref().nodelay().unwrap(),
+                nodelay
+            );
+        }
+    }
+
+    impl Client {
+        /// The next response with binary results on, its frames turned back into the text they
+        /// stand for
+        async fn binary_response(&mut self) -> String {
+            let mut text = String::new();
+            loop {
+                match self.stream.read_u8().await.unwrap() {
+                    BINARY_VALUE => {
+                        text.push_str(&self.stream.read_i32().await.unwrap().to_string())
+                    }
+                    BINARY_PAIRS => {
+                        for _ in 0..self.stream.read_u32().await.unwrap() {
+                            let key = self.stream.read_i32().await.unwrap();
+                            let val = self.stream.read_i32().await.unwrap();
+                            text.push_str(&format!("{key}:{val} "));
+                        }
+                    }
+                    0 => return text,
+                    byte => text.push(byte as char),
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn binary_results_say_what_text_ones_do() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        // keys and values whose bytes hold the delimiter
+        fill_level(&mut db, 0, |storage, dir| {
+            vec![table_of(storage, dir, (-100_000..100_000).step_by(2))]
+        });
+        let db = Arc::new(db);
+        db.insert(0x100, 0).await;
+        db.delete(4).await;
+        let mut text = Client::connect(&db, &config).await;
+        text.handshake().await;
+        let mut binary = Client::connect(&db, &config).await;
+        binary.handshake().await;
+        assert_eq!(binary.request(b"a\x01").await, "OK");
+        let range = |flags: u8, min: i32, max: i32| {
+            [&[b'r', flags][..], &min.to_be_bytes(), &max.to_be_bytes()].concat()
+        };
+        let page = [&range(3, -10, 10)[..], &3u32.to_be_bytes()].concat();
+
+        let requests = [
+            get(0x100),
+            get(-2),
+            get(3),
+            get(4),
+            b"o\x01".to_vec(),
+            get(4),
+            range(3, -10, 10),
+            range(3 | 4, -10, 10),
+            range(3, 20, 10),
+            [b"b", &page[1..]].concat(),
+            range(0, 0, 0),
+        ];
+        for request in requests {
+            let expected = text.request(&request).await;
+            binary.send(&request).await;
+            assert_eq!(binary.binary_response().await, expected, "{request:?}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    async fn binary_ranges_arrive_faster_than_text_ones() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        fill_level(&mut db, 0, |storage, dir| {
+            vec![table_of(storage, dir, -1_000_000..1_000_000)]
+        });
+        let db = Arc::new(db);
+        let mut text = Client::connect(&db, &config).await;
+        text.handshake().await;
+        let mut binary = Client::connect(&db, &config).await;
+        binary.handshake().await;
+        assert_eq!(binary.request(b"a\x01").await, "OK");
+        let range_all = [b'r', 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        // best of a few, each the whole range read and turned back into pairs
+        let (mut text_took, mut binary_took) = (Duration::MAX, Duration::MAX);
+        for _ in 0..5 {
+            let timer = Instant::now();
+            let pairs = text.request(&range_all).await;
+            text_took = text_took.min(timer.elapsed());
+
+            let timer = Instant::now();
+            binary.send(&range_all).await;
+            let binary_pairs = binary.binary_response().await;
+            binary_took = binary_took.min(timer.elapsed());
+            assert_eq!(binary_pairs, pairs);
+        }
+        println!("2M pairs: binary {binary_took:?}, text {text_took:?}");
+        assert!(binary_took < text_took);
+    }
+
+    #[tokio::test]
+    async fn features_left_out_of_the_handshake_are_refused() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        let mut client = Client::connect(&db, &config).await;
+        // a newer client asking for tombstones and a feature this server never heard of
+        let wanted = FEATURE_TOMBSTONES | 1 << 20;
+        let handshake = [&[HANDSHAKE][..], &7u16.to_be_bytes(), &wanted.to_be_bytes()].concat();
+        let agreed = format!("{PROTOCOL_VERSION} {FEATURE_TOMBSTONES}");
+        assert_eq!(client.request(&handshake).await, agreed);
+
+        let refused = |response: String| {
+            response.as_bytes()[..2] == [ERROR_FRAME, CommandError::NotNegotiated.code()]
+        };
+        assert!(refused(client.request(b"a\x01").await));
+        assert!(refused(client.request(&subscribe(0)).await));
+        // turning one off is always fine, and the rest works as before
+        assert_eq!(client.request(b"a\x00").await, "OK");
+        assert_eq!(client.request(b"o\x01").await, "OK");
+        assert_eq!(client.request(&put(1, 10)).await, "OK");
+        assert_eq!(client.request(&get(1)).await, "10");
+
+        // a client that never handshakes gets everything
+        let mut old = Client::connect(&db, &config).await;
+        assert_eq!(old.request(&get(1)).await, "10");
+        assert_eq!(old.request(b"a\x01").await, "OK");
+    }
+
+    #[tokio::test]
+    async fn ranges_without_a_handshake_have_no_flags_byte() {
+        let config = in_memory_config(&[]);
+        let db = Arc::new(Database::new(config.clone()));
+        for key in 0..6 {
+            db.insert(key, key * 10).await;
+        }
+        db.delete(3).await;
+        // min key inclusive, max key exclusive, and a GET pipelined right behind
+        let mut old = Client::connect(&db, &config).await;
+        let legacy_range = [&[b'r'][..], &1i32.to_be_bytes(), &5i32.to_be_bytes()].concat();
+        old.send(&[&legacy_range[..], &get(5)].concat()).await;
+        assert_eq!(old.response().await, "1:10 2:20 4:40 ");
+        assert_eq!(old.response().await, "50");
+
+        // once handshaken, the same bytes start with flags
+        let mut new = Client::connect(&db, &config).await;
+        new.handshake().await;
+        let range = [&[b'r', 3][..], &1i32.to_be_bytes(), &5i32.to_be_bytes()].concat();
+        assert_eq!(new.request(&range).await, "1:10 2:20 4:40 ");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn budgeted_range_reads_no_further_than_the_client() {
+        let keys = 1_000_000;
+        for (args, sees_late_write) in
+            [(&["--range-budget-bytes", "65536"][..], true), (&[], false)]
+        {
+            let config = in_memory_config(args);
+            let mut db = Database::new(config.clone());
+            fill_level(&mut db, 0, |storage, dir| {
+                (0..4)
+                    .map(|t| table_of(storage, dir, t * keys / 4..(t + 1) * keys / 4))
+                    .collect()
+            });
+            let db = Arc::new(db);
+            let mut client = Client::connect(&db, &config).await;
+            client.handshake().await;
+
+            client.send(&range(0, keys)).await;
+            // the first pairs are out, the client stops reading for a while
+            assert_eq!(client.stream.read_u8().await.unwrap(), b'0');
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            db.insert(keys - 1, -1).await;
+
+            let response = client.response().await;
+            let last = response.split_whitespace().last().unwrap();
+            let expected = match sees_late_write {
+                // only a part at a time was read, and the last one only once the client got to it
+                true => format!("{}:-1", keys - 1),
+                false => format!("{0}:{0}", keys - 1),
+            };
+            assert_eq!(last, expected, "{args:?}");
+            assert_eq!(response.split_whitespace().count(), keys as usize);
+        }
+    }
+
+    /// An INGEST of the tables in `dir` into `level`
+    fn ingest(level: u8, merge: bool, dir: &Path) -> Vec<u8> {
+        let dir = dir.to_str().unwrap().as_bytes();
+        let header = [b'I', level, merge as u8];
+        [&header[..], &(dir.len() as u16).to_be_bytes(), dir].concat()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tables_built_offline_are_ingested_and_served() {
+        let directory = scratch_directory("ingest-offline");
+        let incoming = directory.join("incoming");
+        let storage: Arc<dyn Storage> = Arc::new(FsStorage);
+        let options = TableOptions {
+            bloom: true,
+            key_index_every: 0,
+            write_buffer_bytes: 0,
+        };
+        // a batch job's sorted output, more than a table holds
+        let commands = (0..600_000).map(|key| match key {
+            7 => block::Command::Delete(key),
+            key => block::Command::Put(key, -key),
+        });
+        let built = build_tables(commands, &storage, &incoming, options).len();
+        assert!(built > 1);
+
+        let data_dir = directory.join("db");
+        let args = ["--data-dir", data_dir.to_str().unwrap()];
+        let config = Config::parse(args.iter().map(|arg| arg.to_string()));
+        let db = Arc::new(Database::new(config.clone()));
+        // newer than anything ingested below it
+        db.insert(5, 55).await;
+        let mut client = Client::connect(&db, &config).await;
+        client.handshake().await;
+        assert_eq!(
+            client.request(&ingest(2, false, &incoming)).await,
+            format!("OK {built}")
+        );
+        // moved into the level, not copied
+        assert_eq!(fs::read_dir(&incoming).unwrap().count(), 0);
+
+        for (key, expected) in [
+            (0, "0"),
+            (5, "55"),
+            (7, ""),
+            (599_999, "-599999"),
+            (600_000, ""),
+        ] {
+            assert_eq!(client.request(&get(key)).await, expected, "{key}");
+        }
+        assert_eq!(client.request(&range(6, 9)).await, "6:-6 8:-8 ");
+        drop(client);
+        drop(db);
+
+        // and found again on restart
+        let db = Database::new(config);
+        assert_eq!(db.get(450_000).await.0, Some(-450_000));
+        drop(db);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// A pair the way `write_int` used to format it, through `write!` into a cursor
+    fn pair_with_write(out: &mut Vec<u8>, key: i32, val: Option<i32>) {
+        let mut buf = [0u8; 12];
+        let mut int = |out: &mut Vec<u8>, val: i32| {
+            let mut cursor = Cursor::new(&mut buf[..]);
+            write!(cursor, "{val}").unwrap();
+            let len = cursor.position() as usize;
+            out.extend_from_slice(&buf[..len]);
+        };
+        int(out, key);
+        out.push(b':');
+        match val {
+            Some(val) => int(out, val),
+            None => out.extend_from_slice(b"DELETED"),
+        }
+        out.push(b' ');
+    }
+
+    #[test]
+    fn pairs_encode_to_the_bytes_write_did() {
+        let edges = [
+            0,
+            1,
+            -1,
+            9,
+            10,
+            -10,
+            999_999,
+            i32::MAX,
+            i32::MIN,
+            i32::MIN + 1,
+        ];
+        for key in edges {
+            for val in edges.map(Some).into_iter().chain([None]) {
+                let mut expected = vec![];
+                pair_with_write(&mut expected, key, val);
+                let mut buf = [0; PAIR_BYTES];
+                let len = encode_pair(&mut buf, key, val);
+                assert_eq!(buf[..len], expected, "{key} {val:?}");
+            }
+        }
+    }
+
+    /// A range of a million pairs formatted with `write!` and with `encode_pair`, and how long
+    /// each took
+    fn million_pairs_both_ways() -> ((Vec<u8>, Duration), (Vec<u8>, Duration)) {
+        let pairs = || (0..1_000_000).map(|key: i32| (key.wrapping_mul(7919), -key));
+        let start = Instant::now();
+        let mut with_write = Vec::with_capacity(24_000_000);
+        for (key, val) in pairs() {
+            pair_with_write(&mut with_write, key, Some(val));
+        }
+        let write_took = start.elapsed();
+
+        let start = Instant::now();
+        let mut encoded = Vec::with_capacity(24_000_000);
+        let mut buf = [0; PAIR_BYTES];
+        for (key, val) in pairs() {
+            let len = encode_pair(&mut buf, key, Some(val));
+            encoded.extend_from_slice(&buf[..len]);
+        }
+        ((with_write, write_took), (encoded, start.elapsed()))
+    }
+
+    #[test]
+    fn million_pair_range_encodes_like_write() {
+        let ((with_write, _), (encoded, _)) = million_pairs_both_ways();
+        assert_eq!(encoded, with_write);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn encode_pair_formats_faster_than_write() {
+        let ((_, write_took), (_, encode_took)) = million_pairs_both_ways();
+        println!("1M pairs: write! {write_took:?}, encode_pair {encode_took:?}");
+        assert!(encode_took < write_took);
+    }
+
+    /// Median round trip of one GET at a time
+    async fn single_get_round_trip(args: &[&str]) -> Duration {
+        let config = in_memory_config(args);
+        let db = Arc::new(Database::new(config.clone()));
+        db.insert(1, 1).await;
+        let mut client = Client::connect(&db, &config).await;
+        let mut round_trips = vec![];
+        for _ in 0..200 {
+            let start = Instant::now();
+            assert_eq!(client.request(&get(1)).await, "1");
+            round_trips.push(start.elapsed());
+        }
+        round_trips.sort();
+        round_trips[round_trips.len() / 2]
+    }
+
+    #[tokio::test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    async fn single_command_round_trips_with_and_without_nodelay() {
+        // a response goes out in one flush either way, so Nagle has nothing to hold back and
+        // neither waits out a delayed ack
+        let with = single_get_round_trip(&["--tcp-nodelay"]).await;
+        let without = single_get_round_trip(&[]).await;
+        println!("median GET round trip: {with:?} with nodelay, {without:?} without");
+        assert!(
+            with < Duration::from_millis(20) && without < Duration::from_millis(20),
+            "median round trips: {with:?} with nodelay, {without:?} without"
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_responses_to_pipelined_commands_all_arrive() {
+        for policy in ["command", "batched"] {
+            let config = in_memory_config(&["--flush-policy", policy]);
+            let db = Arc::new(Database::new(config.clone()));
+            let mut client = Client::connect(&db, &config).await;
+            let pipelined: Vec<u8> = (0..100)
+                .flat_map(|key| [put(key, -key), get(key)].concat())
+                .collect();
+            client.send(&pipelined).await;
+            for key in 0..100 {
+                assert_eq!(client.response().await, "OK");
+                assert_eq!(client.response().await, (-key).to_string(), "{policy}");
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn gets_answered_during_a_long_merge() {
+        let config = in_memory_config(&[]);
+        let mut db = Database::new(config.clone());
+        // level 1 and the memory level overlap all over, so the flush has most blocks to rewrite
+        let level1 = 2_000_000;
+        fill_level(&mut db, 0, |storage, dir| {
+            (0..5)
+                .map(|t| table_of(storage, dir, t * level1 / 5..(t + 1) * level1 / 5))
+                .collect()
+        });
+        fill_level(&mut db, 2, |storage, dir| {
+            vec![table_of(storage, dir, level1..level1 + 10)]
+        });
+        let db = Arc::new(db);
+        for key in (0..level1).step_by(500) {
+            db.insert(key, -key).await;
+        }
+        let mut client = Client::connect(&db, &config).await;
+        assert_eq!(client.request(&get(level1)).await, level1.to_string());
+
+        // on the only worker thread, which block_in_place hands over to the other tasks
+        let flushing = db.clone();
+        let flush = tokio::spawn(async move { flushing.flush_if_stale(Duration::ZERO).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        assert_eq!(
+            client.request(&get(level1 + 1)).await,
+            (level1 + 1).to_string()
+        );
+        let waited = start.elapsed();
+        assert!(!flush.is_finished(), "the merge was over before the get");
+        flush.await.unwrap();
+        // not held up by the merge's lock on level 1 either
+        let merged = start.elapsed();
+        assert!(
+            waited * 4 < merged,
+            "get took {waited:?} of the merge's {merged:?}"
+        );
+        assert_eq!(client.request(&get(500)).await, "-500");
+    }
 }
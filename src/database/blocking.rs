@@ -0,0 +1,178 @@
+use std::{ops::Bound, sync::Arc};
+
+use tokio::runtime::{self, Runtime};
+
+use super::{Database, WriteError};
+use crate::config::Config;
+
+/// `Database` for callers that aren't async. It owns a multi-threaded runtime of its own, since
+/// flushes and compactions use `block_in_place`, and every call blocks the calling thread on it
+/// until the command is done. Calls can come from any number of threads at once, just not from
+/// inside another tokio runtime, where blocking would stall its workers.
+pub struct BlockingDatabase {
+    runtime: Runtime,
+    db: Arc<Database>,
+}
+
+impl BlockingDatabase {
+    pub fn new(config: Config) -> Self {
+        Self::with_database(Database::new(config))
+    }
+
+    /// Gets and ranges over an existing database, writes fail with `WriteError::ReadOnly`
+    pub fn open_read_only(config: Config) -> Self {
+        Self::with_database(Database::open_read_only(config))
+    }
+
+    fn with_database(db: Database) -> Self {
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let db = Arc::new(db);
+        if db.config.compaction_concurrency.is_some() {
+            // stops along with the runtime
+            let db = db.clone();
+            runtime.spawn(async move {
+                loop {
+                    db.compaction_needed().await;
+                    db.run_pending_compactions().await;
+                }
+            });
+        }
+        Self { runtime, db }
+    }
+
+    pub fn get(&self, key: i32) -> Option<i32> {
+        self.runtime.block_on(self.db.get(key)).0
+    }
+
+    /// Rejected when read-only, outside the configured bounds or once the disk quota is exceeded, same as a
+    /// client's PUT
+    pub fn put(&self, key: i32, value: i32) -> Result<(), WriteError> {
+        self.db.check_writable()?;
+        self.db.check_bounds(key, Some(value))?;
+        self.runtime.block_on(async {
+            self.db.check_quota().await?;
+            self.db.insert(key, value).await;
+            Ok(())
+        })
+    }
+
+    pub fn delete(&self, key: i32) -> Result<(), WriteError> {
+        self.db.check_writable()?;
+        self.runtime.block_on(self.db.delete(key));
+        Ok(())
+    }
+
+    /// Pairs within the bounds, sorted by key
+    pub fn range(&self, start: Bound<i32>, end: Bound<i32>) -> Vec<(i32, i32)> {
+        let (pairs, _) = self.runtime.block_on(self.db.range(start, end, || false));
+        let mut pairs: Vec<(i32, i32)> = pairs.into_iter().flatten().collect();
+        pairs.sort_unstable_by_key(|&(key, _)| key);
+        pairs
+    }
+
+    /// Saves the memory level so the next open picks it up, like the server does on shutdown
+    pub fn close(self) {
+        self.runtime.block_on(self.db.cleanup());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Bound, time::Duration};
+
+    use tokio::runtime;
+
+    use super::BlockingDatabase;
+    use crate::{connection::tests::in_memory_config, database::Database};
+
+    enum Step {
+        Put(i32, i32),
+        Delete(i32),
+        Get(i32),
+        Range(Bound<i32>, Bound<i32>),
+        Flush,
+    }
+
+    /// Puts and deletes over a few rounds with flushes between, reading back as it goes
+    fn script() -> Vec<Step> {
+        let mut steps = vec![];
+        for round in 0..4 {
+            steps.extend((round * 100..round * 100 + 300).map(|key| Step::Put(key, key + round)));
+            steps.extend(
+                (round * 100..round * 100 + 300)
+                    .step_by(7)
+                    .map(Step::Delete),
+            );
+            steps.extend((-5..700).step_by(11).map(Step::Get));
+            steps.push(Step::Range(Bound::Included(150), Bound::Excluded(450)));
+            steps.push(Step::Range(Bound::Unbounded, Bound::Included(99)));
+            if round % 2 == 1 {
+                steps.push(Step::Flush);
+            }
+        }
+        steps.push(Step::Range(Bound::Unbounded, Bound::Unbounded));
+        steps
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Answer {
+        Value(Option<i32>),
+        Pairs(Vec<(i32, i32)>),
+    }
+
+    #[test]
+    fn blocking_calls_answer_like_async_ones() {
+        let config = in_memory_config(&[]);
+        let blocking = BlockingDatabase::new(config.clone());
+        let mut blocking_answers = vec![];
+        for step in script() {
+            match step {
+                Step::Put(key, val) => blocking.put(key, val).unwrap(),
+                Step::Delete(key) => blocking.delete(key).unwrap(),
+                Step::Get(key) => blocking_answers.push(Answer::Value(blocking.get(key))),
+                Step::Range(start, end) => {
+                    blocking_answers.push(Answer::Pairs(blocking.range(start, end)))
+                }
+                Step::Flush => {
+                    let db = &blocking.db;
+                    blocking.runtime.block_on(db.flush_if_stale(Duration::ZERO));
+                }
+            }
+        }
+        blocking.close();
+
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let async_answers = runtime.block_on(async {
+            let db = Database::new(config);
+            let mut answers = vec![];
+            for step in script() {
+                match step {
+                    Step::Put(key, val) => db.insert(key, val).await,
+                    Step::Delete(key) => db.delete(key).await,
+                    Step::Get(key) => answers.push(Answer::Value(db.get(key).await.0)),
+                    Step::Range(start, end) => {
+                        let (pairs, _) = db.range(start, end, || false).await;
+                        let mut pairs: Vec<_> = pairs.into_iter().flatten().collect();
+                        pairs.sort_unstable();
+                        answers.push(Answer::Pairs(pairs));
+                    }
+                    Step::Flush => {
+                        db.flush_if_stale(Duration::ZERO).await;
+                    }
+                }
+            }
+            answers
+        });
+
+        assert_eq!(blocking_answers.len(), async_answers.len());
+        assert_eq!(blocking_answers, async_answers);
+        // and the script reads something back
+        assert!(matches!(blocking_answers.last(), Some(Answer::Pairs(pairs)) if pairs.len() > 500));
+    }
+}
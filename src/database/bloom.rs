@@ -1,18 +1,50 @@
 use fixedbitset::FixedBitSet;
-use std::hash::{BuildHasher, Hash, Hasher, RandomState};
+use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
 
-#[derive(Debug, Default)]
+use crate::config::{BLOOM_BITS_PER_KEY, BLOOM_CAPACITY};
+
+#[derive(Debug, Default, Clone)]
 pub struct Bloom {
     inner: FixedBitSet,
-    random_state: RandomState,
+    hasher: BuildHasherDefault<DefaultHasher>,
 }
 
 impl Bloom {
     pub fn new(capacity: usize) -> Self {
-        return Self {
+        Self {
             inner: FixedBitSet::with_capacity(capacity),
-            random_state: RandomState::new(),
-        };
+            hasher: BuildHasherDefault::default(),
+        }
+    }
+
+    /// Empty, with room for `keys` keys at `BLOOM_BITS_PER_KEY` bits each: `BLOOM_CAPACITY` bits
+    /// doubled as often as it takes
+    pub fn with_room_for(keys: usize) -> Self {
+        Self::new(bits_for(keys))
+    }
+
+    /// Sized for the keys like `with_room_for`
+    pub fn from_keys(keys: &[i32]) -> Self {
+        let mut bloom = Self::with_room_for(keys.len());
+        for &key in keys {
+            bloom.put(key);
+        }
+        bloom
+    }
+
+    /// Whether `keys` keys all told still get `BLOOM_BITS_PER_KEY` bits each
+    pub fn fits(&self, keys: usize) -> bool {
+        bits_for(keys) <= self.inner.len()
+    }
+
+    /// Keys put in so far, going by how many bits they set
+    pub fn estimated_keys(&self) -> usize {
+        let bits = self.inner.len() as f64;
+        let unset = bits - self.inner.count_ones(..) as f64;
+        if unset == 0.0 {
+            return usize::MAX;
+        }
+        (-bits * (unset / bits).ln()).round() as usize
     }
 
     pub fn put(&mut self, key: i32) {
@@ -23,9 +55,59 @@ impl Bloom {
         self.inner[self.get_index(key)]
     }
 
+    /// Memory the bits take
+    pub fn size_bytes(&self) -> usize {
+        self.inner.len().div_ceil(8)
+    }
+
+    /// Single hash function => false positive rate is the fraction of set bits
+    pub fn estimated_fp_rate(&self) -> f64 {
+        self.inner.count_ones(..) as f64 / self.inner.len() as f64
+    }
+
     fn get_index(&self, key: i32) -> usize {
-        let mut hasher = self.random_state.build_hasher();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) % self.inner.len()
+        (self.hasher.hash_one(key) as usize) % self.inner.len()
+    }
+}
+
+fn bits_for(keys: usize) -> usize {
+    keys.saturating_mul(BLOOM_BITS_PER_KEY)
+        .max(BLOOM_CAPACITY)
+        .next_power_of_two()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Share of keys the filter lets through out of `keys`, none of which were put in
+    fn fp_rate(bloom: &Bloom, keys: impl Iterator<Item = i32>) -> f64 {
+        let (mut hits, mut total) = (0, 0);
+        for key in keys {
+            hits += usize::from(bloom.maybe_contains(key));
+            total += 1;
+        }
+        hits as f64 / total as f64
+    }
+
+    #[test]
+    fn rejects_absent_keys() {
+        let keys: Vec<i32> = (0..1000).map(|key| key * 2).collect();
+        let bloom = Bloom::from_keys(&keys);
+        assert!(keys.iter().all(|&key| bloom.maybe_contains(key)));
+        // 1000 keys in the smallest filter, far fewer false positives than a full one
+        assert!(fp_rate(&bloom, (0..1000).map(|key| key * 2 + 1)) < 0.05);
+    }
+
+    #[test]
+    fn stays_within_target_rate_past_capacity() {
+        let keys: Vec<i32> = (0..2 * BLOOM_CAPACITY as i32).collect();
+        let bloom = Bloom::from_keys(&keys);
+        assert!(bloom.fits(keys.len()));
+        assert!(!bloom.fits(2 * keys.len()));
+        // 1 - e^(-1/10) at exactly 10 bits per key, the filter rounds up from there
+        let rate = fp_rate(&bloom, keys.len() as i32..3 * keys.len() as i32);
+        assert!(rate < 0.095, "false positive rate {rate}");
+        assert!((bloom.estimated_fp_rate() - rate).abs() < 0.01);
     }
 }
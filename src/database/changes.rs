@@ -0,0 +1,134 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::broadcast;
+
+use super::table::block::Command;
+
+/// A write as it was applied to the memory level
+#[derive(Clone, Copy, Debug)]
+pub struct Change {
+    pub sequence: u64,
+    pub command: Command,
+}
+
+/// "<sequence> <command>", e.g. "12 PUT 5 42"
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.sequence, self.command)
+    }
+}
+
+impl FromStr for Change {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid change {s:?}");
+        let mut parts = s.split(' ');
+        let sequence = parts.next().and_then(|part| part.parse().ok());
+        let op = parts.next();
+        let mut arg = || parts.next().and_then(|part| part.parse::<i32>().ok());
+        let command = match op {
+            Some("PUT") => Command::Put(arg().ok_or_else(invalid)?, arg().ok_or_else(invalid)?),
+            Some("DELETE") => Command::Delete(arg().ok_or_else(invalid)?),
+            Some("DELETE_RANGE") => {
+                Command::DeleteRange(arg().ok_or_else(invalid)?, arg().ok_or_else(invalid)?)
+            }
+            Some("MERGE") => Command::Merge(arg().ok_or_else(invalid)?, arg().ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            sequence: sequence.ok_or_else(invalid)?,
+            command,
+        })
+    }
+}
+
+/// Writes for SUBSCRIBE-d connections, published under the memory level's write lock so sequence
+/// numbers follow the order writes were applied in. Numbering starts at 1 every time the server
+/// does, it isn't saved anywhere; the epoch tells runs apart.
+///
+/// The last `capacity` changes are kept, for subscribers resuming from a sequence number, and
+/// every subscriber reads from a buffer of the same size. One that falls `capacity` changes
+/// behind loses the oldest ones it hasn't read, and is told how many on its next receive.
+pub struct ChangeFeed {
+    sender: broadcast::Sender<Change>,
+    recent: Mutex<VecDeque<Change>>,
+    capacity: usize,
+    next_sequence: AtomicU64,
+    epoch: u64,
+}
+
+impl ChangeFeed {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity.max(1)).0,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            next_sequence: AtomicU64::new(1),
+            // when the server started, in ms
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        }
+    }
+
+    /// Numbers the write whether anyone is subscribed or not, so sequence numbers count every
+    /// write since startup
+    pub fn publish(&self, command: Command) {
+        // held while sending too, so a subscriber gets every change exactly once from what was
+        // kept or from the channel
+        let mut recent = self.recent.lock().unwrap();
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let change = Change { sequence, command };
+        if self.capacity > 0 {
+            if recent.len() == self.capacity {
+                recent.pop_front();
+            }
+            recent.push_back(change);
+        }
+        // no subscribers is the only way this fails
+        let _ = self.sender.send(change);
+    }
+
+    /// Numbers a write that's never published, so subscribers resuming across it see a gap
+    /// instead of missing it silently
+    pub fn skip(&self) {
+        self.next_sequence.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Changes from sequence number `from` on (0 for only those published from here on): the kept
+    /// ones first, then the receiver for the rest. Along with them, how many changes between
+    /// `from` and the first one returned are gone.
+    pub fn subscribe_from(&self, from: u64) -> (Vec<Change>, u64, broadcast::Receiver<Change>) {
+        let recent = self.recent.lock().unwrap();
+        let next_sequence = self.next_sequence.load(Ordering::Relaxed);
+        let from = match from {
+            0 => next_sequence,
+            from => from.min(next_sequence),
+        };
+        let kept: Vec<Change> = recent
+            .iter()
+            .filter(|change| change.sequence >= from)
+            .copied()
+            .collect();
+        let first = kept.first().map_or(next_sequence, |change| change.sequence);
+        (kept, first - from, self.sender.subscribe())
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
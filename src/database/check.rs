@@ -0,0 +1,93 @@
+use std::fmt::Display;
+
+use super::table::{block::BlockError, HeaderError};
+
+#[derive(Debug)]
+pub enum ViolationKind {
+    OutOfOrderKey {
+        block: usize,
+        prev: i32,
+        key: i32,
+    },
+    KeyOutsideTableRange {
+        block: usize,
+        key: i32,
+    },
+    IndexMismatch {
+        block: usize,
+        expected: (i32, i32),
+        found: (i32, i32),
+    },
+    BlockCountMismatch {
+        expected: usize,
+        found: usize,
+    },
+    OverlapsTable {
+        other: String,
+    },
+    TooManyRuns {
+        runs: usize,
+        max: usize,
+    },
+    MemorySizeMismatch {
+        tracked: usize,
+        actual: usize,
+    },
+    BadFileName,
+    BadHeader(HeaderError),
+    CorruptBlock {
+        block: usize,
+        error: BlockError,
+    },
+}
+
+/// A broken invariant found in some table of a level
+pub struct Violation {
+    pub level: u32,
+    pub table: String,
+    pub kind: ViolationKind,
+}
+
+impl Display for ViolationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfOrderKey { block, prev, key } => {
+                write!(f, "block {block}: key {key} comes after key {prev}")
+            }
+            Self::KeyOutsideTableRange { block, key } => {
+                write!(f, "block {block}: key {key} outside of file name range")
+            }
+            Self::IndexMismatch {
+                block,
+                expected,
+                found,
+            } => write!(
+                f,
+                "block {block}: index says {}..={} but block holds {}..={}",
+                expected.0, expected.1, found.0, found.1
+            ),
+            Self::BlockCountMismatch { expected, found } => {
+                write!(f, "index has {expected} blocks but file has {found}")
+            }
+            Self::OverlapsTable { other } => write!(f, "overlaps table {other}"),
+            Self::TooManyRuns { runs, max } => {
+                write!(f, "level holds {runs} sorted runs, more than {max}")
+            }
+            Self::MemorySizeMismatch { tracked, actual } => {
+                write!(
+                    f,
+                    "tracked size is {tracked} bytes but commands take {actual}"
+                )
+            }
+            Self::BadFileName => write!(f, "file name isn't <min key>_<max key>[_<sequence>]"),
+            Self::BadHeader(err) => write!(f, "{err}"),
+            Self::CorruptBlock { block, error } => write!(f, "block {block}: {error}"),
+        }
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LVL{} {}: {}", self.level, self.table, self.kind)
+    }
+}
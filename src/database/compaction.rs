@@ -0,0 +1,354 @@
+//! Building and merging tables without a running `Database`, for tools that prepare tables offline
+//! and hand them to the server with INGEST. Flushes and compactions go through the same code.
+
+use std::{cmp::Ordering, fmt::Display, path::Path, sync::Arc};
+
+use super::{
+    check::ViolationKind,
+    merge_iter::{merge_sorted_commands, split_range_deletes},
+    storage::Storage,
+    table::{
+        block::{BlockMut, BlockView, Command},
+        check_header, parse_file_name, HeaderError, Table, TableBuilder, TableOptions,
+    },
+};
+
+/// Why a table file can't be taken as is
+pub enum InvalidTable {
+    Name(String), // not "<min key>_<max key>"
+    Header(HeaderError),
+    Contents(Vec<ViolationKind>),
+}
+
+impl Display for InvalidTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{name:?} isn't named <min key>_<max key>"),
+            Self::Header(err) => write!(f, "{err}"),
+            Self::Contents(violations) => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(f, "{}", violations.join(", "))
+            }
+        }
+    }
+}
+
+/// Reads a table file someone else wrote, checking its name, header and blocks the way `FSCK`
+/// does instead of trusting them like startup does with the server's own files
+pub fn open_table(
+    storage: &Arc<dyn Storage>,
+    path: &Path,
+    options: TableOptions,
+) -> Result<Table, InvalidTable> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let bounds = parse_file_name(name);
+    if bounds.is_none_or(|(min_key, max_key, _)| min_key > max_key) {
+        return Err(InvalidTable::Name(name.to_string()));
+    }
+    check_header(storage.as_ref(), path).map_err(InvalidTable::Header)?;
+
+    let table = Table::create_from_existing(storage, path, options)
+        .map_err(|kind| InvalidTable::Contents(vec![kind]))?;
+    let violations = table.check();
+    if !violations.is_empty() {
+        return Err(InvalidTable::Contents(violations));
+    }
+    Ok(table)
+}
+
+/// Merges two levels' worth of tables into new ones under `to_dir`, `newer` winning on equal keys
+/// like a level does over the one below it. Each side has to be sorted and free of overlaps.
+pub fn merge_tables(
+    newer: &[Table],
+    older: &[Table],
+    storage: &Arc<dyn Storage>,
+    to_dir: &Path,
+    options: TableOptions,
+) -> Vec<Table> {
+    let commands = merge_sorted_commands(level_commands(newer), level_commands(older));
+    build_tables(commands, storage, to_dir, options)
+}
+
+fn level_commands(tables: &[Table]) -> impl Iterator<Item = Command> + '_ {
+    tables.iter().flat_map(|table| table.commands(0, false))
+}
+
+/// Writes sorted `commands` into as many full tables under `to_dir` as they take
+pub fn build_tables<I: Iterator<Item = Command>>(
+    commands: I,
+    storage: &Arc<dyn Storage>,
+    to_dir: &Path,
+    options: TableOptions,
+) -> Vec<Table> {
+    let mut writer = TablesWriter::new(storage, to_dir, options);
+    writer.push_commands(commands);
+    writer.finish()
+}
+
+/// Fills tables one after the other, from commands and from blocks copied out of other tables.
+/// Whatever gets pushed has to come in key order.
+pub struct TablesWriter<'a> {
+    storage: &'a Arc<dyn Storage>,
+    to_dir: &'a Path,
+    options: TableOptions,
+    tb: TableBuilder,
+    block: BlockMut,
+    new_tables: Vec<Table>,
+}
+
+impl<'a> TablesWriter<'a> {
+    pub fn new(storage: &'a Arc<dyn Storage>, to_dir: &'a Path, options: TableOptions) -> Self {
+        Self {
+            storage,
+            to_dir,
+            options,
+            tb: TableBuilder::new(storage, to_dir, options),
+            block: BlockMut::new(),
+            new_tables: vec![],
+        }
+    }
+
+    pub fn push_commands<I: Iterator<Item = Command>>(&mut self, iter: I) {
+        for command in split_range_deletes(iter) {
+            if !self.block.push_command(command) {
+                self.write_block();
+                assert!(self.block.push_command(command));
+            }
+        }
+    }
+
+    /// Ends the block being filled, padding it out, and copies `block` in after it
+    pub fn push_block(
+        &mut self,
+        block: &BlockView,
+        bounds: (i32, i32),
+        range_deletes: &[(i32, i32)],
+    ) {
+        if !self.block.is_empty() {
+            self.block.pad();
+            self.write_block();
+        }
+        self.tb.insert_raw_block(block, bounds, range_deletes);
+        self.start_table_if_full();
+    }
+
+    fn write_block(&mut self) {
+        self.tb.insert_block(&self.block);
+        self.start_table_if_full();
+        self.block.clear();
+    }
+
+    fn start_table_if_full(&mut self) {
+        if self.tb.is_full() {
+            let tb = TableBuilder::new(self.storage, self.to_dir, self.options);
+            let new_table = std::mem::replace(&mut self.tb, tb).build();
+            self.new_tables.push(new_table);
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<Table> {
+        if !self.block.is_empty() {
+            self.tb.insert_block(&self.block);
+        }
+        if !self.tb.is_empty() {
+            self.new_tables.push(self.tb.build());
+        }
+        self.new_tables
+    }
+}
+
+/// How the tables of a level line up against those of the level below it
+pub struct Intersections {
+    pub moves: Vec<usize>, // l1 tables intersecting nothing in l2
+    pub groups: Vec<IntersectionGroup>,
+}
+
+/// Tables `tables1.0..tables1.1` of the upper level overlap `tables2.0..tables2.1` of the lower
+/// one, and no others
+pub struct IntersectionGroup {
+    pub tables1: (usize, usize),
+    pub tables2: (usize, usize),
+}
+
+/// Both levels' tables sorted by key and not overlapping one another, as in any level
+pub fn find_intersections<'a>(tables_l1: &'a [Table], tables_l2: &'a [Table]) -> Intersections {
+    let mut non_intersecting = Vec::new();
+    let mut intersecting_groups = Vec::new();
+
+    let mut i = 0;
+    let mut j = 0;
+
+    while i < tables_l1.len() {
+        let start_i = i;
+
+        while j < tables_l2.len() && tables_l1[i].intersects(&tables_l2[j]) == Ordering::Greater {
+            j += 1;
+        }
+
+        let start_j = j;
+
+        let mut intersected = false;
+        while j < tables_l2.len() && tables_l1[i].intersects(&tables_l2[j]) == Ordering::Equal {
+            intersected = true;
+            j += 1;
+        }
+
+        if intersected {
+            i += 1;
+
+            while i < tables_l1.len() {
+                let intersects_prev = tables_l1[i].intersects(&tables_l2[j - 1]);
+                let intersects_cur = if j < tables_l2.len() {
+                    tables_l1[i].intersects(&tables_l2[j])
+                } else {
+                    Ordering::Less
+                };
+                if intersects_prev == Ordering::Equal || intersects_cur == Ordering::Equal {
+                    if intersects_cur == Ordering::Equal {
+                        j += 1;
+                    }
+                    while j < tables_l2.len()
+                        && tables_l1[i].intersects(&tables_l2[j]) == Ordering::Equal
+                    {
+                        j += 1;
+                    }
+                } else {
+                    break;
+                }
+                i += 1;
+            }
+
+            intersecting_groups.push(IntersectionGroup {
+                tables1: (start_i, i),
+                tables2: (start_j, j),
+            });
+        } else {
+            non_intersecting.push(i);
+            i += 1;
+        }
+    }
+
+    Intersections {
+        moves: non_intersecting,
+        groups: intersecting_groups,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::merge_tables;
+    use crate::config::{BLOCK_SIZE_BYTES, BLOOM_CAPACITY, TABLE_HEADER_BYTES};
+    use crate::database::{
+        storage::{MemStorage, Storage},
+        table::{tests::table_of, Table, TableOptions},
+    };
+
+    /// The table's blocks as they are in its file, without the header and its sequence number
+    fn blocks_of(table: &Table) -> Vec<u8> {
+        let len = table.file_size as usize - TABLE_HEADER_BYTES;
+        let mut bytes = vec![0; len];
+        let file = table.storage.open(&table.file_path()).unwrap();
+        file.read_at(&mut bytes, TABLE_HEADER_BYTES as u64).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn merged_table_bloom_is_sized_for_its_keys() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let keys = BLOOM_CAPACITY as i32;
+        let newer = table_of(&storage, Path::new("level1"), (0..2 * keys).step_by(2));
+        let older = table_of(&storage, Path::new("level2"), (1..2 * keys).step_by(2));
+        let options = TableOptions {
+            bloom: true,
+            key_index_every: 0,
+            write_buffer_bytes: 0,
+        };
+        let merged = merge_tables(&[newer], &[older], &storage, Path::new("out"), options);
+        assert_eq!(merged.len(), 1);
+
+        let bloom = merged[0].bloom.as_ref().unwrap();
+        assert!((0..2 * keys).all(|key| bloom.maybe_contains(key)));
+        let false_positives = (2 * keys..6 * keys)
+            .filter(|&key| bloom.maybe_contains(key))
+            .count();
+        let rate = false_positives as f64 / (4 * keys) as f64;
+        assert!(rate < 0.095, "false positive rate {rate}");
+    }
+
+    #[test]
+    fn write_buffer_size_leaves_the_file_as_is() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let newer = [table_of(
+            &storage,
+            Path::new("level1"),
+            (0..20_000).step_by(2),
+        )];
+        let older = [table_of(
+            &storage,
+            Path::new("level2"),
+            (1..20_000).step_by(2),
+        )];
+        let merged_with = |write_buffer_bytes| {
+            let options = TableOptions {
+                bloom: false,
+                key_index_every: 0,
+                write_buffer_bytes,
+            };
+            let out = format!("out{write_buffer_bytes}");
+            let merged = merge_tables(&newer, &older, &storage, Path::new(&out), options);
+            assert_eq!(merged.len(), 1);
+            (blocks_of(&merged[0]), merged[0].index.clone())
+        };
+
+        let unbuffered = merged_with(0);
+        assert!(unbuffered.1.len() > 1);
+        // smaller than a block, so each goes straight through, then either side of one block
+        for bytes in [
+            1,
+            BLOCK_SIZE_BYTES - 1,
+            BLOCK_SIZE_BYTES,
+            BLOCK_SIZE_BYTES + 1,
+            1 << 20,
+        ] {
+            assert!(merged_with(bytes) == unbuffered, "{bytes} byte buffer");
+        }
+    }
+
+    #[test]
+    fn merging_overlapping_tables_keeps_keys_strictly_increasing() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        // every third key in both, and runs only one side has
+        let newer = table_of(
+            &storage,
+            Path::new("level1"),
+            (0..30_000).filter(|k| k % 3 != 1),
+        );
+        let older = table_of(
+            &storage,
+            Path::new("level2"),
+            (0..30_000).filter(|k| k % 3 != 2),
+        );
+        let options = TableOptions {
+            bloom: false,
+            key_index_every: 0,
+            write_buffer_bytes: 0,
+        };
+        let merged = merge_tables(&[newer], &[older], &storage, Path::new("out"), options);
+
+        let keys: Vec<i32> = merged
+            .iter()
+            .flat_map(|t| t.commands(0, false))
+            .map(|c| c.key())
+            .collect();
+        assert_eq!(keys, (0..30_000).collect::<Vec<_>>());
+        let blocks: Vec<(i32, i32)> = merged.iter().flat_map(|t| t.index.clone()).collect();
+        assert!(blocks.len() > 1);
+        assert!(blocks.iter().all(|&(min, max)| min <= max));
+        assert!(blocks.windows(2).all(|w| w[0].1 < w[1].0));
+    }
+}
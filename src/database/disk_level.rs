@@ -1,14 +1,26 @@
 use std::{
-    cmp::Ordering,
-    fs,
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    iter,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI32, Ordering as AtomicOrdering},
+        Arc,
+    },
 };
 
 use crate::config::{LEVEL1_FILE_CAPACITY, MAX_FILE_SIZE_BYTES, SIZE_MULTIPLIER};
 
 use super::{
-    table::{block::Command, Table},
-    GetResult,
+    bloom::Bloom,
+    check::{Violation, ViolationKind},
+    merge_iter::{merge_sorted_commands, split_range_deletes},
+    storage::Storage,
+    table::{
+        block::{decode_commands, Command},
+        is_temp_file, Table, TableOptions,
+    },
+    GetResult, Probe,
 };
 
 pub struct LocateResult {
@@ -16,38 +28,269 @@ pub struct LocateResult {
     pub block_index: usize,
 }
 
+/// Smallest and largest key a level may hold, readable without locking the level. Always covers
+/// the level's tables: widened before data shows up in it, only narrowed once data is gone.
 #[derive(Debug)]
-pub struct DiskLevel {
-    pub level: u32,
-    pub level_directory: PathBuf,
-    pub tables: Vec<Table>, // sorted array by keys
+pub struct KeySpan {
+    min: AtomicI32,
+    max: AtomicI32,
 }
 
-impl DiskLevel {
-    pub fn new(data_directory: &Path, level: u32) -> Self {
-        let mut level_directory = PathBuf::from(data_directory);
-        level_directory.push(format!("level{level}"));
+impl KeySpan {
+    fn empty() -> Self {
+        Self {
+            min: AtomicI32::new(i32::MAX),
+            max: AtomicI32::new(i32::MIN),
+        }
+    }
+
+    pub fn contains(&self, key: i32) -> bool {
+        self.min.load(AtomicOrdering::Relaxed) <= key
+            && key <= self.max.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn overlaps(&self, min_key: i32, max_key: i32) -> bool {
+        self.min.load(AtomicOrdering::Relaxed) <= max_key
+            && min_key <= self.max.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn widen(&self, min_key: i32, max_key: i32) {
+        self.min.fetch_min(min_key, AtomicOrdering::Relaxed);
+        self.max.fetch_max(max_key, AtomicOrdering::Relaxed);
+    }
+}
+
+/// Filter over the point keys of a whole level, at `BLOOM_BITS_PER_KEY` bits each like a table's.
+/// A table's filter can't be folded into a bigger one, so each table joining the level hands over
+/// the keys it was built with. Keys of tables that left stay in, which only costs false positives,
+/// until they outnumber the live ones or overfill the filter. A table joining without its keys
+/// leaves the filter letting every key through. Either way it takes a `build`, which reads every
+/// table back and so is never run under the level's write lock.
+#[derive(Debug)]
+pub struct LevelBloom {
+    bloom: Bloom,
+    tables: HashMap<u64, usize>, // point keys put in for each table, by id
+    put_keys: usize,             // these plus the keys of tables since gone
+    range_deletes: bool,         // some table has range deletes, which no filter knows about
+    complete: bool,              // no table joined without its keys
+}
+
+impl LevelBloom {
+    fn new() -> Self {
+        Self {
+            bloom: Bloom::with_room_for(0),
+            tables: HashMap::new(),
+            put_keys: 0,
+            range_deletes: false,
+            complete: true,
+        }
+    }
+
+    /// Reads the point keys of every table back from its file, with room for the level to double.
+    /// Sized up front by the keys the tables' own filters hold.
+    pub fn build(tables: &[Table]) -> Self {
+        let keys: usize = tables
+            .iter()
+            .filter_map(|t| t.bloom.as_ref())
+            .map(Bloom::estimated_keys)
+            .sum();
+        let mut level_bloom = Self {
+            bloom: Bloom::with_room_for(2 * keys),
+            range_deletes: tables.iter().any(|t| !t.range_deletes.is_empty()),
+            ..Self::new()
+        };
+        for table in tables {
+            let keys = table.point_keys();
+            level_bloom.put(&keys);
+            level_bloom.tables.insert(table.id(), keys.len());
+        }
+        level_bloom
+    }
+
+    /// Catches up with the level's tables, putting in the keys the ones joining it hand over.
+    /// Never reads a block, it runs under the level's write lock.
+    fn update(&mut self, tables: &mut [Table]) {
+        if tables.is_empty() {
+            *self = Self::new();
+            return;
+        }
+        self.range_deletes = tables.iter().any(|t| !t.range_deletes.is_empty());
+
+        let mut live = HashMap::with_capacity(tables.len());
+        for table in tables.iter_mut() {
+            let pending = table.take_pending_keys();
+            if let Some(&keys) = self.tables.get(&table.id()) {
+                live.insert(table.id(), keys);
+                continue;
+            }
+            // an appended table's other keys went in with the table it extends
+            let earlier = match &pending {
+                Some((_, None)) => Some(0),
+                Some((_, Some(appended_to))) => self.tables.get(appended_to).copied(),
+                None => None,
+            };
+            match (pending, earlier) {
+                (Some((keys, _)), Some(earlier)) => {
+                    self.put(&keys);
+                    live.insert(table.id(), earlier + keys.len());
+                }
+                _ => self.complete = false,
+            }
+        }
+        self.tables = live;
+    }
+
+    fn put(&mut self, keys: &[i32]) {
+        for &key in keys {
+            self.bloom.put(key);
+        }
+        self.put_keys += keys.len();
+    }
+
+    /// Whether a `build` would do better: some table's keys are missing, or the keys of tables
+    /// that left outnumber the live ones, or there are more keys than the filter has room for
+    pub fn needs_rebuild(&self) -> bool {
+        let live_keys: usize = self.tables.values().sum();
+        !self.complete || !self.bloom.fits(self.put_keys) || self.put_keys - live_keys > live_keys
+    }
 
-        fs::create_dir_all(&level_directory).unwrap();
+    /// Whether the filter has the keys of exactly `tables`
+    fn covers(&self, tables: &[Table]) -> bool {
+        self.complete
+            && self.tables.len() == tables.len()
+            && tables.iter().all(|t| self.tables.contains_key(&t.id()))
+    }
 
-        let mut tables = vec![];
+    pub fn maybe_contains(&self, key: i32) -> bool {
+        !self.complete || self.bloom.maybe_contains(key)
+    }
 
-        for entry in fs::read_dir(&level_directory).unwrap() {
-            let entry = entry.unwrap();
-            tables.push(Table::create_from_existing(&entry.path()));
+    pub fn estimated_fp_rate(&self) -> f64 {
+        match self.complete {
+            true => self.bloom.estimated_fp_rate(),
+            false => 1.0,
         }
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.bloom.size_bytes()
+    }
+}
+
+#[derive(Debug)]
+pub struct DiskLevel {
+    pub storage: Arc<dyn Storage>,
+    pub level: u32,
+    pub level_directory: PathBuf,
+    pub tables: Vec<Table>,          // sorted array by keys
+    pub table_options: TableOptions, // what the level's tables build besides their blocks
+    pub span: Arc<KeySpan>,          // shared with the database, so gets can skip the level's lock
+    // rejects a key for the whole level before looking for its table, None without table filters
+    pub level_bloom: Option<LevelBloom>,
+    pub max_runs: usize, // sorted runs the level may hold before they're merged into one
+    runs: usize,         // kept up to date by `tables_changed`
+}
 
+impl DiskLevel {
+    /// `tables` are the ones recovered from `existing_tables`
+    pub fn new(
+        storage: &Arc<dyn Storage>,
+        data_directory: &Path,
+        level: u32,
+        table_options: TableOptions,
+        tables: Vec<Table>,
+    ) -> Self {
         let mut res = Self {
+            storage: storage.clone(),
             level,
-            level_directory,
+            level_directory: Self::directory(data_directory, level),
             tables,
+            table_options,
+            span: Arc::new(KeySpan::empty()),
+            level_bloom: None,
+            max_runs: 1,
+            runs: 0,
         };
         res.sort_tables();
+        // tables read back at startup come without their keys
+        res.rebuild_level_bloom();
         res
     }
 
+    fn directory(data_directory: &Path, level: u32) -> PathBuf {
+        let mut level_directory = PathBuf::from(data_directory);
+        level_directory.push(format!("level{level}"));
+        level_directory
+    }
+
+    /// Files a previous run left in the level
+    pub fn existing_tables(
+        storage: &Arc<dyn Storage>,
+        data_directory: &Path,
+        level: u32,
+    ) -> Vec<PathBuf> {
+        // the directory only shows up once the first table moves in
+        let Ok(mut paths) = storage.read_dir(&Self::directory(data_directory, level)) else {
+            return vec![];
+        };
+        // left by a builder that never finished, the sweeper removes them
+        paths.retain(|path| !is_temp_file(path));
+        // directory order is up to the OS, sorting keeps recovery the same from run to run
+        paths.sort();
+        paths
+    }
+
     pub fn sort_tables(&mut self) {
         self.tables.sort_by_key(|t| t.min_key);
+        self.tables_changed();
+    }
+
+    /// Swaps in a level bloom `LevelBloom::build` read back from the tables, unless they changed
+    /// since. False if they did, the level keeps the filter it has.
+    pub fn set_level_bloom(&mut self, level_bloom: LevelBloom) -> bool {
+        if !level_bloom.covers(&self.tables) {
+            return false;
+        }
+        self.level_bloom = Some(level_bloom);
+        true
+    }
+
+    /// Builds the level bloom over from the tables if it needs it, reading them in place. Only for
+    /// a level nothing waits on yet, `set_level_bloom` swaps in one built under the read lock.
+    pub fn rebuild_level_bloom(&mut self) {
+        if self
+            .level_bloom
+            .as_ref()
+            .is_some_and(LevelBloom::needs_rebuild)
+        {
+            self.level_bloom = Some(LevelBloom::build(&self.tables));
+        }
+    }
+
+    /// Fits the span, level bloom and run count to the tables, called under the write lock whenever
+    /// they change
+    pub fn tables_changed(&mut self) {
+        self.runs = count_runs(&self.tables);
+        match &mut self.level_bloom {
+            Some(level_bloom) => level_bloom.update(&mut self.tables),
+            None if self.table_options.bloom => {
+                let mut level_bloom = LevelBloom::new();
+                level_bloom.update(&mut self.tables);
+                self.level_bloom = Some(level_bloom);
+            }
+            None => {
+                for table in self.tables.iter_mut() {
+                    table.take_pending_keys();
+                }
+            }
+        }
+
+        let min_key = self.tables.first().map_or(i32::MAX, |t| t.min_key);
+        let max_key = self.tables.iter().map(|t| t.max_key).max();
+        self.span.min.store(min_key, AtomicOrdering::Relaxed);
+        self.span
+            .max
+            .store(max_key.unwrap_or(i32::MIN), AtomicOrdering::Relaxed);
     }
 
     pub fn is_over_file_capacity(&self) -> bool {
@@ -58,10 +301,64 @@ impl DiskLevel {
         LEVEL1_FILE_CAPACITY * usize::pow(SIZE_MULTIPLIER, self.level - 1)
     }
 
+    /// Tables with room left for more blocks
+    pub fn partial_tables(&self) -> usize {
+        self.tables
+            .iter()
+            .filter(|t| t.data_size() < MAX_FILE_SIZE_BYTES as u64)
+            .count()
+    }
+
+    /// Tables with room left for more blocks that overlap any of the inclusive `ranges`
+    pub fn partial_tables_within(&self, ranges: &[(i32, i32)]) -> usize {
+        self.tables
+            .iter()
+            .filter(|t| t.data_size() < MAX_FILE_SIZE_BYTES as u64)
+            .filter(|t| {
+                ranges
+                    .iter()
+                    .any(|&(min_key, max_key)| t.min_key <= max_key && min_key <= t.max_key)
+            })
+            .count()
+    }
+
+    /// Sorted runs a read may have to go through, the most tables covering any one key. One as
+    /// soon as there are tables, unless the level takes flushes as runs of their own.
+    pub fn sorted_runs(&self) -> usize {
+        self.runs
+    }
+
+    /// Memory the level's blooms take, its own and its tables'
+    pub fn bloom_bytes(&self) -> usize {
+        let tables: usize = self
+            .tables
+            .iter()
+            .filter_map(|t| t.bloom.as_ref())
+            .map(|b| b.size_bytes())
+            .sum();
+        tables + self.level_bloom.as_ref().map_or(0, |b| b.size_bytes())
+    }
+
+    /// Reads every block of the level once, for the OS to cache them. Returns the blocks read.
+    pub fn warm(&self) -> usize {
+        let mut blocks = 0;
+        for table in self.tables.iter() {
+            let mut view = table.view();
+            while view.next_block().is_some() {
+                blocks += 1;
+            }
+        }
+        blocks
+    }
+
+    /// 0 without tables
     pub fn average_table_utilization(&self) -> f32 {
+        if self.tables.is_empty() {
+            return 0.0;
+        }
         self.tables
             .iter()
-            .map(|t| t.file_size as f32 / MAX_FILE_SIZE_BYTES as f32)
+            .map(|t| t.data_size() as f32 / MAX_FILE_SIZE_BYTES as f32)
             .sum::<f32>()
             / self.tables.len() as f32
     }
@@ -117,15 +414,221 @@ impl DiskLevel {
         })
     }
 
+    /// Average and max number of `next` tables each table here overlaps, i.e. how many tables a
+    /// merge into `next` rewrites per table. None without tables.
+    pub fn fanout(&self, next: &DiskLevel) -> Option<(f32, usize)> {
+        if self.tables.is_empty() {
+            return None;
+        }
+
+        let overlaps = self.tables.iter().map(|t| {
+            let start = next.tables.partition_point(|n| n.max_key < t.min_key);
+            let end = next.tables.partition_point(|n| n.min_key <= t.max_key);
+            end.saturating_sub(start)
+        });
+        let (total, max) = overlaps.fold((0, 0), |(total, max), n| (total + n, max.max(n)));
+        Some((total as f32 / self.tables.len() as f32, max))
+    }
+
+    /// Adds each block to the bucket its first key falls in, going by the index alone. Blocks hold
+    /// about the same number of entries, so block counts follow the key distribution.
+    pub fn add_to_histogram(&self, buckets: &mut [u64]) {
+        for &(min_key, _) in self.tables.iter().flat_map(|t| t.index.iter()) {
+            let offset = (min_key as i64 - i32::MIN as i64) as u64;
+            let bucket = (offset * buckets.len() as u64) >> 32;
+            buckets[bucket as usize] += 1;
+        }
+    }
+
+    /// Tables never overlap after a merge, but reads shouldn't count on it
+    pub fn has_overlapping_tables(&self) -> bool {
+        self.tables.windows(2).any(|w| w[0].max_key >= w[1].min_key)
+    }
+
+    /// Commands of the level in key order, starting at the block holding `min_key`. None if no
+    /// table holds keys at or above `min_key`. If tables overlap, the ones intersecting the range
+    /// are k-way merged with the table of the highest sequence number winning.
+    pub fn range_commands<'a, F: FnMut() + Clone + 'a>(
+        &'a self,
+        min_key: i32,
+        max_key: i32,
+        on_block: F,
+    ) -> Option<Box<dyn Iterator<Item = Command> + 'a>> {
+        if !self.has_overlapping_tables() {
+            let locate_min = self.locate_start_block(min_key)?;
+            // the index tells which blocks hold keys in range, blocks past max_key aren't read
+            let commands = self.tables[locate_min.table_index..]
+                .iter()
+                .take_while(move |t| t.min_key <= max_key)
+                .enumerate()
+                .flat_map(move |(i, t)| {
+                    let start_block = if i == 0 { locate_min.block_index } else { 0 };
+                    t.commands_ext(
+                        start_block..t.blocks_until(max_key),
+                        false,
+                        on_block.clone(),
+                    )
+                });
+            return Some(Box::new(commands));
+        }
+
+        let mut intersecting: Vec<&Table> = self
+            .tables
+            .iter()
+            .filter(|t| t.min_key <= max_key && t.max_key >= min_key)
+            .collect();
+        if intersecting.is_empty() {
+            return None;
+        }
+        intersecting.sort_by_key(|t| Reverse(t.sequence));
+
+        let mut commands: Box<dyn Iterator<Item = Command> + 'a> = Box::new(iter::empty());
+        for table in intersecting {
+            let start_block = self
+                .find_block_in_table(table, min_key)
+                .unwrap_or_else(|idx| idx);
+            let older = table.commands_ext(
+                start_block..table.blocks_until(max_key),
+                false,
+                on_block.clone(),
+            );
+            commands = Box::new(split_range_deletes(merge_sorted_commands(commands, older)));
+        }
+        Some(commands)
+    }
+
+    /// Same commands as `range_commands`, read through views opened on the tables in range right
+    /// away. They don't borrow the level, so they can be read once its lock is gone, and a table a
+    /// compaction swaps out meanwhile keeps its file until they're done with it.
+    pub fn range_snapshot<F: FnMut() + Clone + Send + 'static>(
+        &self,
+        min_key: i32,
+        max_key: i32,
+        on_block: F,
+    ) -> Option<Box<dyn Iterator<Item = Command> + Send>> {
+        let mut intersecting: Vec<&Table> = self
+            .tables
+            .iter()
+            .filter(|t| t.min_key <= max_key && t.max_key >= min_key)
+            .collect();
+        if intersecting.is_empty() {
+            return None;
+        }
+        let overlapping = self.has_overlapping_tables();
+        if overlapping {
+            intersecting.sort_by_key(|t| Reverse(t.sequence));
+        }
+        let views = intersecting.into_iter().map(|table| {
+            let start_block = self
+                .find_block_in_table(table, min_key)
+                .unwrap_or_else(|idx| idx);
+            table.commands_ext(
+                start_block..table.blocks_until(max_key),
+                false,
+                on_block.clone(),
+            )
+        });
+        if !overlapping {
+            let tables: Vec<_> = views.collect();
+            return Some(Box::new(tables.into_iter().flatten()));
+        }
+
+        let mut commands: Box<dyn Iterator<Item = Command> + Send> = Box::new(iter::empty());
+        for older in views {
+            commands = Box::new(split_range_deletes(merge_sorted_commands(commands, older)));
+        }
+        Some(commands)
+    }
+
+    /// Every command of every table as stored, tombstones and shadowed versions included, table
+    /// by table and block by block. For looking at what's physically in the level.
+    pub fn raw_commands(&self) -> impl Iterator<Item = Command> + '_ {
+        self.tables
+            .iter()
+            .flat_map(|table| table.commands(0, false))
+    }
+
+    /// Tables whose key range holds `key`, newest first. Only ever the one outside of tiered
+    /// levels, where it's found by binary search.
+    fn tables_holding(&self, key: i32) -> impl Iterator<Item = &Table> {
+        let mut found = None;
+        let mut overlapping = vec![];
+        if self.runs <= 1 {
+            found = self.find_table(key).ok().map(|idx| &self.tables[idx]);
+        } else {
+            overlapping = self
+                .tables
+                .iter()
+                .filter(|t| t.min_key <= key && key <= t.max_key)
+                .collect();
+            overlapping.sort_by_key(|t| Reverse(t.sequence));
+        }
+        found.into_iter().chain(overlapping)
+    }
+
+    /// The checks `get` makes before reading a block. Scan-only levels only have fence pointers.
+    pub fn probe(&self, key: i32) -> Probe {
+        let level_miss = self.level_miss(key);
+        if level_miss.is_some_and(|b| !b.range_deletes) {
+            return Probe::Absent;
+        }
+        // the newest run with something on the key hides the older ones
+        for table in self.tables_holding(key) {
+            if table.range_deleted(key) {
+                return Probe::RangeDeleted;
+            }
+            if level_miss.is_some() || table.bloom.as_ref().is_some_and(|b| !b.maybe_contains(key))
+            {
+                continue;
+            }
+            if self.find_block_in_table(table, key).is_ok() {
+                return Probe::Maybe;
+            }
+        }
+        Probe::Absent
+    }
+
+    /// The level filter if it rules out `key`. Only as a point key: range deletes aren't in it, so
+    /// with any in the level the table's still need checking.
+    fn level_miss(&self, key: i32) -> Option<&LevelBloom> {
+        self.level_bloom.as_ref().filter(|b| !b.maybe_contains(key))
+    }
+
     pub fn get(&self, key: i32) -> GetResult {
-        // find table
-        let table = match self.find_table(key) {
-            Ok(idx) => &self.tables[idx],
-            _ => return GetResult::NotFound(false),
-        };
+        // a hit may be a false positive, the table's own filter gets checked below either way
+        let level_miss = self.level_miss(key);
+        if level_miss.is_some_and(|b| !b.range_deletes) {
+            return GetResult::NotFound(false);
+        }
+
+        // runs newest first, merge operands add onto what the older ones have
+        let mut pending: Option<i32> = None;
+        let mut read_block = false;
+        for table in self.tables_holding(key) {
+            match self.get_in_table(table, key, level_miss.is_some()) {
+                GetResult::NotFound(read) => read_block |= read,
+                GetResult::Merge(delta) => {
+                    pending = Some(delta.wrapping_add(pending.unwrap_or(0)));
+                }
+                GetResult::Value(val) => {
+                    return GetResult::Value(val.wrapping_add(pending.unwrap_or(0)));
+                }
+                // merge operands onto a deleted key add onto 0, like in `Database::lookup`
+                GetResult::Deleted => return pending.map_or(GetResult::Deleted, GetResult::Value),
+            }
+        }
+        pending.map_or(GetResult::NotFound(read_block), GetResult::Merge)
+    }
+
+    /// `get` within one table, `level_miss` if the level filter ruled out the key
+    fn get_in_table(&self, table: &Table, key: i32, level_miss: bool) -> GetResult {
+        // range deletes aren't in the bloom filter
+        if table.range_deleted(key) {
+            return GetResult::Deleted;
+        }
 
-        // consult bloom filter
-        if !table.bloom.maybe_contains(key) {
+        // consult bloom filter, scan-only levels go straight to the fence pointers
+        if level_miss || table.bloom.as_ref().is_some_and(|b| !b.maybe_contains(key)) {
             return GetResult::NotFound(false);
         }
 
@@ -135,25 +638,568 @@ impl DiskLevel {
             _ => return GetResult::NotFound(false),
         };
 
-        // read block in table
-        for command in table.view().get_block_at(block_num).unwrap().iter() {
-            if command.key() > key {
-                // block is sorted => can break early
-                break;
+        // read block in table, just the part the key index narrows it down to if there is one
+        let mut view = table.view();
+        match &table.key_index {
+            Some(key_index) => {
+                let span = key_index.span(block_num, key);
+                find_key(
+                    decode_commands(view.get_span_of_block(block_num, span)),
+                    key,
+                )
             }
+            None => find_key(view.get_block_at(block_num).unwrap().iter(), key),
+        }
+    }
 
-            if command.key() == key {
-                match command {
-                    Command::Delete(..) => return GetResult::Deleted,
-                    Command::Put(_, val) => return GetResult::Value(val),
-                }
+    /// Checks each table and that no two tables overlap, or for tiered levels that there aren't
+    /// more runs than allowed. Tables are sorted by min key, so any overlap shows up between
+    /// neighbours.
+    pub fn check(&self) -> Vec<Violation> {
+        let mut violations = vec![];
+
+        for table in self.tables.iter() {
+            violations.extend(table.check().into_iter().map(|kind| Violation {
+                level: self.level,
+                table: table.file_name(),
+                kind,
+            }));
+        }
+
+        if self.max_runs > 1 {
+            if self.runs > self.max_runs {
+                violations.push(Violation {
+                    level: self.level,
+                    table: self.tables[0].file_name(),
+                    kind: ViolationKind::TooManyRuns {
+                        runs: self.runs,
+                        max: self.max_runs,
+                    },
+                });
+            }
+            return violations;
+        }
+
+        for pair in self.tables.windows(2) {
+            if pair[0].intersects(&pair[1]) == Ordering::Equal {
+                violations.push(Violation {
+                    level: self.level,
+                    table: pair[0].file_name(),
+                    kind: ViolationKind::OverlapsTable {
+                        other: pair[1].file_name(),
+                    },
+                });
             }
         }
 
-        GetResult::NotFound(true)
+        violations
     }
 
     pub fn size_bytes(&self) -> usize {
         self.tables.iter().map(|t| t.file_size).sum::<u64>() as usize
     }
 }
+
+/// The most of the sorted `tables` covering any one key
+fn count_runs(tables: &[Table]) -> usize {
+    // max keys of the tables covering the current min key, soonest to end on top
+    let mut open = BinaryHeap::new();
+    let mut runs = 0;
+    for table in tables {
+        while open
+            .peek()
+            .is_some_and(|&Reverse(max_key)| max_key < table.min_key)
+        {
+            open.pop();
+        }
+        open.push(Reverse(table.max_key));
+        runs = runs.max(open.len());
+    }
+    runs
+}
+
+/// Looks for `key` among the sorted commands of a block, the block having been read either way
+fn find_key<I: Iterator<Item = Command>>(commands: I, key: i32) -> GetResult {
+    for command in commands {
+        if command.key() > key {
+            // block is sorted => can break early
+            break;
+        }
+
+        if command.key() == key {
+            match command {
+                Command::Delete(..) | Command::DeleteRange(..) => return GetResult::Deleted,
+                Command::Put(_, val) => return GetResult::Value(val),
+                Command::Merge(_, delta) => return GetResult::Merge(delta),
+            }
+        }
+    }
+
+    GetResult::NotFound(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        io::{self, Write},
+        ops::Range,
+        path::{Path, PathBuf},
+        rc::Rc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Instant, SystemTime},
+    };
+
+    use super::{DiskLevel, LevelBloom};
+    use crate::config::BLOCK_SIZE_BYTES;
+    use crate::database::{
+        check::ViolationKind,
+        storage::{MemStorage, ReadAt, Storage},
+        table::{
+            block::{Command, PUT_BYTES},
+            tests::{table_of, table_with},
+            Table, TableOptions,
+        },
+        GetResult,
+    };
+
+    const OPTIONS: TableOptions = TableOptions {
+        bloom: true,
+        key_index_every: 0,
+        write_buffer_bytes: 0,
+    };
+
+    /// Counts the files opened, which every block read from a table starts with, and the bytes
+    /// read from them
+    #[derive(Debug, Default)]
+    struct CountingStorage {
+        inner: MemStorage,
+        opens: AtomicUsize,
+        bytes_read: Arc<AtomicUsize>,
+    }
+
+    impl CountingStorage {
+        fn opens(&self) -> usize {
+            self.opens.load(Ordering::Relaxed)
+        }
+
+        fn bytes_read(&self) -> usize {
+            self.bytes_read.load(Ordering::Relaxed)
+        }
+    }
+
+    struct CountingReader {
+        inner: Box<dyn ReadAt>,
+        bytes_read: Arc<AtomicUsize>,
+    }
+
+    impl ReadAt for CountingReader {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let read = self.inner.read_at(buf, offset)?;
+            self.bytes_read.fetch_add(read, Ordering::Relaxed);
+            Ok(read)
+        }
+    }
+
+    impl Storage for CountingStorage {
+        fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + Sync>> {
+            self.inner.create(path)
+        }
+        fn reopen(&self, path: &Path, len: u64) -> io::Result<Box<dyn Write + Send + Sync>> {
+            self.inner.reopen(path, len)
+        }
+        fn open(&self, path: &Path) -> io::Result<Box<dyn ReadAt>> {
+            self.opens.fetch_add(1, Ordering::Relaxed);
+            Ok(Box::new(CountingReader {
+                inner: self.inner.open(path)?,
+                bytes_read: self.bytes_read.clone(),
+            }))
+        }
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.rename(from, to)
+        }
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove(path)
+        }
+        fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            self.inner.read_dir(dir)
+        }
+        fn len(&self, path: &Path) -> io::Result<u64> {
+            self.inner.len(path)
+        }
+        fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+            self.inner.modified(path)
+        }
+        fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+            self.inner.create_dir_all(dir)
+        }
+        fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+            self.inner.remove_dir_all(dir)
+        }
+        fn sync(&self, path: &Path) -> io::Result<()> {
+            self.inner.sync(path)
+        }
+    }
+
+    /// Even keys only, 10000 per table, so the odd keys between them are all absent
+    fn even_key_level(storage: &Arc<dyn Storage>, tables: i32) -> DiskLevel {
+        let directory = Path::new("data/level2");
+        let tables = (0..tables)
+            .map(|t| {
+                table_of(
+                    storage,
+                    directory,
+                    (t * 20_000..(t + 1) * 20_000).step_by(2),
+                )
+            })
+            .collect();
+        DiskLevel::new(storage, Path::new("data"), 2, OPTIONS, tables)
+    }
+
+    #[test]
+    fn check_reports_overlapping_tables() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let directory = Path::new("data/level2");
+        let tables = vec![
+            table_of(&storage, directory, 0..100),
+            table_of(&storage, directory, 100..200),
+            table_of(&storage, directory, 150..250),
+        ];
+        let level = DiskLevel::new(&storage, Path::new("data"), 2, OPTIONS, tables);
+
+        let violations = level.check();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].table, "100_199");
+        assert!(matches!(
+            &violations[0].kind,
+            ViolationKind::OverlapsTable { other } if other == "150_249"
+        ));
+    }
+
+    #[test]
+    fn overlapping_tables_merge_newest_sequence_first() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let directory = Path::new("data/level2");
+        let older = table_with(
+            &storage,
+            directory,
+            (0..100).map(|key| Command::Put(key, 1)),
+        );
+        let newer = table_with(
+            &storage,
+            directory,
+            (50..150).map(|key| Command::Put(key, 2)),
+        );
+        assert!(older.sequence < newer.sequence);
+
+        // touching the older file afterwards, e.g. by copying it, can't make it win
+        let len = storage.len(&older.file_path()).unwrap();
+        storage.reopen(&older.file_path(), len).unwrap();
+        // read back like startup does, so the order comes from the headers
+        let tables = [older, newer]
+            .map(|t| Table::create_from_existing(&storage, &t.file_path(), OPTIONS).unwrap());
+        let level = DiskLevel::new(&storage, Path::new("data"), 2, OPTIONS, tables.into());
+        assert!(level.has_overlapping_tables());
+
+        let commands: Vec<Command> = level.range_commands(0, 149, || {}).unwrap().collect();
+        let keys: Vec<i32> = commands.iter().map(|c| c.key()).collect();
+        assert_eq!(keys, (0..150).collect::<Vec<_>>());
+        for command in commands {
+            let expected = if command.key() < 50 { 1 } else { 2 };
+            assert_eq!(command.value(), Some(expected), "{command}");
+        }
+    }
+
+    #[test]
+    fn level_bloom_miss_reads_no_table() {
+        let counting = Arc::new(CountingStorage::default());
+        let storage: Arc<dyn Storage> = counting.clone();
+        let mut level = even_key_level(&storage, 8);
+        // without filters of their own, every get would read the block its key falls in
+        for table in level.tables.iter_mut() {
+            table.bloom = None;
+        }
+        let level_bloom = level.level_bloom.as_ref().unwrap();
+
+        let mut rejected = 0;
+        for key in (1..160_000).step_by(2) {
+            let opens = counting.opens();
+            assert!(matches!(level.get(key), GetResult::NotFound(_)));
+            if !level_bloom.maybe_contains(key) {
+                assert_eq!(counting.opens(), opens, "key {key} read a block");
+                rejected += 1;
+            }
+        }
+        // sized for the level's 80000 keys, a filter of one table's size would let most through
+        assert!(rejected > 72_000, "{rejected} of 80000 rejected");
+    }
+
+    #[test]
+    fn finer_key_index_reads_less_per_get() {
+        let counting = Arc::new(CountingStorage::default());
+        let storage: Arc<dyn Storage> = counting.clone();
+        let directory = Path::new("data/level2");
+        let paths: Vec<PathBuf> = (0..4)
+            .map(|t| table_of(&storage, directory, t * 10_000..(t + 1) * 10_000).file_path())
+            .collect();
+
+        let mut last = usize::MAX;
+        for every in [0, 64, 16, 4] {
+            let options = TableOptions {
+                key_index_every: every,
+                ..OPTIONS
+            };
+            let tables = paths
+                .iter()
+                .map(|path| Table::create_from_existing(&storage, path, options).unwrap())
+                .collect();
+            let level = DiskLevel::new(&storage, Path::new("data"), 2, options, tables);
+
+            let before = counting.bytes_read();
+            let keys = (0..40_000).step_by(37);
+            let gets = keys.len();
+            for key in keys {
+                assert!(matches!(level.get(key), GetResult::Value(val) if val == key));
+            }
+            let read = counting.bytes_read() - before;
+            assert!(
+                read < last,
+                "every {every} read {read}, coarser read {last}"
+            );
+            // a whole block per get without samples, no more than the puts between two with them
+            let per_get = read / gets;
+            if every == 0 {
+                assert!(
+                    (BLOCK_SIZE_BYTES * 9 / 10..=BLOCK_SIZE_BYTES).contains(&per_get),
+                    "{per_get}"
+                );
+            } else {
+                assert!(per_get <= every * PUT_BYTES, "every {every} read {per_get}");
+            }
+            last = read;
+        }
+    }
+
+    #[test]
+    fn level_bloom_leaves_range_deletes_to_the_tables() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let directory = Path::new("data/level2");
+        let commands = [
+            Command::Put(0, 0),
+            Command::DeleteRange(1, 1000),
+            Command::Put(1001, 1),
+        ];
+        let tables = vec![table_with(&storage, directory, commands)];
+        let level = DiskLevel::new(&storage, Path::new("data"), 2, OPTIONS, tables);
+
+        let level_bloom = level.level_bloom.as_ref().unwrap();
+        let key = (1..=1000)
+            .find(|&key| !level_bloom.maybe_contains(key))
+            .unwrap();
+        assert!(matches!(level.get(key), GetResult::Deleted));
+        assert!(matches!(level.get(1001), GetResult::Value(1)));
+    }
+
+    #[test]
+    fn level_bloom_drops_keys_of_tables_that_left() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let mut level = even_key_level(&storage, 4);
+        let directory = Path::new("data/level2");
+        level.tables = vec![table_of(&storage, directory, (1..40_000).step_by(2))];
+        level.tables_changed();
+        // 40000 keys gone, 20000 live
+        assert!(level.level_bloom.as_ref().unwrap().needs_rebuild());
+
+        let rebuilt = LevelBloom::build(&level.tables);
+        assert!(level.set_level_bloom(rebuilt));
+        let level_bloom = level.level_bloom.as_ref().unwrap();
+        assert!(!level_bloom.needs_rebuild());
+        let false_positives = (0..80_000)
+            .step_by(2)
+            .filter(|&key| level_bloom.maybe_contains(key))
+            .count();
+        assert!(false_positives < 4_000, "{false_positives} of 40000");
+    }
+
+    #[test]
+    fn joining_tables_hand_the_level_bloom_their_keys() {
+        let counting = Arc::new(CountingStorage::default());
+        let storage: Arc<dyn Storage> = counting.clone();
+        let mut level = even_key_level(&storage, 4);
+        let directory = Path::new("data/level2");
+        level
+            .tables
+            .push(table_of(&storage, directory, (80_000..100_000).step_by(2)));
+
+        let opens = counting.opens();
+        level.tables_changed();
+        assert_eq!(counting.opens(), opens);
+        let level_bloom = level.level_bloom.as_ref().unwrap();
+        assert!(!level_bloom.needs_rebuild());
+        assert!((80_000..100_000)
+            .step_by(2)
+            .all(|key| level_bloom.maybe_contains(key)));
+    }
+
+    #[test]
+    fn table_joining_without_its_keys_waits_for_a_rebuild() {
+        let counting = Arc::new(CountingStorage::default());
+        let storage: Arc<dyn Storage> = counting.clone();
+        let mut level = even_key_level(&storage, 4);
+        let directory = Path::new("data/level2");
+        // as if moved down from a level whose filter took its keys
+        let mut moved = table_of(&storage, directory, (80_000..100_000).step_by(2));
+        moved.take_pending_keys();
+        level.tables.push(moved);
+
+        let opens = counting.opens();
+        level.tables_changed();
+        assert_eq!(counting.opens(), opens);
+        // lets every key through to the tables' own filters until then
+        let level_bloom = level.level_bloom.as_ref().unwrap();
+        assert!(level_bloom.needs_rebuild());
+        assert!((1..100_000)
+            .step_by(2)
+            .all(|key| level_bloom.maybe_contains(key)));
+        assert!(matches!(level.get(90_000), GetResult::Value(90_000)));
+
+        // a rebuild of tables that changed since isn't swapped in
+        let stale = LevelBloom::build(&level.tables[1..]);
+        assert!(!level.set_level_bloom(stale));
+        let rebuilt = LevelBloom::build(&level.tables);
+        assert!(level.set_level_bloom(rebuilt));
+        let level_bloom = level.level_bloom.as_ref().unwrap();
+        assert!((80_000..100_000)
+            .step_by(2)
+            .all(|key| level_bloom.maybe_contains(key)));
+        let rejected = (1..100_000)
+            .step_by(2)
+            .filter(|&key| !level_bloom.maybe_contains(key))
+            .count();
+        assert!(rejected > 45_000, "{rejected} of 50000 rejected");
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn level_bloom_saves_cpu_on_gets_that_miss() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let mut level = even_key_level(&storage, 64);
+        // 19 in 20 gets miss, on the odd keys between the tables' even ones
+        let keys: Vec<i32> = (0..640_000)
+            .map(|i| if i % 20 == 0 { 2 * i } else { 2 * i + 1 })
+            .collect();
+        let time_gets = |level: &DiskLevel| {
+            let started = Instant::now();
+            for &key in &keys {
+                let expected = key % 2 == 0;
+                assert_eq!(matches!(level.get(key), GetResult::Value(_)), expected);
+            }
+            started.elapsed()
+        };
+
+        let with = time_gets(&level);
+        level.level_bloom = None;
+        let without = time_gets(&level);
+        println!(
+            "{} gets, 95% misses: {with:?} with the level bloom, {without:?} with table blooms alone",
+            keys.len()
+        );
+        assert!(with < without);
+    }
+
+    #[test]
+    fn sorted_runs_count_the_deepest_overlap() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let directory = Path::new("data/level2");
+        let level = |ranges: &[Range<i32>]| {
+            let tables = ranges
+                .iter()
+                .map(|keys| table_of(&storage, directory, keys.clone()))
+                .collect();
+            DiskLevel::new(&storage, Path::new("data"), 2, OPTIONS, tables)
+        };
+
+        assert_eq!(level(&[]).sorted_runs(), 0);
+        assert_eq!(level(&[0..100, 100..200, 200..300]).sorted_runs(), 1);
+        // 0..100 and 150..250 never cover the same key, so no more than two do at once
+        assert_eq!(level(&[0..100, 50..200, 150..250]).sorted_runs(), 2);
+        assert_eq!(level(&[0..300, 50..200, 150..250]).sorted_runs(), 3);
+    }
+
+    #[test]
+    fn warming_reads_every_block_once() {
+        let counting = Arc::new(CountingStorage::default());
+        let storage: Arc<dyn Storage> = counting.clone();
+        let level = even_key_level(&storage, 3);
+        let opens = counting.opens();
+
+        let blocks: usize = level.tables.iter().map(|t| t.index.len()).sum();
+        assert_eq!(level.warm(), blocks);
+        assert_eq!(counting.opens(), opens + 3);
+    }
+
+    #[test]
+    fn range_reads_only_the_blocks_holding_keys_in_range() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let level = even_key_level(&storage, 3);
+        let table = &level.tables[1];
+        let (min_key, max_key) = (23_000, 25_000);
+
+        let blocks_read = Rc::new(Cell::new(0));
+        let counter = blocks_read.clone();
+        let on_block = move || counter.set(counter.get() + 1);
+        let keys: Vec<i32> = level
+            .range_commands(min_key, max_key, on_block)
+            .unwrap()
+            .map(|c| c.key())
+            .filter(|key| (min_key..=max_key).contains(key))
+            .collect();
+        assert_eq!(keys, (min_key..=max_key).step_by(2).collect::<Vec<_>>());
+
+        // from the block holding min_key through the last one starting at or before max_key
+        let first = table.index.partition_point(|&(key, _)| key <= min_key) - 1;
+        let expected = table.blocks_until(max_key) - first;
+        assert!(expected < table.index.len() / 4);
+        assert_eq!(blocks_read.get(), expected);
+    }
+
+    #[test]
+    fn scan_only_level_gets_read_blocks_instead_of_blooms() {
+        let counting = Arc::new(CountingStorage::default());
+        let storage: Arc<dyn Storage> = counting.clone();
+        let with_blooms = even_key_level(&storage, 2);
+        let options = TableOptions {
+            bloom: false,
+            ..OPTIONS
+        };
+        let mut tables = even_key_level(&storage, 2).tables;
+        for table in tables.iter_mut() {
+            table.set_bloom(false);
+        }
+        let scan_only = DiskLevel::new(&storage, Path::new("data"), 2, options, tables);
+        assert!(scan_only.level_bloom.is_none());
+        assert_eq!(scan_only.bloom_bytes(), 0);
+        assert!(with_blooms.bloom_bytes() > 10_000);
+
+        let reads = |level: &DiskLevel| {
+            let opens = counting.opens();
+            for key in 0..40_000 {
+                let expected = if key % 2 == 0 { Some(key) } else { None };
+                match level.get(key) {
+                    GetResult::Value(val) => assert_eq!(Some(val), expected),
+                    GetResult::NotFound(_) => assert_eq!(None, expected, "key {key}"),
+                    _ => panic!("key {key} deleted"),
+                }
+            }
+            counting.opens() - opens
+        };
+        // the same answers, only absent keys cost a block read without blooms, short of the ones
+        // falling between two blocks
+        let (bloom_reads, scan_reads) = (reads(&with_blooms), reads(&scan_only));
+        assert!(scan_reads > 39_000, "{scan_reads} blocks read");
+        assert!(bloom_reads < 22_000, "{bloom_reads} blocks read");
+    }
+}
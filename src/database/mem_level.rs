@@ -1,83 +1,419 @@
-use std::{collections::BTreeMap, fs, ops::Deref, path::Path};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
+#[cfg(feature = "skiplist-memtable")]
+use crossbeam_skiplist::SkipMap;
 use deepsize::DeepSizeOf;
 
+use crate::config::MEM_CAPACITY_BYTES;
+
 use super::{
+    check::ViolationKind,
+    merge_iter::{merge_sorted_commands, split_range_deletes},
+    range_deletes::RangeDeletes,
+    storage::Storage,
     table::{
-        block::{BlockMut, Command},
-        Table, TableBuilder, TableView,
+        block::{BlockMut, Command, DELETE_BYTES, MERGE_BYTES, PUT_BYTES, RANGE_DELETE_BYTES},
+        check_header, is_temp_file, Table, TableBuilder, TableOptions, TableView,
     },
     GetResult,
 };
 
-#[derive(DeepSizeOf)]
+/// Puts and tombstones (None) of the memory level. A BTreeMap only takes inserts under the
+/// memory level's write lock, with `skiplist-memtable` it's a concurrent skip list that plain puts
+/// and deletes insert into under the read lock (see `MemLevel::insert_shared`).
+#[cfg(not(feature = "skiplist-memtable"))]
+#[derive(Default)]
+struct Entries(BTreeMap<i32, Option<i32>>);
+
+#[cfg(feature = "skiplist-memtable")]
+#[derive(Default)]
+struct Entries(SkipMap<i32, Option<i32>>);
+
+#[cfg(not(feature = "skiplist-memtable"))]
+impl Entries {
+    fn get(&self, key: i32) -> Option<Option<i32>> {
+        self.0.get(&key).copied()
+    }
+
+    fn insert(&mut self, key: i32, value: Option<i32>) -> Option<Option<i32>> {
+        self.0.insert(key, value)
+    }
+
+    fn remove(&mut self, key: i32) {
+        self.0.remove(&key);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (i32, Option<i32>)> + '_ {
+        self.0.iter().map(|(&key, &val)| (key, val))
+    }
+
+    fn range(&self, min_key: i32, max_key: i32) -> impl Iterator<Item = (i32, Option<i32>)> + '_ {
+        self.0
+            .range(min_key..=max_key)
+            .map(|(&key, &val)| (key, val))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn deep_size_of_children(&self, context: &mut deepsize::Context) -> usize {
+        self.0.deep_size_of_children(context)
+    }
+}
+
+#[cfg(feature = "skiplist-memtable")]
+impl Entries {
+    fn get(&self, key: i32) -> Option<Option<i32>> {
+        self.0.get(&key).map(|entry| *entry.value())
+    }
+
+    fn insert(&mut self, key: i32, value: Option<i32>) -> Option<Option<i32>> {
+        // exclusive access, so nothing can slip in between the lookup and the insert
+        let old = self.get(key);
+        self.0.insert(key, value);
+        old
+    }
+
+    fn remove(&mut self, key: i32) {
+        self.0.remove(&key);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (i32, Option<i32>)> + '_ {
+        self.0.iter().map(|entry| (*entry.key(), *entry.value()))
+    }
+
+    fn range(&self, min_key: i32, max_key: i32) -> impl Iterator<Item = (i32, Option<i32>)> + '_ {
+        self.0
+            .range(min_key..=max_key)
+            .map(|entry| (*entry.key(), *entry.value()))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn deep_size_of_children(&self, _context: &mut deepsize::Context) -> usize {
+        // a node holds the entry, its tower of next pointers (2 on average) and a refcount
+        self.0.len()
+            * (std::mem::size_of::<(i32, Option<i32>)>() + 3 * std::mem::size_of::<usize>())
+    }
+}
+
 pub struct MemLevel {
-    data: BTreeMap<i32, Option<i32>>, // None represents a tombstone
+    data: Entries,
+    // entries in `data` are always newer than the range deletes covering them
+    range_deletes: RangeDeletes,
+    // merge operands whose base value lives further down, never overlaps `data`/`range_deletes`
+    merges: BTreeMap<i32, i32>,
+    // serialized size of `data` and `merges`, range deletes are counted on demand. Atomic so
+    // shared inserts can charge it, everything else has the level to itself and uses `get_mut`.
+    entry_bytes: AtomicUsize,
+    // what shared inserts charged past the entries they replaced, until `is_full` recounts
+    overcharged: AtomicUsize,
 }
 
-impl Deref for MemLevel {
-    type Target = BTreeMap<i32, Option<i32>>;
+/// Overcharges from shared inserts a full level gets recounted for. Less than this can flush a
+/// level that early, more is worth one pass over the entries.
+const RECOUNT_AT_BYTES: usize = MEM_CAPACITY_BYTES / 64;
 
-    fn deref(&self) -> &Self::Target {
-        return &self.data;
+impl DeepSizeOf for MemLevel {
+    fn deep_size_of_children(&self, context: &mut deepsize::Context) -> usize {
+        self.data.deep_size_of_children(context)
+            + self.range_deletes.deep_size_of_children(context)
+            + self.merges.deep_size_of_children(context)
     }
 }
 
 impl MemLevel {
-    pub fn new(data_directory: &Path) -> Self {
+    /// Reads back what the last shutdown saved to level0, removing the file unless `keep_file`
+    pub fn new(storage: &Arc<dyn Storage>, data_directory: &Path, keep_file: bool) -> Self {
         let level_directory = data_directory.join("level0");
-        fs::create_dir_all(&level_directory).unwrap();
 
         let mut res = Self {
-            data: BTreeMap::new(),
+            data: Entries::default(),
+            range_deletes: RangeDeletes::new(),
+            merges: BTreeMap::new(),
+            entry_bytes: AtomicUsize::new(0),
+            overcharged: AtomicUsize::new(0),
         };
 
-        if let Some(Ok(entry)) = fs::read_dir(&level_directory).unwrap().into_iter().next() {
-            for command in
-                TableView::new(entry.path(), 0).flat_map(|b| unsafe { b.as_ref().unwrap().iter() })
-            {
+        let mut paths = storage.read_dir(&level_directory).unwrap_or_default();
+        paths.retain(|path| !is_temp_file(path));
+        paths.sort();
+        if let Some(path) = paths.into_iter().next() {
+            if let Err(err) = check_header(storage.as_ref(), &path) {
+                panic!("{}: {err}", path.display());
+            }
+            for command in TableView::new(storage.clone(), path.clone(), 0).commands() {
                 match command {
                     Command::Delete(key) => res.delete(key),
                     Command::Put(key, val) => res.insert(key, val),
+                    Command::DeleteRange(start, end) => res.delete_range(start, end),
+                    Command::Merge(key, delta) => {
+                        res.merge(key, delta);
+                    }
                 };
             }
-            let _ = fs::remove_file(&entry.path());
+            if !keep_file {
+                let _ = storage.remove(&path);
+            }
         };
 
-        return res;
+        res
     }
 
     pub fn insert(&mut self, key: i32, value: i32) {
-        self.data.insert(key, Some(value));
+        self.set_entry(key, Some(value));
     }
 
     pub fn delete(&mut self, key: i32) {
-        self.data.insert(key, None);
+        self.set_entry(key, None);
+    }
+
+    fn set_entry(&mut self, key: i32, value: Option<i32>) {
+        *self.entry_bytes.get_mut() += self.entry_size(key, value);
+        if let Some(old) = self.data.insert(key, value) {
+            *self.entry_bytes.get_mut() -= self.entry_size(key, old);
+        }
+        if self.merges.remove(&key).is_some() {
+            *self.entry_bytes.get_mut() -= MERGE_BYTES;
+        }
+    }
+
+    /// Puts (or with None, deletes) a key while other writers may be doing the same, which the
+    /// skip list allows under the memory level's read lock. Returns false without writing if the
+    /// key has a pending merge operand, which only `insert`/`delete` can drop.
+    ///
+    /// The size is charged against the entry the key had on lookup, exact unless another writer
+    /// of the key got in between. The skip list doesn't say which entry its insert replaced, so a
+    /// charge never goes negative: a tombstone over a put is charged nothing, and what it saved
+    /// goes into `overcharged` for `is_full` to refund. The tracked size may run over (flushing a
+    /// little early) but never short.
+    #[cfg(feature = "skiplist-memtable")]
+    pub fn insert_shared(&self, key: i32, value: Option<i32>) -> bool {
+        // merges and range deletes only change under the write lock, so these checks hold
+        if self.merges.contains_key(&key) {
+            return false;
+        }
+        let new = self.entry_size(key, value);
+        let old = self
+            .data
+            .get(key)
+            .map_or(0, |old| self.entry_size(key, old));
+        self.entry_bytes
+            .fetch_add(new.saturating_sub(old), Ordering::Relaxed);
+        self.overcharged
+            .fetch_add(old.saturating_sub(new), Ordering::Relaxed);
+        self.data.0.insert(key, value);
+        true
+    }
+
+    /// Whether the level has reached `MEM_CAPACITY_BYTES` and should be flushed. With enough
+    /// overcharged by shared inserts, the entries are recounted first.
+    pub fn is_full(&mut self) -> bool {
+        if self.size_bytes() >= MEM_CAPACITY_BYTES
+            && *self.overcharged.get_mut() >= RECOUNT_AT_BYTES
+        {
+            let entries: usize = self
+                .data
+                .iter()
+                .map(|(key, value)| self.entry_size(key, value))
+                .sum();
+            *self.entry_bytes.get_mut() = entries + self.merges.len() * MERGE_BYTES;
+            *self.overcharged.get_mut() = 0;
+        }
+        self.size_bytes() >= MEM_CAPACITY_BYTES
+    }
+
+    /// Bytes the entry flushes as. An entry inside a range delete splits it in two, which costs
+    /// one more DeleteRange. Entries are newer than the range deletes covering them, so whether
+    /// a key is covered doesn't change while it's in `data`.
+    fn entry_size(&self, key: i32, value: Option<i32>) -> usize {
+        let split = if self.range_deletes.covers(key) {
+            RANGE_DELETE_BYTES
+        } else {
+            0
+        };
+        match value {
+            Some(_) => PUT_BYTES + split,
+            None => DELETE_BYTES + split,
+        }
+    }
+
+    pub fn delete_range(&mut self, start: i32, end: i32) {
+        let covered: Vec<(i32, Option<i32>)> = self.data.range(start, end).collect();
+        for (key, val) in covered {
+            *self.entry_bytes.get_mut() -= self.entry_size(key, val);
+            self.data.remove(key);
+        }
+        let covered: Vec<i32> = self
+            .merges
+            .range(start..=end)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in covered {
+            *self.entry_bytes.get_mut() -= MERGE_BYTES;
+            self.merges.remove(&key);
+        }
+        self.range_deletes.insert(start, end);
+    }
+
+    /// Applies a record of the write-ahead log, see `Wal` for how merges are logged
+    pub fn replay(&mut self, command: Command) {
+        match command {
+            Command::Delete(key) => self.delete(key),
+            Command::Put(key, val) => self.insert(key, val),
+            Command::DeleteRange(start, end) => self.delete_range(start, end),
+            Command::Merge(key, operand) => self.set_merge_operand(key, operand),
+        }
+    }
+
+    /// Makes `operand` the key's whole pending merge, in place of any entry it had. Mid-replay
+    /// this can leave an operand under a range delete, the later record that put it there
+    /// drops it again.
+    fn set_merge_operand(&mut self, key: i32, operand: i32) {
+        if let Some(old) = self.data.get(key) {
+            *self.entry_bytes.get_mut() -= self.entry_size(key, old);
+            self.data.remove(key);
+        }
+        if self.merges.insert(key, operand).is_none() {
+            *self.entry_bytes.get_mut() += MERGE_BYTES;
+        }
+    }
+
+    /// Adds `delta` onto the key's value, treating a deleted key as 0. Returns what the key ended
+    /// up as, a put or its whole pending operand, which is what the write-ahead log records.
+    pub fn merge(&mut self, key: i32, delta: i32) -> Command {
+        let base = match self.data.get(key) {
+            Some(Some(val)) => Some(val),
+            Some(None) => Some(0),
+            None if self.range_deletes.covers(key) => Some(0),
+            None => None,
+        };
+
+        match base {
+            Some(val) => {
+                let val = val.wrapping_add(delta);
+                self.set_entry(key, Some(val));
+                Command::Put(key, val)
+            }
+            None => {
+                // the value is on disk (if anywhere), resolved on read or during compaction
+                let operand = self.merges.entry(key).or_insert_with(|| {
+                    *self.entry_bytes.get_mut() += MERGE_BYTES;
+                    0
+                });
+                *operand = operand.wrapping_add(delta);
+                Command::Merge(key, *operand)
+            }
+        }
+    }
+
+    /// Puts and tombstones in key order
+    pub fn entries(&self) -> impl Iterator<Item = (i32, Option<i32>)> + '_ {
+        self.data.iter()
+    }
+
+    /// Puts and tombstones of the keys in `min_key..=max_key`, in key order
+    pub fn entries_in(
+        &self,
+        min_key: i32,
+        max_key: i32,
+    ) -> impl Iterator<Item = (i32, Option<i32>)> + '_ {
+        self.data.range(min_key, max_key)
+    }
+
+    pub fn merges(&self) -> &BTreeMap<i32, i32> {
+        &self.merges
+    }
+
+    pub fn range_deletes(&self) -> &RangeDeletes {
+        &self.range_deletes
+    }
+
+    /// Number of commands the level flushes as (before splitting range deletes)
+    pub fn len(&self) -> usize {
+        self.data.len() + self.merges.len() + self.range_deletes.len()
+    }
+
+    /// Bytes the level takes up once flushed to a table, not counting block padding
+    pub fn size_bytes(&self) -> usize {
+        self.entry_bytes.load(Ordering::Relaxed) + self.range_deletes.len() * RANGE_DELETE_BYTES
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.merges.is_empty() && self.range_deletes.is_empty()
+    }
+
+    /// Sorted commands to flush, range deletes split around the newer entries inside them
+    pub fn commands(&self) -> impl Iterator<Item = Command> + '_ {
+        let entries = self.data.iter().map(|(key, val)| match val {
+            None => Command::Delete(key),
+            Some(val) => Command::Put(key, val),
+        });
+        let merges = self
+            .merges
+            .iter()
+            .map(|(&key, &delta)| Command::Merge(key, delta));
+        let entries = merge_sorted_commands(entries, merges);
+        let range_deletes = self
+            .range_deletes
+            .iter()
+            .map(|(start, end)| Command::DeleteRange(start, end));
+
+        split_range_deletes(merge_sorted_commands(entries, range_deletes))
     }
 
     pub fn get(&self, key: i32) -> GetResult {
-        match self.data.get(&key).cloned() {
-            None => GetResult::NotFound(false),
+        match self.data.get(key) {
+            None if self.range_deletes.covers(key) => GetResult::Deleted,
+            None => match self.merges.get(&key) {
+                Some(&delta) => GetResult::Merge(delta),
+                None => GetResult::NotFound(false),
+            },
             Some(None) => GetResult::Deleted,
             Some(Some(val)) => GetResult::Value(val),
         }
     }
 
-    pub fn write_to_table(&self, to_dir: &Path) -> Table {
-        let mut iter = self.iter();
-        let mut tb = TableBuilder::new(to_dir);
+    /// Recounts the flushed size from scratch, it should match what `size_bytes` tracked
+    pub fn check(&self) -> Option<ViolationKind> {
+        let tracked = self.size_bytes();
+        let actual = self.commands().map(|c| c.encoded_len()).sum();
+        // splits are charged per entry, so only the tracked size running short is a problem
+        (tracked < actual).then_some(ViolationKind::MemorySizeMismatch { tracked, actual })
+    }
 
-        let mut block = BlockMut::new();
-        while let Some((&key, &val)) = iter.next() {
-            let command = match val {
-                None => Command::Delete(key),
-                Some(val) => Command::Put(key, val),
-            };
+    pub fn write_to_table(
+        &self,
+        storage: &Arc<dyn Storage>,
+        to_dir: &Path,
+        options: TableOptions,
+    ) -> Table {
+        let mut tb = TableBuilder::new(storage, to_dir, options);
 
+        let mut block = BlockMut::new();
+        for command in self.commands() {
             if !block.push_command(command) {
                 tb.insert_block(&block);
                 block.clear();
-                block.push_command(command);
+                assert!(block.push_command(command));
             }
         }
         if !block.is_empty() {
@@ -87,8 +423,240 @@ impl MemLevel {
         tb.build()
     }
 
+    /// Takes everything out, leaving the level empty. Shared inserts hold the read lock, so with
+    /// the write lock held none can land in the old list after it's taken.
     pub fn clear(&mut self) -> MemLevel {
         let data = std::mem::take(&mut self.data);
-        MemLevel { data }
+        let range_deletes = std::mem::take(&mut self.range_deletes);
+        let merges = std::mem::take(&mut self.merges);
+        let entry_bytes = AtomicUsize::new(std::mem::take(self.entry_bytes.get_mut()));
+        let overcharged = AtomicUsize::new(std::mem::take(self.overcharged.get_mut()));
+        MemLevel {
+            data,
+            range_deletes,
+            merges,
+            entry_bytes,
+            overcharged,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::MemLevel;
+    use crate::{
+        config::{MAX_FILE_SIZE_BLOCKS, MEM_CAPACITY_BYTES},
+        database::{
+            check::ViolationKind,
+            storage::{MemStorage, Storage},
+            table::{block::MAX_COMMAND_BYTES, TableOptions},
+        },
+    };
+
+    fn empty_level() -> MemLevel {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        MemLevel::new(&storage, Path::new("data"), false)
+    }
+
+    #[test]
+    fn check_catches_undercounted_size() {
+        let mut level = empty_level();
+        for key in 0..100 {
+            level.insert(key, key);
+        }
+        level.delete_range(10, 19);
+        assert!(level.check().is_none());
+
+        *level.entry_bytes.get_mut() -= 1;
+        assert!(matches!(
+            level.check(),
+            Some(ViolationKind::MemorySizeMismatch { tracked, actual }) if tracked + 1 == actual
+        ));
+    }
+
+    /// Size of the commands the level flushes as, as written to a block
+    fn flushed_size(level: &MemLevel) -> usize {
+        level.commands().map(|c| c.encoded_len()).sum()
+    }
+
+    #[test]
+    fn full_level_fits_one_table_whatever_it_holds() {
+        // puts, smaller deletes, and puts that each split the range delete under them
+        type Write = fn(&mut MemLevel, i32);
+        let writes: [(bool, Write); 3] = [
+            (false, |level, key| level.insert(key, key)),
+            (false, |level, key| level.delete(key)),
+            (true, |level, key| level.insert(2 * key, key)),
+        ];
+        let mut entries = vec![];
+        for (range_deleted, write) in writes {
+            let mut level = empty_level();
+            if range_deleted {
+                level.delete_range(0, i32::MAX);
+            }
+            let mut key = 0;
+            while level.size_bytes() < MEM_CAPACITY_BYTES {
+                write(&mut level, key);
+                key += 1;
+            }
+            assert!(level.size_bytes() >= flushed_size(&level));
+            entries.push(key);
+
+            let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+            let options = TableOptions {
+                bloom: false,
+                key_index_every: 0,
+                write_buffer_bytes: 0,
+            };
+            let table = level.write_to_table(&storage, Path::new("data/level0"), options);
+            assert!(table.index.len() <= MAX_FILE_SIZE_BLOCKS);
+            assert!(table.index.len() > MAX_FILE_SIZE_BLOCKS * 99 / 100);
+        }
+        // the overflow goes by bytes, not entries
+        assert!(entries[1] > entries[0] && entries[0] > entries[2]);
+    }
+
+    #[test]
+    fn overwrites_keep_the_size_exact() {
+        let mut level = empty_level();
+        for round in 0..10_000 {
+            let key = round % 10;
+            match round % 4 {
+                0 => level.insert(key, round),
+                1 => level.delete(key),
+                2 => {
+                    level.merge(key, 1);
+                }
+                _ => level.insert(key + 10, round),
+            }
+            assert_eq!(level.size_bytes(), flushed_size(&level), "round {round}");
+        }
+        // never more than the 20 keys written over and over
+        assert!(level.size_bytes() <= 20 * MAX_COMMAND_BYTES);
+
+        // under a range delete the splits are charged per entry, never short
+        level.delete_range(-100, 100);
+        for round in 0..1000 {
+            level.insert(round % 10, round);
+            level.delete(round % 7);
+            assert!(level.check().is_none(), "round {round}");
+        }
+    }
+
+    #[cfg(feature = "skiplist-memtable")]
+    #[test]
+    fn concurrent_shared_inserts_never_undercount() {
+        let shared = empty_level();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let shared = &shared;
+                s.spawn(move || {
+                    // overlapping keys, so writers race on both new keys and overwrites
+                    for key in 0..2000 {
+                        let value = (key % 3 != t % 3).then_some(key + t);
+                        assert!(shared.insert_shared(key, value));
+                    }
+                });
+            }
+        });
+
+        let mut sequential = empty_level();
+        for (key, value) in shared.entries() {
+            match value {
+                Some(value) => sequential.insert(key, value),
+                None => sequential.delete(key),
+            }
+        }
+        assert_eq!(shared.len(), sequential.len());
+        assert!(shared.size_bytes() >= sequential.size_bytes());
+    }
+
+    #[cfg(feature = "skiplist-memtable")]
+    #[test]
+    fn shared_overwrites_charge_what_they_change() {
+        let mut level = empty_level();
+        for round in 0..10_000 {
+            level.insert_shared(round % 10, Some(round));
+        }
+        assert_eq!(level.size_bytes(), flushed_size(&level));
+
+        // tombstones over puts are charged nothing, until the level looks full
+        let mut keys = 0;
+        while level.size_bytes() < MEM_CAPACITY_BYTES {
+            level.insert_shared(keys, Some(keys));
+            keys += 1;
+        }
+        for key in 0..keys {
+            level.insert_shared(key, None);
+        }
+        assert!(level.size_bytes() >= MEM_CAPACITY_BYTES);
+        assert!(!level.is_full());
+        assert_eq!(level.size_bytes(), flushed_size(&level));
+    }
+
+    /// Puts per second from `writers` threads through `put`, each on keys of its own in a
+    /// scattered order, as many in all as a level holds
+    #[cfg(feature = "skiplist-memtable")]
+    fn puts_per_second(writers: i32, put: impl Fn(i32) + Sync) -> f64 {
+        let per_writer = 400_000 / writers;
+        let started = std::time::Instant::now();
+        std::thread::scope(|s| {
+            for w in 0..writers {
+                let put = &put;
+                s.spawn(move || {
+                    for i in 0..per_writer {
+                        put((i * writers + w).wrapping_mul(0x9E37_79B1u32 as i32));
+                    }
+                });
+            }
+        });
+        f64::from(per_writer * writers) / started.elapsed().as_secs_f64()
+    }
+
+    #[cfg(feature = "skiplist-memtable")]
+    #[test]
+    #[ignore = "benchmark, run with --release --features skiplist-memtable -- --ignored --nocapture"]
+    fn shared_inserts_against_a_locked_btree_map() {
+        use std::{collections::BTreeMap, sync::RwLock};
+
+        let rates = [1, 4].map(|writers| {
+            let shared = empty_level();
+            let skip_list = puts_per_second(writers, |key| {
+                shared.insert_shared(key, Some(key));
+            });
+            // what the memory level is without the feature: a BTreeMap under the write lock
+            let locked = RwLock::new(BTreeMap::new());
+            let btree_map = puts_per_second(writers, |key| {
+                locked.write().unwrap().insert(key, Some(key));
+            });
+            println!(
+                "{writers} writers: {skip_list:.0} puts/s into the skip list, {btree_map:.0} into the locked BTreeMap"
+            );
+            (skip_list, btree_map)
+        });
+
+        // a BTreeMap insert is cheaper than a skip list's, so one writer at a time favors it.
+        // Only with a core for each writer can the skip list's add up, with fewer there's nothing
+        // to compare.
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if cores >= 4 {
+            let [(skip_list_1, btree_map_1), (skip_list_4, btree_map_4)] = rates;
+            assert!(skip_list_4 / skip_list_1 > btree_map_4 / btree_map_1);
+        }
+    }
+
+    #[cfg(feature = "skiplist-memtable")]
+    #[test]
+    fn deep_size_covers_every_entry() {
+        use deepsize::DeepSizeOf;
+
+        let level = empty_level();
+        for key in 0..1000 {
+            level.insert_shared(key, Some(key));
+        }
+        let entry = std::mem::size_of::<(i32, Option<i32>)>();
+        assert!(level.deep_size_of() >= level.len() * entry);
     }
 }
@@ -9,6 +9,10 @@ where
 {
     iter1: Peekable<I1>,
     iter2: Peekable<I2>,
+    // end of the furthest range delete seen in iter1, older puts/deletes in iter2 up to here are dropped
+    deleted_until: Option<i32>,
+    // same for iter2, merges in iter1 up to here apply onto a deleted value
+    older_deleted_until: Option<i32>,
 }
 
 impl<I1, I2> Iterator for MergeCommands<I1, I2>
@@ -19,22 +23,60 @@ where
     type Item = Command;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match (self.iter1.peek(), self.iter2.peek()) {
-            (Some(&v1), Some(&v2)) => match v1.key().cmp(&v2.key()) {
-                Ordering::Less => self.iter1.next(),
-                Ordering::Greater => self.iter2.next(),
-                Ordering::Equal => {
-                    self.iter2.next(); // ignore older command in iter2
-                    self.iter1.next()
+        loop {
+            let (take_first, older) = match (self.iter1.peek(), self.iter2.peek()) {
+                (Some(&v1), Some(&v2)) => match v1.key().cmp(&v2.key()) {
+                    Ordering::Less => (true, None),
+                    Ordering::Greater => (false, None),
+                    // older range deletes may still cover keys further down, keep them
+                    Ordering::Equal if matches!(v2, Command::DeleteRange(..)) => (false, None),
+                    // older command in iter2 is shadowed
+                    Ordering::Equal => (true, self.iter2.next()),
+                },
+                (Some(_), None) => (true, None),
+                (None, Some(_)) => (false, None),
+                (None, None) => return None,
+            };
+
+            if take_first {
+                let command = self.iter1.next()?;
+                return Some(match command {
+                    Command::DeleteRange(_, end) => {
+                        self.deleted_until = Some(self.deleted_until.map_or(end, |d| d.max(end)));
+                        command
+                    }
+                    Command::Merge(key, delta) => match older {
+                        Some(Command::Put(_, val)) => Command::Put(key, val.wrapping_add(delta)),
+                        Some(Command::Merge(_, older_delta)) => {
+                            Command::Merge(key, older_delta.wrapping_add(delta))
+                        }
+                        // merging onto a deleted value
+                        Some(_) => Command::Put(key, delta),
+                        None if self.older_deleted_until.is_some_and(|end| key <= end) => {
+                            Command::Put(key, delta)
+                        }
+                        None => command,
+                    },
+                    _ => command,
+                });
+            }
+
+            let command = self.iter2.next()?;
+            match command {
+                Command::DeleteRange(_, end) => {
+                    self.older_deleted_until =
+                        Some(self.older_deleted_until.map_or(end, |d| d.max(end)));
+                    return Some(command);
                 }
-            },
-            (Some(_), None) => self.iter1.next(),
-            (None, Some(_)) => self.iter2.next(),
-            (None, None) => None,
+                _ if self.deleted_until.is_some_and(|end| command.key() <= end) => continue,
+                _ => return Some(command),
+            }
         }
     }
 }
 
+/// `iter1` has to be the newer stream: on equal keys its command wins and iter2's is dropped, or
+/// folded in if iter1's is a merge
 pub fn merge_sorted_commands<I1, I2>(iter1: I1, iter2: I2) -> MergeCommands<I1, I2>
 where
     I1: Iterator<Item = Command>,
@@ -43,5 +85,79 @@ where
     MergeCommands {
         iter1: iter1.peekable(),
         iter2: iter2.peekable(),
+        deleted_until: None,
+        older_deleted_until: None,
+    }
+}
+
+/// Keeps range deletes in a sorted stream from overlapping anything else:
+/// overlapping/touching range deletes are unioned, and a range delete is split around
+/// any put/delete/merge inside it. A put/delete/merge inside a range delete of the same stream
+/// is always the newer of the two (older ones were dropped by the memtable or `MergeCommands`).
+///
+/// The result is a stream where each command's `key()..=end_key()` is disjoint and increasing,
+/// which keeps table and block key ranges from overlapping.
+pub struct SplitRangeDeletes<I: Iterator<Item = Command>> {
+    iter: I,
+    pending: Option<(i32, i32)>,
+    queued: Option<Command>,
+}
+
+impl<I: Iterator<Item = Command>> Iterator for SplitRangeDeletes<I> {
+    type Item = Command;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(command) = self.queued.take() {
+            return Some(command);
+        }
+
+        loop {
+            let Some(command) = self.iter.next() else {
+                return self
+                    .pending
+                    .take()
+                    .map(|(start, end)| Command::DeleteRange(start, end));
+            };
+
+            let Some((start, end)) = self.pending else {
+                if let Command::DeleteRange(s, e) = command {
+                    self.pending = Some((s, e));
+                    continue;
+                }
+                return Some(command);
+            };
+
+            match command {
+                Command::DeleteRange(s, e) if s as i64 <= end as i64 + 1 => {
+                    self.pending = Some((start, end.max(e)));
+                }
+                Command::DeleteRange(s, e) => {
+                    self.pending = Some((s, e));
+                    return Some(Command::DeleteRange(start, end));
+                }
+                _ if command.key() > end => {
+                    self.pending = None;
+                    self.queued = Some(command);
+                    return Some(Command::DeleteRange(start, end));
+                }
+                _ => {
+                    let key = command.key();
+                    self.pending = (key < end).then(|| (key + 1, end));
+                    if key > start {
+                        self.queued = Some(command);
+                        return Some(Command::DeleteRange(start, key - 1));
+                    }
+                    return Some(command);
+                }
+            }
+        }
+    }
+}
+
+pub fn split_range_deletes<I: Iterator<Item = Command>>(iter: I) -> SplitRangeDeletes<I> {
+    SplitRangeDeletes {
+        iter,
+        pending: None,
+        queued: None,
     }
 }
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+
+use deepsize::DeepSizeOf;
+
+/// Sorted set of disjoint deleted key ranges (inclusive on both ends).
+/// Inserting a range that overlaps or touches existing ones merges them together.
+#[derive(Clone, Debug, Default, DeepSizeOf)]
+pub struct RangeDeletes {
+    ranges: BTreeMap<i32, i32>, // start -> end
+}
+
+impl RangeDeletes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, mut start: i32, mut end: i32) {
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..start).next_back() {
+            if prev_end as i64 + 1 >= start as i64 {
+                start = prev_start;
+                end = end.max(prev_end);
+                self.ranges.remove(&prev_start);
+            }
+        }
+
+        let absorbed: Vec<(i32, i32)> = self
+            .ranges
+            .range(start..=end.saturating_add(1))
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in absorbed {
+            end = end.max(e);
+            self.ranges.remove(&s);
+        }
+
+        self.ranges.insert(start, end);
+    }
+
+    pub fn covers(&self, key: i32) -> bool {
+        self.ranges
+            .range(..=key)
+            .next_back()
+            .is_some_and(|(_, &end)| key <= end)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        self.ranges.iter().map(|(&start, &end)| (start, end))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+
+/// Where table files live. Paths keep the same layout (data dir, level dirs, file names) no matter
+/// the backend, so the levels don't need to know which one they're on.
+pub trait Storage: Debug + Send + Sync {
+    /// Creates a new empty file, failing if one is already there
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + Sync>>;
+    /// Opens an existing file for writing at `len`, cutting off anything past it first
+    fn reopen(&self, path: &Path, len: u64) -> io::Result<Box<dyn Write + Send + Sync>>;
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadAt>>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    /// Paths of the files directly inside `dir`
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn len(&self, path: &Path) -> io::Result<u64>;
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()>;
+    /// Makes everything written to the file durable
+    fn sync(&self, path: &Path) -> io::Result<()>;
+}
+
+/// Positional reads, so a view never has to track a cursor
+pub trait ReadAt: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+}
+
+/// Files on disk
+#[derive(Debug, Default)]
+pub struct FsStorage;
+
+impl ReadAt for File {
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.seek_read(buf, offset)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+}
+
+impl Storage for FsStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + Sync>> {
+        Ok(Box::new(File::create_new(path)?))
+    }
+
+    fn reopen(&self, path: &Path, len: u64) -> io::Result<Box<dyn Write + Send + Sync>> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        Ok(Box::new(file))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadAt>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::remove_dir_all(dir)
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+}
+
+struct MemFile {
+    data: Arc<RwLock<Vec<u8>>>,
+    modified: SystemTime,
+}
+
+/// Files kept in RAM, for ephemeral databases. Directories are implied by the paths of the files
+/// in them, and are gone once they're empty.
+#[derive(Default)]
+pub struct MemStorage {
+    files: Mutex<HashMap<PathBuf, MemFile>>,
+}
+
+impl Debug for MemStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // tables hold on to their storage, printing every file's bytes along with them won't help
+        let file_count = self.files.lock().unwrap().len();
+        write!(f, "MemStorage {{ {file_count} files }}")
+    }
+}
+
+struct MemWriter(Arc<RwLock<Vec<u8>>>);
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Holds on to the contents, so like on disk an opened file stays readable after being removed
+struct MemReader(Arc<RwLock<Vec<u8>>>);
+
+impl ReadAt for MemReader {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.0.read().unwrap();
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl Storage for MemStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + Sync>> {
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+
+        let data = Arc::new(RwLock::new(Vec::new()));
+        files.insert(
+            path.to_owned(),
+            MemFile {
+                data: data.clone(),
+                modified: SystemTime::now(),
+            },
+        );
+        Ok(Box::new(MemWriter(data)))
+    }
+
+    fn reopen(&self, path: &Path, len: u64) -> io::Result<Box<dyn Write + Send + Sync>> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.get_mut(path).ok_or_else(|| not_found(path))?;
+        file.data.write().unwrap().truncate(len as usize);
+        file.modified = SystemTime::now();
+        Ok(Box::new(MemWriter(file.data.clone())))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadAt>> {
+        let files = self.files.lock().unwrap();
+        let file = files.get(path).ok_or_else(|| not_found(path))?;
+        Ok(Box::new(MemReader(file.data.clone())))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        let files = self.files.lock().unwrap();
+        let file = files.get(path).ok_or_else(|| not_found(path))?;
+        let len = file.data.read().unwrap().len();
+        Ok(len as u64)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        let files = self.files.lock().unwrap();
+        files
+            .get(path)
+            .map(|file| file.modified)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files.retain(|path, _| !path.starts_with(dir));
+        Ok(())
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        // nothing outlives the process anyway
+        let files = self.files.lock().unwrap();
+        files.get(path).map(|_| ()).ok_or_else(|| not_found(path))
+    }
+}
+
+/// Where a `FaultyStorage` goes wrong, counting every create, reopen, write, rename, remove and sync
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultPlan {
+    pub fail_at_op: Option<u64>, // that one op returns an error, the ones after go through
+    pub crash_at_op: Option<u64>, // that op and every one after is dropped, like a power cut
+}
+
+#[cfg(feature = "fault-injection")]
+#[derive(Debug)]
+struct FaultState {
+    plan: FaultPlan,
+    ops: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(feature = "fault-injection")]
+enum Fault {
+    None,
+    Fail,
+    Crashed,
+}
+
+#[cfg(feature = "fault-injection")]
+impl FaultState {
+    fn next_op(&self) -> Fault {
+        let op = 1 + self.ops.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if self.plan.crash_at_op.is_some_and(|crash_at| op >= crash_at) {
+            Fault::Crashed
+        } else if self.plan.fail_at_op == Some(op) {
+            Fault::Fail
+        } else {
+            Fault::None
+        }
+    }
+
+    /// Runs `op` unless the plan says this one fails or the storage has crashed
+    fn apply<T: Default>(&self, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        match self.next_op() {
+            Fault::None => op(),
+            Fault::Fail => Err(io::Error::other("injected fault")),
+            Fault::Crashed => Ok(T::default()),
+        }
+    }
+}
+
+/// Wraps another storage to fail or stop writing at a chosen op, for checking that errors
+/// propagate and that whatever made it out before a crash can be recovered
+#[cfg(feature = "fault-injection")]
+#[derive(Debug)]
+pub struct FaultyStorage {
+    inner: Arc<dyn Storage>,
+    state: Arc<FaultState>,
+}
+
+#[cfg(feature = "fault-injection")]
+impl FaultyStorage {
+    pub fn new(inner: Arc<dyn Storage>, plan: FaultPlan) -> Self {
+        Self {
+            inner,
+            state: Arc::new(FaultState {
+                plan,
+                ops: Default::default(),
+            }),
+        }
+    }
+
+    /// Ops counted so far, so a plan can aim at one of them
+    pub fn ops(&self) -> u64 {
+        self.state.ops.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+struct FaultyWriter {
+    inner: Box<dyn Write + Send + Sync>,
+    state: Arc<FaultState>,
+}
+
+#[cfg(feature = "fault-injection")]
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.state.next_op() {
+            Fault::None => self.inner.write(buf),
+            Fault::Fail => Err(io::Error::other("injected fault")),
+            // claims the bytes were written
+            Fault::Crashed => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+impl Storage for FaultyStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + Sync>> {
+        let inner = match self.state.next_op() {
+            Fault::None => self.inner.create(path)?,
+            Fault::Fail => return Err(io::Error::other("injected fault")),
+            Fault::Crashed => Box::new(io::sink()),
+        };
+        Ok(Box::new(FaultyWriter {
+            inner,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn reopen(&self, path: &Path, len: u64) -> io::Result<Box<dyn Write + Send + Sync>> {
+        let inner = match self.state.next_op() {
+            Fault::None => self.inner.reopen(path, len)?,
+            Fault::Fail => return Err(io::Error::other("injected fault")),
+            Fault::Crashed => Box::new(io::sink()),
+        };
+        Ok(Box::new(FaultyWriter {
+            inner,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadAt>> {
+        self.inner.open(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.state.apply(|| self.inner.rename(from, to))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.state.apply(|| self.inner.remove(path))
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(dir)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        self.inner.len(path)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        self.inner.modified(path)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.inner.remove_dir_all(dir)
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        self.state.apply(|| self.inner.sync(path))
+    }
+}
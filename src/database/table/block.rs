@@ -1,84 +1,273 @@
+// Block encoding. Like the rest of the protocol and file format, every integer is big-endian
+// (`BytesMut::put_i32`/`Buf::get_i32`), no matter the host.
+
 use bytes::{Buf, BufMut, BytesMut};
-use std::io::Cursor;
+use std::{fmt::Display, io::Cursor};
+
+use crate::config::{BLOCK_HEADER_BYTES, BLOCK_SIZE_BYTES};
+
+// Serialized size of each command: a tag byte followed by its arguments
+pub const PUT_BYTES: usize = 9;
+pub const DELETE_BYTES: usize = 5;
+pub const RANGE_DELETE_BYTES: usize = 9;
+pub const MERGE_BYTES: usize = 9;
+// Values are fixed size, so every command fits in an empty block and nothing ever spans two
+pub const MAX_COMMAND_BYTES: usize = 9;
+const _: () = assert!(BLOCK_HEADER_BYTES + MAX_COMMAND_BYTES <= BLOCK_SIZE_BYTES);
+// Most commands a block can hold, all of them deletes. A bigger count in a header is corruption.
+pub const MAX_BLOCK_COMMANDS: usize = (BLOCK_SIZE_BYTES - BLOCK_HEADER_BYTES) / DELETE_BYTES;
+
+/// A byte where a command should start that isn't any command's tag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BadTag(pub u8);
 
-use crate::config::BLOCK_SIZE_BYTES;
+/// Why a block read back from a file can't be trusted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    BadTag { offset: usize, tag: u8 },
+    // the header's count against the commands found before the padding
+    BadCount { count: usize, found: usize },
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadTag { offset, tag } => write!(f, "invalid tag {tag:#04x} at offset {offset}"),
+            Self::BadCount { count, found } => {
+                write!(f, "header counts {count} commands but block holds {found}")
+            }
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug)]
 pub enum Command {
     Delete(i32),
     Put(i32, i32),
+    DeleteRange(i32, i32), // inclusive on both ends
+    Merge(i32, i32),       // delta added onto whatever older value the key has
 }
 
 impl Command {
     pub fn key(&self) -> i32 {
         match self {
-            &Self::Delete(key) => key,
-            &Self::Put(key, ..) => key,
+            Self::Delete(key) => *key,
+            Self::Put(key, ..) => *key,
+            Self::DeleteRange(start, ..) => *start,
+            Self::Merge(key, ..) => *key,
+        }
+    }
+
+    /// Last key the command applies to
+    pub fn end_key(&self) -> i32 {
+        match self {
+            Self::DeleteRange(_, end) => *end,
+            _ => self.key(),
+        }
+    }
+
+    /// Bytes the command takes up in a block
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Self::Delete(..) => DELETE_BYTES,
+            Self::Put(..) => PUT_BYTES,
+            Self::DeleteRange(..) => RANGE_DELETE_BYTES,
+            Self::Merge(..) => MERGE_BYTES,
         }
     }
 
     pub fn value(&self) -> Option<i32> {
         match self {
-            Self::Delete(_) => None,
+            Self::Delete(_) | Self::DeleteRange(..) | Self::Merge(..) => None,
             &Self::Put(_, val) => Some(val),
         }
     }
+
+    /// Appends the tag byte and arguments
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match *self {
+            Command::Delete(key) => {
+                buf.put_u8(1);
+                buf.put_i32(key);
+            }
+            Command::Put(key, val) => {
+                buf.put_u8(0);
+                buf.put_i32(key);
+                buf.put_i32(val);
+            }
+            Command::DeleteRange(start, end) => {
+                buf.put_u8(2);
+                buf.put_i32(start);
+                buf.put_i32(end);
+            }
+            Command::Merge(key, delta) => {
+                buf.put_u8(3);
+                buf.put_i32(key);
+                buf.put_i32(delta);
+            }
+        }
+    }
+
+    /// Reads the command at the cursor. None at padding, or if `buf` ends before the command does.
+    pub fn decode(buf: &mut Cursor<&[u8]>) -> Result<Option<Command>, BadTag> {
+        if !buf.has_remaining() {
+            return Ok(None);
+        }
+        let len = match buf.chunk()[0] {
+            0 => PUT_BYTES,
+            1 => DELETE_BYTES,
+            2 => RANGE_DELETE_BYTES,
+            3 => MERGE_BYTES,
+            0xFF => {
+                // padding. Blocks stop at the count in their header before ever getting here.
+                return Ok(None);
+            }
+            tag => return Err(BadTag(tag)),
+        };
+        if buf.remaining() < len {
+            return Ok(None);
+        }
+
+        let command = match buf.get_u8() {
+            0 => {
+                let key = buf.get_i32();
+                let val = buf.get_i32();
+                Command::Put(key, val)
+            }
+            1 => {
+                let key = buf.get_i32();
+                Command::Delete(key)
+            }
+            2 => {
+                let start = buf.get_i32();
+                let end = buf.get_i32();
+                Command::DeleteRange(start, end)
+            }
+            _ => {
+                let key = buf.get_i32();
+                let delta = buf.get_i32();
+                Command::Merge(key, delta)
+            }
+        };
+        Ok(Some(command))
+    }
+}
+
+/// "PUT <key> <value>", "DELETE <key>", "DELETE_RANGE <min> <max>" or "MERGE <key> <delta>"
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Command::Put(key, val) => write!(f, "PUT {key} {val}"),
+            Command::Delete(key) => write!(f, "DELETE {key}"),
+            Command::DeleteRange(min_key, max_key) => write!(f, "DELETE_RANGE {min_key} {max_key}"),
+            Command::Merge(key, delta) => write!(f, "MERGE {key} {delta}"),
+        }
+    }
+}
+
+/// Commands packed back to back in `bytes`, up to the last whole one. A bad tag ends them too,
+/// `FSCK` is what reports it.
+pub fn decode_commands(bytes: &[u8]) -> impl Iterator<Item = Command> + '_ {
+    let mut commands = Cursor::new(bytes);
+    std::iter::from_fn(move || Command::decode(&mut commands).ok().flatten())
 }
 
 /// Block Builder
 pub struct BlockMut {
-    pub commands: BytesMut,
-    pub keys: Vec<i32>,
+    pub commands: BytesMut, // the block as it goes in the file, header included
+    pub keys: Vec<i32>,     // point keys only
+    pub range_deletes: Vec<(i32, i32)>, // start/end of each DeleteRange
+}
+
+impl Default for BlockMut {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockMut {
     pub fn new() -> Self {
+        let mut commands = BytesMut::with_capacity(BLOCK_SIZE_BYTES);
+        commands.put_u16(0);
         Self {
-            commands: BytesMut::with_capacity(BLOCK_SIZE_BYTES),
+            commands,
             keys: Vec::with_capacity(BLOCK_SIZE_BYTES >> 2),
+            range_deletes: Vec::new(),
         }
     }
 
+    /// Number of commands in the block
+    pub fn command_count(&self) -> usize {
+        self.keys.len() + self.range_deletes.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Command> + '_ {
+        decode_commands(&self.commands[BLOCK_HEADER_BYTES..]).take(self.command_count())
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.keys.is_empty()
+        self.keys.is_empty() && self.range_deletes.is_empty()
     }
 
     pub fn clear(&mut self) {
         self.commands.clear();
+        self.commands.put_u16(0);
         self.keys.clear();
+        self.range_deletes.clear();
     }
 
-    /// Returns whether the new command was able to fit inside the block
-    pub fn push_command(&mut self, command: Command) -> bool {
-        let bytes_to_write = match command {
-            Command::Delete(..) => 5,
-            Command::Put(..) => 9,
-        };
+    pub fn min_key(&self) -> Option<i32> {
+        let first_range_delete = self.range_deletes.first().map(|&(start, _)| start);
+        self.keys
+            .first()
+            .copied()
+            .into_iter()
+            .chain(first_range_delete)
+            .min()
+    }
 
-        if self.commands.len() + bytes_to_write > self.commands.capacity() {
-            let remaining_space = self.commands.capacity() - self.commands.len();
+    pub fn max_key(&self) -> Option<i32> {
+        let last_range_delete = self.range_deletes.last().map(|&(_, end)| end);
+        self.keys
+            .last()
+            .copied()
+            .into_iter()
+            .chain(last_range_delete)
+            .max()
+    }
 
-            // Pad the remaining space with 0xFF
-            for _ in 0..remaining_space {
-                self.commands.put_u8(0xFF);
-            }
+    /// Fills the remaining space with 0xFF, so a block can be followed by another one in the file
+    pub fn pad(&mut self) {
+        let remaining_space = BLOCK_SIZE_BYTES - self.commands.len();
+        for _ in 0..remaining_space {
+            self.commands.put_u8(0xFF);
+        }
+    }
+
+    /// Returns whether the new command was able to fit inside the block. Always true for an empty
+    /// block.
+    pub fn push_command(&mut self, command: Command) -> bool {
+        let bytes_to_write = command.encoded_len();
+        // gets binary search the blocks, a repeated or out of order key would hide the other one
+        debug_assert!(
+            self.max_key().is_none_or(|max| command.key() > max),
+            "{command:?} pushed after key {:?}",
+            self.max_key()
+        );
 
+        // not the buffer's capacity, which may come out bigger than asked for
+        if self.commands.len() + bytes_to_write > BLOCK_SIZE_BYTES {
+            self.pad();
             return false;
         }
 
+        command.encode(&mut self.commands);
         match command {
-            Command::Delete(key) => {
-                self.commands.put_u8(1);
-                self.commands.put_i32(key);
-                self.keys.push(key);
-            }
-            Command::Put(key, val) => {
-                self.commands.put_u8(0);
-                self.commands.put_i32(key);
-                self.commands.put_i32(val);
-                self.keys.push(key);
-            }
+            Command::DeleteRange(start, end) => self.range_deletes.push((start, end)),
+            _ => self.keys.push(command.key()),
         }
+        let count = self.command_count() as u16;
+        self.commands[..BLOCK_HEADER_BYTES].copy_from_slice(&count.to_be_bytes());
         true
     }
 }
@@ -87,51 +276,201 @@ pub struct BlockView {
     buf: [u8; BLOCK_SIZE_BYTES],
 }
 
+impl Default for BlockView {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BlockView {
     pub fn new() -> Self {
-        Self {
-            buf: [0xFF; BLOCK_SIZE_BYTES],
-        }
+        let mut buf = [0xFF; BLOCK_SIZE_BYTES];
+        // nothing read in yet
+        buf[..BLOCK_HEADER_BYTES].fill(0);
+        Self { buf }
+    }
+
+    /// Number of commands in the block, going by its header
+    pub fn command_count(&self) -> usize {
+        u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize
     }
 
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
         &mut self.buf
     }
 
-    pub fn iter(&self) -> BlockViewIter {
+    /// The block as it sits in the file, padding included
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn iter(&self) -> BlockViewIter<'_> {
         BlockViewIter {
-            commands: Cursor::new(&self.buf[..]),
+            block: self,
+            offset: BLOCK_HEADER_BYTES,
+            remaining: self.command_count(),
+        }
+    }
+
+    /// Decodes the command starting at `offset`, along with the offset of the one after it. None
+    /// at padding and at a bad tag alike, reads treat a corrupt block as ending there.
+    pub fn command_at(&self, offset: usize) -> Option<(Command, usize)> {
+        let mut commands = Cursor::new(self.buf.get(offset..)?);
+        let command = Command::decode(&mut commands).ok()??;
+        Some((command, offset + commands.position() as usize))
+    }
+
+    /// Every command in the block, checking each tag and the header's count against them instead
+    /// of stopping quietly the way `iter` does
+    pub fn try_commands(&self) -> Result<Vec<Command>, BlockError> {
+        let count = self.command_count();
+        let mut commands = Vec::with_capacity(count.min(MAX_BLOCK_COMMANDS));
+        let mut cursor = Cursor::new(&self.buf[BLOCK_HEADER_BYTES..]);
+        loop {
+            let offset = BLOCK_HEADER_BYTES + cursor.position() as usize;
+            match Command::decode(&mut cursor) {
+                Ok(Some(command)) => commands.push(command),
+                Ok(None) => break,
+                Err(BadTag(tag)) => return Err(BlockError::BadTag { offset, tag }),
+            }
+        }
+        if count > MAX_BLOCK_COMMANDS || count != commands.len() {
+            return Err(BlockError::BadCount {
+                count,
+                found: commands.len(),
+            });
         }
+        Ok(commands)
     }
 }
 
 pub struct BlockViewIter<'a> {
-    commands: Cursor<&'a [u8]>,
+    block: &'a BlockView,
+    offset: usize,
+    remaining: usize, // commands left, the header says where they end rather than the padding
 }
 
 impl<'a> Iterator for BlockViewIter<'a> {
     type Item = Command;
 
     fn next(&mut self) -> Option<Command> {
-        if !self.commands.has_remaining() {
+        if self.remaining == 0 {
             return None;
         }
+        let (command, next_offset) = self.block.command_at(self.offset)?;
+        self.offset = next_offset;
+        self.remaining -= 1;
+        Some(command)
+    }
+}
 
-        match self.commands.get_u8() {
-            0 => {
-                let key = self.commands.get_i32();
-                let val = self.commands.get_i32();
-                Some(Command::Put(key, val))
-            }
-            1 => {
-                let key = self.commands.get_i32();
-                Some(Command::Delete(key))
-            }
-            0xFF => {
-                // Fin
-                None
-            }
-            _ => panic!("INVALID TAG!"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The block as a view reads it back out of a file
+    fn read_back(block: &BlockMut) -> BlockView {
+        let mut view = BlockView::new();
+        view.as_mut_slice()[..block.commands.len()].copy_from_slice(&block.commands);
+        view
+    }
+
+    #[test]
+    fn commands_ending_in_0xff_bytes_round_trip() {
+        // -1 is all 0xFF bytes, the same as padding
+        let commands = [
+            Command::Put(-257, -1),
+            Command::Delete(-1),
+            Command::Merge(0, -1),
+            Command::DeleteRange(1, 255),
+            Command::Put(i32::MAX, -1),
+        ];
+        let mut block = BlockMut::new();
+        for command in commands {
+            assert!(block.push_command(command));
+        }
+        block.pad();
+
+        let view = read_back(&block);
+        assert_eq!(view.command_count(), commands.len());
+        let read: Vec<String> = view.iter().map(|c| c.to_string()).collect();
+        let written: Vec<String> = commands.iter().map(|c| c.to_string()).collect();
+        assert_eq!(read, written);
+        assert_eq!(view.try_commands().unwrap().len(), commands.len());
+    }
+
+    #[test]
+    fn command_ending_at_the_block_end_fits() {
+        let mut block = BlockMut::new();
+        // 9 + 817 * 5 bytes fill the block exactly
+        assert!(block.push_command(Command::Put(-1, 0)));
+        for key in 0..817 {
+            assert!(block.push_command(Command::Delete(key)));
         }
+        assert_eq!(block.commands.len(), BLOCK_SIZE_BYTES);
+
+        // one byte over is one too many, and the full block gets no padding
+        assert!(!block.push_command(Command::Delete(817)));
+        assert_eq!(block.commands.len(), BLOCK_SIZE_BYTES);
+        assert_eq!(read_back(&block).try_commands().unwrap().len(), 818);
+
+        // the biggest command fits a block with room for it and no more
+        block.clear();
+        let pairs = ((BLOCK_SIZE_BYTES - BLOCK_HEADER_BYTES) / PUT_BYTES) as i32;
+        for key in 0..pairs {
+            assert!(block.push_command(Command::Put(key, key)));
+        }
+        let left = BLOCK_SIZE_BYTES - block.commands.len();
+        assert!(left < MAX_COMMAND_BYTES);
+        assert!(!block.push_command(Command::Put(pairs, 0)));
+
+        block.clear();
+        assert!(block.push_command(Command::Put(pairs, 0)));
+    }
+
+    #[test]
+    fn full_block_of_deletes_fits_the_count() {
+        let mut block = BlockMut::new();
+        let mut key = 0;
+        while block.push_command(Command::Delete(key)) {
+            key += 1;
+        }
+        assert_eq!(block.command_count(), MAX_BLOCK_COMMANDS);
+        assert_eq!(
+            read_back(&block).try_commands().unwrap().len(),
+            MAX_BLOCK_COMMANDS
+        );
+    }
+
+    #[test]
+    fn count_past_what_a_block_holds_is_corruption() {
+        let mut block = BlockMut::new();
+        block.push_command(Command::Put(1, 1));
+        block.pad();
+        let mut view = read_back(&block);
+
+        let count = MAX_BLOCK_COMMANDS as u16 + 1;
+        view.as_mut_slice()[..BLOCK_HEADER_BYTES].copy_from_slice(&count.to_be_bytes());
+        assert_eq!(
+            view.try_commands().unwrap_err(),
+            BlockError::BadCount {
+                count: count as usize,
+                found: 1
+            }
+        );
+        // reads still stop at the padding
+        assert_eq!(view.iter().count(), 1);
+
+        view.as_mut_slice()[..BLOCK_HEADER_BYTES].copy_from_slice(&0u16.to_be_bytes());
+        assert!(view.try_commands().is_err());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "pushed after key Some(5)")]
+    fn repeated_key_in_a_block_is_caught() {
+        let mut block = BlockMut::new();
+        block.push_command(Command::Put(5, 1));
+        block.push_command(Command::Put(5, 2));
     }
 }
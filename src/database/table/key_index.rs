@@ -0,0 +1,69 @@
+use std::ops::Range;
+
+use crate::config::BLOCK_HEADER_BYTES;
+
+use super::block::Command;
+
+/// Finer fence pointers within each block: the key and byte offset of every `every`th command,
+/// starting with the first. A get reads and scans only the stretch between the two samples around
+/// its key instead of the whole block.
+#[derive(Clone, Debug)]
+pub struct KeyIndex {
+    every: usize,
+    blocks: Vec<Vec<(i32, u16)>>, // samples of each block, in file order
+    ends: Vec<u16>,               // where the commands of each block end, padding follows
+    offset: usize,                // where the next command pushed starts in the last block
+    count: usize,                 // commands pushed into the last block so far
+}
+
+impl KeyIndex {
+    pub fn new(every: usize) -> Self {
+        Self {
+            every: every.max(1),
+            blocks: vec![],
+            ends: vec![],
+            offset: 0,
+            count: 0,
+        }
+    }
+
+    /// Takes the commands of each block in the order they're laid out in it, commands go back to
+    /// back from the block's header so their offsets add up. A `block` past the last starts a new
+    /// one.
+    pub fn push(&mut self, block: usize, command: Command) {
+        if block >= self.blocks.len() {
+            self.blocks.push(vec![]);
+            self.ends.push(0);
+            self.offset = BLOCK_HEADER_BYTES;
+            self.count = 0;
+        }
+        if self.count.is_multiple_of(self.every) {
+            let samples = self.blocks.last_mut().unwrap();
+            samples.push((command.key(), self.offset as u16));
+        }
+        self.offset += command.encoded_len();
+        self.count += 1;
+        *self.ends.last_mut().unwrap() = self.offset as u16;
+    }
+
+    /// Drops the samples of blocks past the first `blocks`
+    pub fn truncate(&mut self, blocks: usize) {
+        self.blocks.truncate(blocks);
+        self.ends.truncate(blocks);
+    }
+
+    /// Bytes of `block` that hold `key`, if the block has it at all. Empty when the key comes
+    /// before the block's first command.
+    pub fn span(&self, block: usize, key: i32) -> Range<usize> {
+        let samples = &self.blocks[block];
+        let after = samples.partition_point(|&(first, _)| first <= key);
+        let start = match after {
+            0 => return 0..0,
+            after => samples[after - 1].1 as usize,
+        };
+        let end = samples
+            .get(after)
+            .map_or(self.ends[block], |&(_, offset)| offset) as usize;
+        start..end
+    }
+}
@@ -1,72 +1,279 @@
-use crate::config::{BLOCK_SIZE_BYTES, BLOOM_CAPACITY, MAX_FILE_SIZE_BLOCKS};
+use crate::config::{
+    BLOCK_HEADER_BYTES, BLOCK_SIZE_BYTES, MAX_FILE_SIZE_BLOCKS, TABLE_HEADER_BYTES,
+};
 
 use super::bloom::Bloom;
+use super::check::ViolationKind;
 use super::once_done::OnceDoneTrait;
+use super::storage::{ReadAt, Storage};
 use block::*;
+use key_index::KeyIndex;
 use std::cmp::Ordering;
-use std::fmt::Debug;
-
-#[cfg(windows)]
-use std::os::windows::fs::FileExt;
-
-#[cfg(unix)]
-use std::os::unix::fs::FileExt;
+use std::fmt::{Debug, Display};
 
 use std::{
-    fs::{self, File},
-    io::Write,
+    io::{self, BufWriter, Write},
+    ops::Range,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
     time::SystemTime,
 };
 
 pub mod block;
+pub mod key_index;
+
+pub const TABLE_MAGIC: [u8; 4] = *b"LSMT";
+// Bump whenever the block encoding or file layout changes
+pub const FORMAT_VERSION: u8 = 3;
+
+// Tables are numbered in the order their builders start, so the newer of two overlapping tables
+// can be told apart without going by file times. Startup moves it past every table read back.
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+// Unlike the sequence number, unique to every table this process builds or reads back, appended
+// ones included. Never stored.
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum HeaderError {
+    Io(io::Error),
+    BadMagic,
+    UnknownVersion(u8),
+}
+
+impl Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read table header: {err}"),
+            Self::BadMagic => write!(f, "not a table file (missing header)"),
+            Self::UnknownVersion(version) => write!(
+                f,
+                "unsupported table format version {version} (this build reads version {FORMAT_VERSION})"
+            ),
+        }
+    }
+}
+
+/// Magic, format version and the table's sequence number, zero padded to a block
+fn header_block(sequence: u64) -> [u8; TABLE_HEADER_BYTES] {
+    let mut header = [0; TABLE_HEADER_BYTES];
+    header[..TABLE_MAGIC.len()].copy_from_slice(&TABLE_MAGIC);
+    header[TABLE_MAGIC.len()] = FORMAT_VERSION;
+    header[TABLE_MAGIC.len() + 1..][..8].copy_from_slice(&sequence.to_be_bytes());
+    header
+}
+
+/// Makes sure the file starts with a header this build knows how to read, returning the table's
+/// sequence number
+pub fn check_header(storage: &dyn Storage, file_path: &Path) -> Result<u64, HeaderError> {
+    let mut header = [0; TABLE_MAGIC.len() + 1 + 8];
+    let bytes_read = storage
+        .open(file_path)
+        .and_then(|file| file.read_at(&mut header, 0))
+        .map_err(HeaderError::Io)?;
+
+    if bytes_read < header.len() || header[..TABLE_MAGIC.len()] != TABLE_MAGIC {
+        return Err(HeaderError::BadMagic);
+    }
+    let sequence = u64::from_be_bytes(header[TABLE_MAGIC.len() + 1..].try_into().unwrap());
+    match header[TABLE_MAGIC.len()] {
+        FORMAT_VERSION => Ok(sequence),
+        version => Err(HeaderError::UnknownVersion(version)),
+    }
+}
+
+/// What gets built alongside the blocks of a level's tables, kept in memory, and how the blocks
+/// get written
+#[derive(Clone, Copy, Debug)]
+pub struct TableOptions {
+    pub bloom: bool,               // false for scan-only levels
+    pub key_index_every: usize,    // 0 for the per-block fence pointers alone
+    pub write_buffer_bytes: usize, // bytes of blocks gathered per file write, 0 for one by one
+}
+
+impl TableOptions {
+    fn bloom_keys(&self) -> Option<Vec<i32>> {
+        self.bloom.then(Vec::new)
+    }
+
+    fn key_index(&self) -> Option<KeyIndex> {
+        (self.key_index_every > 0).then(|| KeyIndex::new(self.key_index_every))
+    }
+}
+
+/// A block is written as soon as it's done unless there's a buffer to gather it in. Either way a
+/// block bigger than the buffer goes straight to the file.
+fn buffered(file: Box<dyn Write + Send + Sync>, bytes: usize) -> Box<dyn Write + Send + Sync> {
+    match bytes {
+        0 => file,
+        bytes => Box::new(BufWriter::with_capacity(bytes, file)),
+    }
+}
+
+/// Builders write under a timestamp until the table is built and gets its "{min_key}_{max_key}"
+/// name, a file still named that way was never finished
+pub fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()))
+}
 
 pub struct TableBuilder {
+    pub storage: Arc<dyn Storage>,
     pub directory: PathBuf,
     pub file_path: PathBuf,
-    pub file: File,
+    pub file: Box<dyn Write + Send + Sync>,
     pub min_key: Option<i32>,
     pub max_key: Option<i32>,
-    pub bloom: Bloom,
+    pub bloom: Option<Bloom>, // the appended table's, None for new tables
+    bloom_keys: Option<Vec<i32>>, // None for scan-only levels, the bloom is sized for them on build
     pub index: Vec<(i32, i32)>, // min/max key for each block in file
+    pub key_index: Option<KeyIndex>,
+    pub range_deletes: Vec<(i32, i32)>,
+    sequence: u64,
+    named_by_sequence: bool,  // the appended table's, false for new tables
+    appended_to: Option<u64>, // id of the table being extended
+    lease: Arc<FileLease>,    // the appended table's when extending one
 }
 
 impl TableBuilder {
-    pub fn new(directory: &Path) -> Self {
+    pub fn new(storage: &Arc<dyn Storage>, directory: &Path, options: TableOptions) -> Self {
         let tmp_file_name = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_nanos()
             .to_string();
 
+        // directories are made by the first table written to them, so opening a database doesn't
+        // write anything. create_dir_all succeeds if a concurrent compaction got there first.
+        storage.create_dir_all(directory).unwrap();
         let file_path = directory.join(tmp_file_name);
-        let file = File::create_new(&file_path).unwrap();
+        let mut file = buffered(
+            storage.create(&file_path).unwrap(),
+            options.write_buffer_bytes,
+        );
+        let sequence = NEXT_SEQUENCE.fetch_add(1, AtomicOrdering::Relaxed);
+        file.write_all(&header_block(sequence)).unwrap();
         Self {
+            storage: storage.clone(),
             directory: directory.to_path_buf(),
             min_key: None,
             max_key: None,
-            bloom: Bloom::new(BLOOM_CAPACITY),
+            bloom: None,
+            bloom_keys: options.bloom_keys(),
             index: Vec::with_capacity(MAX_FILE_SIZE_BLOCKS),
+            key_index: options.key_index(),
+            range_deletes: Vec::new(),
+            sequence,
+            named_by_sequence: false,
+            appended_to: None,
             file,
             file_path,
+            lease: Arc::default(),
         }
     }
 
+    /// Picks up where `table` ends, so more blocks get written to the end of its file instead of a
+    /// new one. Views on the table keep reading what they knew about, the bytes after it are only
+    /// seen through the table `build_appended` returns. The header, sequence number included, is
+    /// left as is. Only the write buffer is taken from `options`, the bloom and key index are kept
+    /// the way the table has them.
+    pub fn append_to(table: &Table, options: TableOptions) -> Self {
+        let file_path = table.file_path();
+        // anything past the table's size was left by an append that never got renamed in
+        let file = table.storage.reopen(&file_path, table.file_size).unwrap();
+        let mut file = buffered(file, options.write_buffer_bytes);
+        // the last block went in without its padding
+        let tail = table.data_size() as usize % BLOCK_SIZE_BYTES;
+        if tail > 0 {
+            file.write_all(&[0xFF; BLOCK_SIZE_BYTES][tail..]).unwrap();
+        }
+
+        Self {
+            storage: table.storage.clone(),
+            directory: table.directory.clone(),
+            min_key: Some(table.min_key),
+            max_key: Some(table.max_key),
+            bloom: table.bloom.clone(),
+            bloom_keys: table.bloom.is_some().then(Vec::new),
+            index: table.index.clone(),
+            key_index: table.key_index.clone(),
+            range_deletes: table.range_deletes.clone(),
+            sequence: table.sequence,
+            named_by_sequence: table.named_by_sequence,
+            appended_to: Some(table.id),
+            file,
+            file_path,
+            lease: table.lease.clone(),
+        }
+    }
+    /// Empty blocks are skipped, they'd have nothing to put in the index
     pub fn insert_block(&mut self, block: &BlockMut) {
-        let min = *block.keys.first().unwrap();
-        let max = *block.keys.last().unwrap();
+        let (Some(min), Some(max)) = (block.min_key(), block.max_key()) else {
+            return;
+        };
+        debug_assert!(
+            self.max_key.is_none_or(|prev| min > prev),
+            "block starting at {min} inserted after key {:?}",
+            self.max_key
+        );
 
         if self.min_key.is_none() {
             self.min_key = Some(min);
         }
         self.max_key = Some(max);
 
+        if let Some(key_index) = &mut self.key_index {
+            for command in block.iter() {
+                key_index.push(self.index.len(), command);
+            }
+        }
         self.file.write_all(&block.commands).unwrap();
         self.index.push((min, max));
 
-        for &key in block.keys.iter() {
-            self.bloom.put(key);
+        if let Some(bloom_keys) = &mut self.bloom_keys {
+            bloom_keys.extend_from_slice(&block.keys);
         }
+        self.range_deletes.extend_from_slice(&block.range_deletes);
+    }
+
+    /// Copies a block of another table over as is. `bounds` is its index entry and `range_deletes`
+    /// the source table's range deletes inside it, only point keys get decoded, for the bloom filter.
+    pub fn insert_raw_block(
+        &mut self,
+        block: &BlockView,
+        bounds: (i32, i32),
+        range_deletes: &[(i32, i32)],
+    ) {
+        debug_assert!(
+            self.max_key.is_none_or(|prev| bounds.0 > prev),
+            "block starting at {} inserted after key {:?}",
+            bounds.0,
+            self.max_key
+        );
+        if self.min_key.is_none() {
+            self.min_key = Some(bounds.0);
+        }
+        self.max_key = Some(bounds.1);
+
+        if let Some(key_index) = &mut self.key_index {
+            for command in block.iter() {
+                key_index.push(self.index.len(), command);
+            }
+        }
+        self.file.write_all(block.as_bytes()).unwrap();
+        self.index.push(bounds);
+
+        if let Some(bloom_keys) = &mut self.bloom_keys {
+            for command in block.iter() {
+                if !matches!(command, Command::DeleteRange(..)) {
+                    bloom_keys.push(command.key());
+                }
+            }
+        }
+        self.range_deletes.extend_from_slice(range_deletes);
     }
 
     pub fn is_full(&self) -> bool {
@@ -77,45 +284,124 @@ impl TableBuilder {
         self.index.is_empty()
     }
 
-    pub fn build(self) -> Table {
+    pub fn build(mut self) -> Table {
         let new_path = self.directory.join(format!(
             "{}_{}",
             self.min_key.unwrap(),
             self.max_key.unwrap()
         ));
-        fs::rename(&self.file_path, &new_path).unwrap();
+        // on disk before it gets its real name, so a table file that shows up after a crash is whole
+        self.file.flush().unwrap();
+        self.storage.sync(&self.file_path).unwrap();
+        self.storage.rename(&self.file_path, &new_path).unwrap();
+
+        let file_size = self.storage.len(&new_path).unwrap();
+        self.into_table(file_size)
+    }
+
+    /// Finishes a builder from `append_to`. The file keeps the old table's name, which the caller
+    /// renames to the returned table's once the old one is out of its level.
+    pub fn build_appended(mut self) -> Table {
+        self.file.flush().unwrap();
+        self.storage.sync(&self.file_path).unwrap();
 
-        let file_size = fs::metadata(&new_path).unwrap().len();
+        let file_size = self.storage.len(&self.file_path).unwrap();
+        self.into_table(file_size)
+    }
 
-        Table {
+    fn into_table(self, file_size: u64) -> Table {
+        let (bloom, outgrown) = match (self.bloom, &self.bloom_keys) {
+            (_, None) => (None, false),
+            (None, Some(keys)) => (Some(Bloom::from_keys(keys)), false),
+            (Some(mut bloom), Some(keys)) if bloom.fits(bloom.estimated_keys() + keys.len()) => {
+                for &key in keys {
+                    bloom.put(key);
+                }
+                (Some(bloom), false)
+            }
+            (Some(_), Some(_)) => (None, true),
+        };
+        let mut table = Table {
+            storage: self.storage,
             directory: self.directory,
             min_key: self.min_key.unwrap(),
             max_key: self.max_key.unwrap(),
             file_size,
-            bloom: self.bloom,
+            bloom,
             index: self.index,
+            key_index: self.key_index,
+            range_deletes: self.range_deletes,
+            sequence: self.sequence,
+            named_by_sequence: self.named_by_sequence,
+            id: NEXT_TABLE_ID.fetch_add(1, AtomicOrdering::Relaxed),
+            pending_keys: self.bloom_keys,
+            appended_to: self.appended_to,
+            lease: self.lease,
+        };
+        // the appended table's keys are only in its filter, a bigger one needs them read back
+        if outgrown {
+            table.set_point_keys(table.point_keys());
         }
+        table
     }
 }
 
 #[derive(Debug)]
 pub struct Table {
+    pub storage: Arc<dyn Storage>,
     pub directory: PathBuf,
-    // file name = "{min_key}_{max_key}"
+    // file name = "{min_key}_{max_key}", "{min_key}_{max_key}_{sequence}" if `named_by_sequence`
     pub min_key: i32,
     pub max_key: i32,
     pub file_size: u64,
-    pub bloom: Bloom,
+    pub bloom: Option<Bloom>, // None for scan-only levels, gets go straight to the fence pointers
     pub index: Vec<(i32, i32)>, // min/max key for each block in file
+    pub key_index: Option<KeyIndex>, // None when gets scan the whole block the index points to
+    // every DeleteRange in the file, sorted and disjoint. Kept in memory since the bloom
+    // filter only knows about point keys.
+    pub range_deletes: Vec<(i32, i32)>,
+    pub sequence: u64, // from the header, higher for tables whose builder started later
+    // runs of a tiered level can cover the same keys, so those tables' names can't be just that
+    named_by_sequence: bool,
+    id: u64,
+    // point keys the table was built or read back with, until the level it joins puts them in its
+    // filter. An appended table only has the ones it added past the end of `appended_to`.
+    pending_keys: Option<Vec<i32>>,
+    appended_to: Option<u64>,
+    lease: Arc<FileLease>, // shared with every view open on the file
+}
+
+/// Held by a table and each of its views. A file whose table was dropped from its level while views
+/// were still reading it is only removed once the last of them is done.
+#[derive(Debug, Default)]
+struct FileLease {
+    retired: Mutex<Option<(Arc<dyn Storage>, PathBuf)>>,
+}
+
+impl Drop for FileLease {
+    fn drop(&mut self) {
+        if let Some((storage, path)) = self.retired.get_mut().unwrap().take() {
+            if let Err(err) = storage.remove(&path) {
+                eprintln!("Could not remove retired table {}: {err}", path.display());
+            }
+        }
+    }
 }
 
 impl Table {
+    /// Bytes taken up by blocks, i.e. the file without its header
+    pub fn data_size(&self) -> u64 {
+        self.file_size - TABLE_HEADER_BYTES as u64
+    }
+
     pub fn view(&self) -> TableView {
-        TableView::new(self.file_path(), 0)
+        self.view_from(0)
     }
 
     pub fn view_from(&self, block_index: usize) -> TableView {
-        TableView::new(self.file_path(), block_index)
+        let mut view = TableView::new(self.storage.clone(), self.file_path(), block_index);
+        view.lease = Some(self.lease.clone());
+        view
     }
 
     pub fn commands(
@@ -123,25 +409,46 @@ impl Table {
         start_at_block: usize,
         delete_on_finish: bool,
     ) -> impl Iterator<Item = Command> {
-        self.commands_ext(start_at_block, delete_on_finish, || {})
+        self.commands_ext(start_at_block..self.index.len(), delete_on_finish, || {})
     }
 
-    pub fn commands_ext<T: Fn()>(
+    pub fn commands_ext<T: FnMut()>(
         &self,
-        start_at_block: usize,
+        blocks: Range<usize>,
         delete_on_finish: bool,
         on_block: T,
     ) -> impl Iterator<Item = Command> {
-        self.view_from(start_at_block)
-            .once_done(move |v| {
-                if delete_on_finish {
-                    v.delete_file()
-                }
-            })
-            .flat_map(move |b| {
-                on_block();
-                unsafe { b.as_ref().unwrap().iter() }
-            })
+        TableCommands::new(self.view_from(blocks.start), blocks.end, on_block).once_done(move |c| {
+            if delete_on_finish {
+                c.view.delete_file()
+            }
+        })
+    }
+
+    /// Number of blocks from the start of the table holding any key at or below `max_key`
+    pub fn blocks_until(&self, max_key: i32) -> usize {
+        self.index
+            .partition_point(|&(min_key, _)| min_key <= max_key)
+    }
+
+    /// Whether a DeleteRange in this table covers `key`. Puts/deletes in the same table never sit
+    /// inside one of its range deletes, so a covered key is deleted as far as this table goes.
+    pub fn range_deleted(&self, key: i32) -> bool {
+        let idx = self
+            .range_deletes
+            .partition_point(|&(start, _)| start <= key);
+        idx > 0 && key <= self.range_deletes[idx - 1].1
+    }
+
+    /// Range deletes lying within `min_key..=max_key`, e.g. the ones of a block going by its index
+    pub fn range_deletes_within(&self, min_key: i32, max_key: i32) -> &[(i32, i32)] {
+        let start = self
+            .range_deletes
+            .partition_point(|&(start, _)| start < min_key);
+        let end = self
+            .range_deletes
+            .partition_point(|&(start, _)| start <= max_key);
+        &self.range_deletes[start..end]
     }
 
     pub fn intersects(&self, other: &Table) -> Ordering {
@@ -159,100 +466,340 @@ impl Table {
     }
 
     pub fn file_name(&self) -> String {
-        format!("{}_{}", self.min_key, self.max_key)
+        match self.named_by_sequence {
+            true => format!("{}_{}_{}", self.min_key, self.max_key, self.sequence),
+            false => format!("{}_{}", self.min_key, self.max_key),
+        }
+    }
+
+    /// Removes the file right away unless views are still reading it. Then it's moved into the
+    /// staging directory under a name of its own, so a new table can take its name, and removed
+    /// once the last view drops. Startup clears out the staging directory in case that never happens.
+    pub fn delete_file(&self) {
+        // views are only opened under the level's lock, which whoever drops the table holds
+        if Arc::strong_count(&self.lease) == 1 {
+            self.storage.remove(&self.file_path()).unwrap();
+            return;
+        }
+
+        let staging_directory = self.directory.parent().unwrap().join("staging");
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let retired_path = staging_directory.join(format!("retired_{nanos}_{}", self.file_name()));
+
+        self.storage.create_dir_all(&staging_directory).unwrap();
+        self.storage
+            .rename(&self.file_path(), &retired_path)
+            .unwrap();
+        *self.lease.retired.lock().unwrap() = Some((self.storage.clone(), retired_path));
     }
 
     pub fn rename(&mut self, to_dir: &Path) {
+        self.rename_named_by_sequence(to_dir, self.named_by_sequence);
+    }
+
+    /// Moves the table into a tiered level as a run of its own, under a name no other run's
+    /// table can have
+    pub fn rename_as_run(&mut self, to_dir: &Path) {
+        self.rename_named_by_sequence(to_dir, true);
+    }
+
+    fn rename_named_by_sequence(&mut self, to_dir: &Path, named_by_sequence: bool) {
         let old_file_path = self.file_path();
         self.directory = to_dir.to_owned();
+        self.named_by_sequence = named_by_sequence;
         let new_file_path = self.file_path();
 
-        fs::rename(old_file_path, new_file_path).unwrap();
+        self.storage.create_dir_all(to_dir).unwrap();
+        self.storage.rename(&old_file_path, &new_file_path).unwrap();
     }
 
-    pub fn create_from_existing(file_path: &Path) -> Self {
-        let file_name = file_path.file_name().unwrap().to_str().unwrap();
-        let (min_key_str, max_key_str) = file_name
-            .split_once('_')
-            .expect("File name was tampered with...");
+    /// Rescans the file, checking it against the file name and the in-memory index
+    pub fn check(&self) -> Vec<ViolationKind> {
+        let mut violations = vec![];
+        let mut prev: Option<i32> = None;
 
-        let min_key: i32 = min_key_str.parse().expect("File name was tampered with...");
-        let max_key: i32 = max_key_str.parse().expect("File name was tampered with...");
+        let mut found_index = vec![];
+        let scanned = scan_blocks(
+            &self.storage,
+            &self.file_path(),
+            self.file_size,
+            &mut found_index,
+            |block, command| {
+                let key = command.key();
+                if let Some(prev) = prev.filter(|&p| key <= p) {
+                    violations.push(ViolationKind::OutOfOrderKey { block, prev, key });
+                }
+                if key < self.min_key || command.end_key() > self.max_key {
+                    violations.push(ViolationKind::KeyOutsideTableRange { block, key });
+                }
+                prev = Some(command.end_key());
+            },
+        );
+        // nothing past a corrupt block can be read, so there's no index to compare
+        if let Err(kind) = scanned {
+            violations.push(kind);
+            return violations;
+        }
 
-        let directory = file_path.parent().unwrap().to_owned();
+        if found_index.len() != self.index.len() {
+            violations.push(ViolationKind::BlockCountMismatch {
+                expected: self.index.len(),
+                found: found_index.len(),
+            });
+        }
 
-        let mut bloom = Bloom::new(BLOOM_CAPACITY);
+        for (block, (&expected, &found)) in self.index.iter().zip(found_index.iter()).enumerate() {
+            if expected != found {
+                violations.push(ViolationKind::IndexMismatch {
+                    block,
+                    expected,
+                    found,
+                });
+            }
+        }
+
+        violations
+    }
+
+    /// Tells this table apart from every other one the process has, even one with the same file
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Every key but the range deletes', read from the blocks
+    pub fn point_keys(&self) -> Vec<i32> {
+        self.commands(0, false)
+            .filter(|command| !matches!(command, Command::DeleteRange(..)))
+            .map(|command| command.key())
+            .collect()
+    }
+
+    /// Builds or drops the bloom filter, for when the table moves to a level with a different setting
+    pub fn set_bloom(&mut self, with_bloom: bool) {
+        match (with_bloom, self.bloom.is_some()) {
+            (true, false) => self.set_point_keys(self.point_keys()),
+            (false, true) => self.bloom = None,
+            _ => {}
+        }
+    }
 
-        let file_size = fs::metadata(file_path).unwrap().len();
-        let block_count = file_size.div_ceil(BLOCK_SIZE_BYTES as u64);
+    /// Hands over the table's point keys read ahead of time, so a move into a level with filters
+    /// reads nothing under its write lock. The table's filter is built from them if it has none,
+    /// the level's takes them with `take_pending_keys`.
+    pub fn set_point_keys(&mut self, keys: Vec<i32>) {
+        if self.bloom.is_none() {
+            self.bloom = Some(Bloom::from_keys(&keys));
+        }
+        self.pending_keys = Some(keys);
+        self.appended_to = None;
+    }
 
-        let mut index = Vec::with_capacity(block_count as usize);
+    pub fn has_pending_keys(&self) -> bool {
+        self.pending_keys.is_some()
+    }
 
-        let table_view = TableView::new(file_path.to_path_buf(), 0);
+    /// The point keys the table was built or read back with, and the id of the table it extends if
+    /// they're only the appended ones. None once taken.
+    pub fn take_pending_keys(&mut self) -> Option<(Vec<i32>, Option<u64>)> {
+        let keys = self.pending_keys.take()?;
+        Some((keys, self.appended_to))
+    }
 
-        for block_ptr in table_view {
-            let mut block_iter = unsafe { &*block_ptr }.iter();
+    /// Reads a table back from its file, rebuilding everything kept in memory. Fails on a name,
+    /// header or block that can't be read, except for a torn block an unrenamed append left behind.
+    pub fn create_from_existing(
+        storage: &Arc<dyn Storage>,
+        file_path: &Path,
+        options: TableOptions,
+    ) -> Result<Self, ViolationKind> {
+        let (min_key, max_key, named_by_sequence) = file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_file_name)
+            .ok_or(ViolationKind::BadFileName)?;
 
-            let first = block_iter.next().unwrap();
-            let mut last = first;
-            bloom.put(first.key());
+        let sequence =
+            check_header(storage.as_ref(), file_path).map_err(ViolationKind::BadHeader)?;
+        NEXT_SEQUENCE.fetch_max(sequence + 1, AtomicOrdering::Relaxed);
 
-            while let Some(command) = block_iter.next() {
-                last = command;
-                bloom.put(command.key());
-            }
+        let directory = file_path.parent().unwrap().to_owned();
 
-            index.push((first.key(), last.key()));
+        let mut bloom_keys = options.bloom_keys();
+        let mut key_index = options.key_index();
+        let mut range_deletes = vec![];
+
+        let mut file_size = storage.len(file_path).unwrap();
+        let mut index = vec![];
+        let scanned = scan_blocks(
+            storage,
+            file_path,
+            file_size,
+            &mut index,
+            |block, command| {
+                if let Some(key_index) = &mut key_index {
+                    key_index.push(block, command);
+                }
+                match command {
+                    // appended by a merge that crashed before the rename, not part of the table
+                    _ if command.key() > max_key => {}
+                    Command::DeleteRange(start, end) => range_deletes.push((start, end)),
+                    _ => {
+                        if let Some(bloom_keys) = &mut bloom_keys {
+                            bloom_keys.push(command.key())
+                        }
+                    }
+                }
+            },
+        );
+        if let Err(kind) = scanned {
+            if index.last().is_none_or(|&(_, end)| end < max_key) {
+                return Err(kind);
+            }
+        }
+        let blocks = index.partition_point(|&(min_key, _)| min_key <= max_key);
+        if blocks < index.len() {
+            index.truncate(blocks);
+            if let Some(key_index) = &mut key_index {
+                key_index.truncate(blocks);
+            }
+            file_size = (TABLE_HEADER_BYTES + blocks * BLOCK_SIZE_BYTES) as u64;
         }
 
-        Table {
+        Ok(Table {
+            storage: storage.clone(),
             directory,
             min_key,
             max_key,
             file_size,
-            bloom,
+            bloom: bloom_keys.as_deref().map(Bloom::from_keys),
             index,
+            key_index,
+            range_deletes,
+            sequence,
+            named_by_sequence,
+            id: NEXT_TABLE_ID.fetch_add(1, AtomicOrdering::Relaxed),
+            pending_keys: bloom_keys,
+            appended_to: None,
+            lease: Arc::default(),
+        })
+    }
+}
+
+/// Min and max key of a table's file name, and whether the name ends in the sequence number
+pub fn parse_file_name(name: &str) -> Option<(i32, i32, bool)> {
+    let parts: Vec<&str> = name.split('_').collect();
+    let (min_key, max_key, named_by_sequence) = match parts[..] {
+        [min_key, max_key] => (min_key, max_key, false),
+        [min_key, max_key, sequence] => {
+            sequence.parse::<u64>().ok()?;
+            (min_key, max_key, true)
+        }
+        _ => return None,
+    };
+    Some((
+        min_key.parse().ok()?,
+        max_key.parse().ok()?,
+        named_by_sequence,
+    ))
+}
+
+/// Reads every block within the first `file_size` bytes of the file, pushing the first/last key
+/// of each block onto `index`. Stops at the first corrupt block, `index` holds the ones before it.
+fn scan_blocks<F: FnMut(usize, Command)>(
+    storage: &Arc<dyn Storage>,
+    file_path: &Path,
+    file_size: u64,
+    index: &mut Vec<(i32, i32)>,
+    mut on_command: F,
+) -> Result<(), ViolationKind> {
+    let block_count = file_size
+        .saturating_sub(TABLE_HEADER_BYTES as u64)
+        .div_ceil(BLOCK_SIZE_BYTES as u64);
+
+    index.reserve(block_count as usize);
+
+    let mut table_view = TableView::new(storage.clone(), file_path.to_path_buf(), 0);
+
+    let mut block_index = 0;
+    while (block_index as u64) < block_count {
+        let Some(block) = table_view.next_block() else {
+            break;
+        };
+        let commands = block
+            .try_commands()
+            .map_err(|error| ViolationKind::CorruptBlock {
+                block: block_index,
+                error,
+            })?;
+
+        // a block of nothing but padding can only be trailing, there's no data past it
+        let (Some(first), Some(last)) = (commands.first(), commands.last()) else {
+            break;
+        };
+        for &command in &commands {
+            on_command(block_index, command);
         }
+
+        index.push((first.key(), last.end_key()));
+        block_index += 1;
     }
+
+    Ok(())
 }
 
 pub struct TableView {
+    storage: Arc<dyn Storage>,
     file_path: PathBuf,
-    file: File,
+    lease: Option<Arc<FileLease>>, // keeps the file around if its table gets dropped meanwhile
+    file: Box<dyn ReadAt>,
+    file_len: usize,
     block_buf: BlockView,
     cur_block: usize,
 }
 
 impl TableView {
-    pub fn new(file_path: PathBuf, cur_block: usize) -> Self {
-        let file = File::open(&file_path).unwrap();
+    pub fn new(storage: Arc<dyn Storage>, file_path: PathBuf, cur_block: usize) -> Self {
+        let file = storage.open(&file_path).unwrap();
+        let file_len = storage.len(&file_path).unwrap() as usize;
 
         Self {
+            storage,
             file_path,
+            lease: None,
             file,
+            file_len,
             block_buf: BlockView::new(),
             cur_block,
         }
     }
 
-    #[cfg(windows)]
+    /// Reads as much of the block as the file holds. A read may come back short anywhere in the
+    /// file, so only the file length says where the last block ends.
     fn read_block(&mut self, index: usize) -> usize {
-        self.file
-            .seek_read(
-                self.block_buf.as_mut_slice(),
-                (index * BLOCK_SIZE_BYTES) as u64,
-            )
-            .unwrap()
+        self.read_span(index, 0..BLOCK_SIZE_BYTES)
     }
 
-    #[cfg(unix)]
-    fn read_block(&mut self, index: usize) -> usize {
-        self.file
-            .read_at(
-                self.block_buf.as_mut_slice(),
-                (index * BLOCK_SIZE_BYTES) as u64,
-            )
-            .unwrap()
+    /// Same for bytes `span` of the block, read into the same place in the buffer
+    fn read_span(&mut self, index: usize, span: Range<usize>) -> usize {
+        let offset = TABLE_HEADER_BYTES + index * BLOCK_SIZE_BYTES + span.start;
+        let want = self.file_len.saturating_sub(offset).min(span.len());
+
+        let buf = &mut self.block_buf.as_mut_slice()[span.start..];
+        let mut bytes_read = 0;
+        while bytes_read < want {
+            match self
+                .file
+                .read_at(&mut buf[bytes_read..want], (offset + bytes_read) as u64)
+                .unwrap()
+            {
+                0 => break, // truncated under us
+                n => bytes_read += n,
+            }
+        }
+        bytes_read
     }
 
     pub fn get_block_at(&mut self, index: usize) -> Option<&BlockView> {
@@ -264,24 +811,528 @@ impl TableView {
 
         if bytes_read < BLOCK_SIZE_BYTES {
             // this must be the last page
-            // sentinel of 0xFF
-            self.block_buf.as_mut_slice()[bytes_read] = 0xFF;
+            // padding, all the way so the block can be copied into another table as is
+            self.block_buf.as_mut_slice()[bytes_read..].fill(0xFF);
         }
 
         Some(&self.block_buf)
     }
 
+    /// Bytes `span` of the block, cut short where the file ends
+    pub fn get_span_of_block(&mut self, index: usize, span: Range<usize>) -> &[u8] {
+        let start = span.start;
+        let bytes_read = self.read_span(index, span);
+        &self.block_buf.as_bytes()[start..start + bytes_read]
+    }
+
     pub fn delete_file(&self) {
-        fs::remove_file(&self.file_path).unwrap();
+        self.storage.remove(&self.file_path).unwrap();
+    }
+
+    /// Reads the next block into the view's buffer. The block is only good until the next call.
+    pub fn next_block(&mut self) -> Option<&BlockView> {
+        self.cur_block += 1;
+        self.get_block_at(self.cur_block - 1)
+    }
+
+    pub fn commands(self) -> TableCommands<fn()> {
+        TableCommands::new(self, usize::MAX, || {})
+    }
+}
+
+/// Every command of a table from some block on. Decodes straight out of the view's block buffer,
+/// reading the next block once the current one runs out.
+pub struct TableCommands<F: FnMut()> {
+    view: TableView,
+    // offset in the current block and commands left in it, None before the first block is read
+    position: Option<(usize, usize)>,
+    end_block: usize, // exclusive
+    on_block: F,
+}
+
+impl<F: FnMut()> TableCommands<F> {
+    pub fn new(view: TableView, end_block: usize, on_block: F) -> Self {
+        Self {
+            view,
+            position: None,
+            end_block,
+            on_block,
+        }
     }
 }
 
-impl Iterator for TableView {
-    type Item = *const BlockView;
+impl<F: FnMut()> Iterator for TableCommands<F> {
+    type Item = Command;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.cur_block += 1;
-        self.get_block_at(self.cur_block - 1)
-            .map(|b| b as *const BlockView)
+        loop {
+            if let Some((offset, remaining @ 1..)) = self.position {
+                if let Some((command, next_offset)) = self.view.block_buf.command_at(offset) {
+                    self.position = Some((next_offset, remaining - 1));
+                    return Some(command);
+                }
+            }
+
+            if self.view.cur_block >= self.end_block {
+                return None;
+            }
+            self.view.next_block()?;
+            (self.on_block)();
+            let count = self.view.block_buf.command_count();
+            self.position = Some((BLOCK_HEADER_BYTES, count));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        io::{self, Write},
+        path::{Path, PathBuf},
+        slice,
+        sync::Arc,
+        time::SystemTime,
+    };
+
+    use super::{
+        block::*, check_header, parse_file_name, HeaderError, Table, TableBuilder, TableOptions,
+        FORMAT_VERSION, TABLE_MAGIC,
+    };
+    use crate::config::{BLOCK_HEADER_BYTES, BLOCK_SIZE_BYTES, TABLE_HEADER_BYTES};
+    use crate::database::{
+        check::ViolationKind,
+        compaction::merge_tables,
+        storage::{FsStorage, MemStorage, ReadAt, Storage},
+    };
+
+    const OPTIONS: TableOptions = TableOptions {
+        bloom: true,
+        key_index_every: 0,
+        write_buffer_bytes: 0,
+    };
+
+    /// A table under `directory` putting each of `keys` (ascending) to itself
+    pub(crate) fn table_of(
+        storage: &Arc<dyn Storage>,
+        directory: &Path,
+        keys: impl IntoIterator<Item = i32>,
+    ) -> Table {
+        table_with(
+            storage,
+            directory,
+            keys.into_iter().map(|key| Command::Put(key, key)),
+        )
+    }
+
+    /// A table under `directory` holding `commands`, which have to be sorted
+    pub(crate) fn table_with(
+        storage: &Arc<dyn Storage>,
+        directory: &Path,
+        commands: impl IntoIterator<Item = Command>,
+    ) -> Table {
+        let mut builder = TableBuilder::new(storage, directory, OPTIONS);
+        let mut block = BlockMut::new();
+        for command in commands {
+            if !block.push_command(command) {
+                builder.insert_block(&block);
+                block.clear();
+                block.push_command(command);
+            }
+        }
+        builder.insert_block(&block);
+        builder.build()
+    }
+
+    /// Every even key in `0..2 * count`, spread over as many blocks as they take
+    fn build_table(storage: &Arc<dyn Storage>, count: i32) -> Table {
+        table_of(storage, Path::new("level1"), (0..2 * count).step_by(2))
+    }
+
+    /// Rewrites the table's file with `corrupt` applied to its bytes
+    fn corrupt_file(table: &Table, corrupt: impl FnOnce(&mut Vec<u8>)) {
+        let path = table.file_path();
+        let mut bytes = vec![0; table.storage.len(&path).unwrap() as usize];
+        let file = table.storage.open(&path).unwrap();
+        assert_eq!(file.read_at(&mut bytes, 0).unwrap(), bytes.len());
+        corrupt(&mut bytes);
+        let mut file = table.storage.reopen(&path, 0).unwrap();
+        file.write_all(&bytes).unwrap();
+        file.flush().unwrap();
+    }
+
+    /// Offset in the file of the `n`th put's key in the first block
+    fn key_offset(n: usize) -> usize {
+        TABLE_HEADER_BYTES + 2 + n * PUT_BYTES + 1
+    }
+
+    fn storage() -> Arc<dyn Storage> {
+        Arc::new(MemStorage::default())
+    }
+
+    #[test]
+    fn intact_table_has_no_violations() {
+        let table = build_table(&storage(), 1000);
+        assert!(table.index.len() > 1);
+        assert!(table.check().is_empty());
+    }
+
+    #[test]
+    fn detects_out_of_order_key() {
+        let table = build_table(&storage(), 1000);
+        corrupt_file(&table, |bytes| {
+            // the third key drops back below the second
+            let at = key_offset(2);
+            bytes[at..at + 4].copy_from_slice(&1i32.to_be_bytes());
+        });
+        let violations = table.check();
+        assert!(
+            violations.iter().any(|v| matches!(
+                v,
+                ViolationKind::OutOfOrderKey {
+                    block: 0,
+                    prev: 2,
+                    key: 1
+                }
+            )),
+            "{violations:?}"
+        );
+    }
+
+    #[test]
+    fn detects_key_outside_table_range() {
+        let table = build_table(&storage(), 1000);
+        corrupt_file(&table, |bytes| {
+            let at = key_offset(0);
+            bytes[at..at + 4].copy_from_slice(&(-5i32).to_be_bytes());
+        });
+        let violations = table.check();
+        assert!(
+            violations
+                .iter()
+                .any(|v| matches!(v, ViolationKind::KeyOutsideTableRange { block: 0, key: -5 })),
+            "{violations:?}"
+        );
+    }
+
+    #[test]
+    fn detects_index_mismatch() {
+        let table = build_table(&storage(), 1000);
+        let last = table.index[0].1;
+        let count = table.index[0].1 as usize / 2;
+        corrupt_file(&table, |bytes| {
+            // still between its neighbours, only the index disagrees
+            let at = key_offset(count);
+            bytes[at..at + 4].copy_from_slice(&(last + 1).to_be_bytes());
+        });
+        let violations = table.check();
+        assert!(
+            violations.iter().any(|v| matches!(
+                v,
+                &ViolationKind::IndexMismatch { block: 0, found, .. } if found == (0, last + 1)
+            )),
+            "{violations:?}"
+        );
+    }
+
+    #[test]
+    fn detects_missing_block() {
+        let table = build_table(&storage(), 1000);
+        let blocks = table.index.len();
+        corrupt_file(&table, |bytes| {
+            bytes.truncate(TABLE_HEADER_BYTES + (blocks - 1) * BLOCK_SIZE_BYTES)
+        });
+        let violations = table.check();
+        assert!(
+            violations.iter().any(|v| matches!(
+                v,
+                &ViolationKind::BlockCountMismatch { expected, found }
+                    if expected == blocks && found == blocks - 1
+            )),
+            "{violations:?}"
+        );
+    }
+
+    #[test]
+    fn detects_bad_tag() {
+        let table = build_table(&storage(), 1000);
+        corrupt_file(&table, |bytes| bytes[key_offset(3) - 1] = 0x7F);
+        let violations = table.check();
+        assert!(
+            matches!(
+                violations[..],
+                [ViolationKind::CorruptBlock {
+                    block: 0,
+                    error: BlockError::BadTag { tag: 0x7F, offset }
+                }] if offset == 2 + 3 * PUT_BYTES
+            ),
+            "{violations:?}"
+        );
+    }
+
+    #[test]
+    fn detects_bad_command_count() {
+        let table = build_table(&storage(), 1000);
+        let count = (table.index[1].1 - table.index[1].0) as usize / 2 + 1;
+        corrupt_file(&table, |bytes| {
+            let at = TABLE_HEADER_BYTES + BLOCK_SIZE_BYTES;
+            bytes[at..at + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+        });
+        let violations = table.check();
+        assert!(
+            matches!(
+                violations[..],
+                [ViolationKind::CorruptBlock {
+                    block: 1,
+                    error: BlockError::BadCount { count: 0xFFFF, found }
+                }] if found == count
+            ),
+            "{violations:?}"
+        );
+    }
+
+    #[test]
+    fn reopening_reports_instead_of_panicking() {
+        let storage = storage();
+        let table = build_table(&storage, 1000);
+        let path = table.file_path();
+
+        corrupt_file(&table, |bytes| bytes[key_offset(0) - 1] = 0x7F);
+        let err = Table::create_from_existing(&storage, &path, OPTIONS).unwrap_err();
+        assert!(matches!(err, ViolationKind::CorruptBlock { block: 0, .. }));
+
+        corrupt_file(&table, |bytes| bytes[0] = b'X');
+        let err = Table::create_from_existing(&storage, &path, OPTIONS).unwrap_err();
+        assert!(matches!(
+            err,
+            ViolationKind::BadHeader(HeaderError::BadMagic)
+        ));
+
+        let renamed = path.with_file_name("not_a_range");
+        storage.rename(&path, &renamed).unwrap();
+        let err = Table::create_from_existing(&storage, &renamed, OPTIONS).unwrap_err();
+        assert!(matches!(err, ViolationKind::BadFileName));
+    }
+
+    #[test]
+    fn runs_share_a_key_range_under_their_sequence_numbers() {
+        let storage = storage();
+        // built under the same name, each renamed out of the next one's way
+        let mut older = build_table(&storage, 1000);
+        older.rename_as_run(Path::new("level1"));
+        let mut newer = build_table(&storage, 1000);
+        newer.rename_as_run(Path::new("level1"));
+        assert_eq!(older.file_name(), format!("0_1998_{}", older.sequence));
+        assert_ne!(older.file_name(), newer.file_name());
+
+        let reopened = Table::create_from_existing(&storage, &newer.file_path(), OPTIONS).unwrap();
+        assert_eq!(reopened.file_name(), newer.file_name());
+        assert_eq!(parse_file_name("0_1998_x"), None);
+        assert_eq!(parse_file_name("0_1998"), Some((0, 1998, false)));
+    }
+
+    #[test]
+    fn rejects_tables_of_other_format_versions() {
+        let storage = storage();
+        let table = build_table(&storage, 1000);
+        let path = table.file_path();
+        assert!(Table::create_from_existing(&storage, &path, OPTIONS).is_ok());
+
+        for version in [FORMAT_VERSION + 1, FORMAT_VERSION - 1] {
+            corrupt_file(&table, |bytes| bytes[TABLE_MAGIC.len()] = version);
+            let err = Table::create_from_existing(&storage, &path, OPTIONS).unwrap_err();
+            let ViolationKind::BadHeader(HeaderError::UnknownVersion(read)) = err else {
+                panic!("{err}");
+            };
+            assert_eq!(read, version);
+            assert!(
+                err.to_string().contains(&format!("version {version}")),
+                "{err}"
+            );
+            assert!(check_header(storage.as_ref(), &path).is_err());
+        }
+    }
+
+    /// Small enough to run under Miri, see the README
+    #[test]
+    fn table_iteration() {
+        let storage = storage();
+        let directory = Path::new("level1");
+        let newer = table_with(
+            &storage,
+            directory,
+            (0..600).map(|key| Command::Put(key, key)),
+        );
+        let older = table_with(
+            &storage,
+            directory,
+            (0..1200).step_by(2).map(|key| Command::Put(key, -key)),
+        );
+        assert!(newer.index.len() > 1);
+
+        let keys: Vec<i32> = newer.commands(1, false).map(|c| c.key()).collect();
+        let first_block = (BLOCK_SIZE_BYTES - BLOCK_HEADER_BYTES) / PUT_BYTES;
+        assert_eq!(keys, (first_block as i32..600).collect::<Vec<_>>());
+
+        let mut view = older.view();
+        let mut blocks = 0;
+        while let Some(block) = view.next_block() {
+            assert_eq!(block.iter().count(), block.command_count());
+            blocks += 1;
+        }
+        assert_eq!(blocks, older.index.len());
+
+        let options = TableOptions {
+            bloom: true,
+            key_index_every: 4,
+            write_buffer_bytes: 0,
+        };
+        let merged = merge_tables(&[newer], &[older], &storage, Path::new("level2"), options);
+        let merged: Vec<Command> = merged.iter().flat_map(|t| t.commands(0, false)).collect();
+        assert_eq!(merged.len(), 900);
+        for command in merged {
+            let key = command.key();
+            let expected = if key < 600 { key } else { -key };
+            assert_eq!(command.value(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn concurrent_builds_share_a_new_level_directory() {
+        let storage: Arc<dyn Storage> = Arc::new(FsStorage);
+        let directory = crate::database::tests::scratch_directory("concurrent-level");
+        let level = directory.join("level3");
+        let tables: Vec<Table> = std::thread::scope(|scope| {
+            let builds: Vec<_> = (0..8)
+                .map(|t| {
+                    let (storage, level) = (&storage, &level);
+                    scope.spawn(move || table_of(storage, level, t * 100..(t + 1) * 100))
+                })
+                .collect();
+            builds.into_iter().map(|b| b.join().unwrap()).collect()
+        });
+        assert_eq!(storage.read_dir(&level).unwrap().len(), 8);
+        for table in tables {
+            assert_eq!(table.commands(0, false).count(), 100);
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn table_lifecycle_over_in_memory_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let (level1, level2) = (Path::new("data/level1"), Path::new("data/level2"));
+        let mut table = table_of(&storage, level1, 0..3000);
+        let path = table.file_path();
+        assert_eq!(storage.read_dir(level1).unwrap(), slice::from_ref(&path));
+        storage.sync(&path).unwrap();
+
+        let reopened = Table::create_from_existing(&storage, &path, OPTIONS).unwrap();
+        assert_eq!(reopened.index, table.index);
+        assert!(reopened.check().is_empty());
+        drop(reopened);
+
+        table.rename(level2);
+        assert!(storage.read_dir(level1).unwrap().is_empty());
+        assert_eq!(storage.read_dir(level2).unwrap(), [table.file_path()]);
+        let keys: Vec<i32> = table.commands(0, false).map(|c| c.key()).collect();
+        assert_eq!(keys, (0..3000).collect::<Vec<_>>());
+
+        table.delete_file();
+        assert!(storage.read_dir(level2).unwrap().is_empty());
+        assert!(storage.open(&table.file_path()).is_err());
+    }
+
+    #[test]
+    fn trailing_padding_block_and_empty_blocks_are_skipped() {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let mut builder = TableBuilder::new(&storage, Path::new("level1"), OPTIONS);
+        let mut block = BlockMut::new();
+        builder.insert_block(&block);
+        for key in 0..10 {
+            block.push_command(Command::Put(key, key));
+        }
+        builder.insert_block(&block);
+        block.clear();
+        builder.insert_block(&block);
+        let table = builder.build();
+        assert_eq!(table.index, [(0, 9)]);
+
+        corrupt_file(&table, |bytes| bytes.extend([0xFF; BLOCK_SIZE_BYTES]));
+        let recovered = Table::create_from_existing(&storage, &table.file_path(), OPTIONS).unwrap();
+        assert_eq!(recovered.index, table.index);
+        let keys: Vec<i32> = recovered.commands(0, false).map(|c| c.key()).collect();
+        assert_eq!(keys, (0..10).collect::<Vec<_>>());
+    }
+
+    /// Hands out at most 100 bytes per read, like a filesystem allowed to return short reads
+    #[derive(Debug, Default)]
+    struct ShortReadStorage {
+        inner: MemStorage,
+    }
+
+    struct ShortReader(Box<dyn ReadAt>);
+
+    impl ReadAt for ShortReader {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let len = buf.len().min(100);
+            self.0.read_at(&mut buf[..len], offset)
+        }
+    }
+
+    impl Storage for ShortReadStorage {
+        fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + Sync>> {
+            self.inner.create(path)
+        }
+        fn reopen(&self, path: &Path, len: u64) -> io::Result<Box<dyn Write + Send + Sync>> {
+            self.inner.reopen(path, len)
+        }
+        fn open(&self, path: &Path) -> io::Result<Box<dyn ReadAt>> {
+            Ok(Box::new(ShortReader(self.inner.open(path)?)))
+        }
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.rename(from, to)
+        }
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove(path)
+        }
+        fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            self.inner.read_dir(dir)
+        }
+        fn len(&self, path: &Path) -> io::Result<u64> {
+            self.inner.len(path)
+        }
+        fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+            self.inner.modified(path)
+        }
+        fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+            self.inner.create_dir_all(dir)
+        }
+        fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+            self.inner.remove_dir_all(dir)
+        }
+        fn sync(&self, path: &Path) -> io::Result<()> {
+            self.inner.sync(path)
+        }
+    }
+
+    #[test]
+    fn short_reads_still_fill_whole_blocks() {
+        let storage: Arc<dyn Storage> = Arc::new(ShortReadStorage::default());
+        let table = table_of(&storage, Path::new("level1"), 0..3000);
+        assert!(table.index.len() > 2);
+
+        let recovered = Table::create_from_existing(&storage, &table.file_path(), OPTIONS).unwrap();
+        assert_eq!(recovered.index, table.index);
+        assert!(recovered.check().is_empty());
+        let keys: Vec<i32> = recovered.commands(0, false).map(|c| c.key()).collect();
+        assert_eq!(keys, (0..3000).collect::<Vec<_>>());
+
+        let mut view = recovered.view();
+        let mut blocks = 0;
+        while let Some(block) = view.next_block() {
+            assert_eq!(block.iter().count(), block.command_count());
+            blocks += 1;
+        }
+        assert_eq!(blocks, table.index.len());
     }
 }
@@ -0,0 +1,133 @@
+use std::{
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bytes::BytesMut;
+
+use super::{storage::Storage, table::block::Command};
+
+/// Write-ahead log of the memory level, one segment file per memtable generation.
+///
+/// Writes go to the active segment. When the memory level is swapped out for a flush, the active
+/// segment is frozen and a new one started, the frozen ones are only removed once the flushed
+/// table is synced. Recovery replays every segment left, oldest first.
+///
+/// Records are chosen so that replaying them onto a state they already led to changes nothing:
+/// merges are logged as the key's resulting put, or its whole pending operand (`Command::Merge`
+/// holding the total, not the delta). So a segment that outlived its flush, or one replayed
+/// after the level0 file of the same writes, is harmless as long as what's left is a suffix of
+/// the log, which removing segments oldest first keeps true.
+pub struct Wal {
+    storage: Arc<dyn Storage>,
+    directory: PathBuf,
+    active: Mutex<Segment>,
+}
+
+struct Segment {
+    generation: u64,
+    path: PathBuf,
+    writer: Box<dyn Write + Send + Sync>,
+}
+
+impl Wal {
+    /// Segments in the data directory, oldest first
+    pub fn existing_segments(storage: &dyn Storage, data_directory: &Path) -> Vec<(u64, PathBuf)> {
+        let mut segments: Vec<(u64, PathBuf)> = storage
+            .read_dir(&data_directory.join("wal"))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|path| Some((segment_generation(&path)?, path)))
+            .collect();
+        segments.sort();
+        segments
+    }
+
+    /// Records of a segment in the order they were written. A record cut off by a crash (or garbled) ends it.
+    pub fn read_segment(storage: &dyn Storage, path: &Path) -> Vec<Command> {
+        let len = storage.len(path).unwrap() as usize;
+        let mut buf = vec![0; len];
+        let file = storage.open(path).unwrap();
+        let mut read = 0;
+        while read < len {
+            match file.read_at(&mut buf[read..], read as u64).unwrap() {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf.truncate(read);
+
+        let mut records = Cursor::new(buf.as_slice());
+        std::iter::from_fn(|| Command::decode(&mut records).ok().flatten()).collect()
+    }
+
+    /// Starts logging to a new segment numbered after every existing one
+    pub fn create(storage: &Arc<dyn Storage>, data_directory: &Path) -> Self {
+        let directory = data_directory.join("wal");
+        storage.create_dir_all(&directory).unwrap();
+        let generation = Self::existing_segments(storage.as_ref(), data_directory)
+            .last()
+            .map_or(0, |&(generation, _)| generation + 1);
+
+        Self {
+            active: Mutex::new(Segment::create(storage, &directory, generation)),
+            storage: storage.clone(),
+            directory,
+        }
+    }
+
+    pub fn append(&self, command: Command) {
+        let mut record = BytesMut::with_capacity(command.encoded_len());
+        command.encode(&mut record);
+        // one write per record, so a crash can only cut off the last one
+        self.active
+            .lock()
+            .unwrap()
+            .writer
+            .write_all(&record)
+            .unwrap();
+    }
+
+    pub fn sync(&self) {
+        let active = self.active.lock().unwrap();
+        self.storage.sync(&active.path).unwrap();
+    }
+
+    /// Starts a new segment for the next memtable, returns the generation of the one frozen.
+    /// Called under the memory level's write lock, along with taking the memtable out.
+    pub fn freeze(&self) -> u64 {
+        let mut active = self.active.lock().unwrap();
+        let frozen = active.generation;
+        *active = Segment::create(&self.storage, &self.directory, frozen + 1);
+        frozen
+    }
+
+    /// Removes the segments up to and including `generation`, oldest first. Their writes have to
+    /// be durable somewhere else by now.
+    pub fn remove_through(&self, generation: u64) {
+        let segments =
+            Self::existing_segments(self.storage.as_ref(), self.directory.parent().unwrap());
+        for (_, path) in segments.into_iter().take_while(|&(g, _)| g <= generation) {
+            let _ = self.storage.remove(&path);
+        }
+    }
+}
+
+impl Segment {
+    fn create(storage: &Arc<dyn Storage>, directory: &Path, generation: u64) -> Self {
+        let path = directory.join(format!("{generation}.log"));
+        Self {
+            generation,
+            writer: storage.create(&path).unwrap(),
+            path,
+        }
+    }
+}
+
+fn segment_generation(path: &Path) -> Option<u64> {
+    if path.extension()? != "log" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
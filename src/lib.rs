@@ -0,0 +1,9 @@
+//! The database and server behind the `lsm-tree` binary. Tools can also use it as a library, e.g.
+//! `database::compaction` to build tables offline for INGEST.
+
+pub mod client_stats;
+pub mod command;
+pub mod config;
+pub mod connection;
+pub mod database;
+pub mod replication;
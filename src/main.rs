@@ -1,18 +1,11 @@
-mod database;
-use std::sync::Arc;
-
-use client_stats::ClientStats;
-use config::Config;
-use connection::Connection;
-use database::Database;
-use tokio::{net::TcpListener, signal};
+use std::{sync::Arc, time::Duration};
+
+use lsm_tree::{config::Config, connection::Connection, database::Database, replication::Replica};
+use tokio::{net::TcpListener, signal, time};
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 
-mod client_stats;
-mod command;
-mod config;
-mod connection;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() {
@@ -20,7 +13,11 @@ async fn main() {
 
     // Starts up the database
     // If the data directory has contents at startup, reconstructs bloom filters and fence pointers for each file
-    let db = Arc::new(Database::new(config.data_dir));
+    let db = Arc::new(if config.read_only {
+        Database::open_read_only(config.clone())
+    } else {
+        Database::new(config.clone())
+    });
 
     // Starts up the server on localhost
     let listener = TcpListener::bind(("0.0.0.0", config.port)).await.unwrap();
@@ -42,6 +39,73 @@ async fn main() {
         }
     });
 
+    // Compacts in the background once clients go quiet
+    if let Some(idle_window) = config.idle_compaction {
+        let db_clone = db.clone();
+        let cloned_token = token.clone();
+        tracker.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = time::sleep(idle_window) => db_clone.compact_while_idle(idle_window).await,
+                    _ = cloned_token.cancelled() => break,
+                }
+            }
+        });
+    }
+
+    // Runs the compactions flushes leave behind, several at once on disjoint levels
+    if config.compaction_concurrency.is_some() {
+        let db_clone = db.clone();
+        let cloned_token = token.clone();
+        tracker.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = db_clone.compaction_needed() => db_clone.run_pending_compactions().await,
+                    _ = cloned_token.cancelled() => break,
+                }
+            }
+        });
+    }
+
+    // Removes files left behind by unfinished builds and compactions
+    if let Some(sweep_interval) = config.sweep_interval {
+        let db_clone = db.clone();
+        let cloned_token = token.clone();
+        tracker.spawn(async move {
+            tokio::select! {
+                _ = db_clone.sweep_orphans_every(sweep_interval) => {}
+                _ = cloned_token.cancelled() => {}
+            }
+        });
+    }
+
+    // Flushes the memory level once it's held writes too long, even under capacity
+    if let Some(max_age) = config.max_mem_age.filter(|_| !config.read_only) {
+        let db_clone = db.clone();
+        let cloned_token = token.clone();
+        tracker.spawn(async move {
+            tokio::select! {
+                _ = db_clone.flush_when_stale(max_age) => {}
+                _ = cloned_token.cancelled() => {}
+            }
+        });
+    }
+
+    // Applies the primary's writes as they happen, clients only get to read. Not while read-only,
+    // there'd be no way to save how far it got.
+    let replica = config
+        .replica_of
+        .filter(|_| !config.read_only)
+        .map(|primary| Arc::new(Replica::new(primary, &db)));
+    if let Some(replica) = replica.clone() {
+        let db_clone = db.clone();
+        let cloned_token = token.clone();
+        tracker.spawn(async move {
+            println!("Replicating from {}", replica.primary());
+            replica.follow(&db_clone, cloned_token).await;
+        });
+    }
+
     // Repeatedly accept incoming client connections
     loop {
         tokio::select! {
@@ -50,7 +114,7 @@ async fn main() {
                 let db_clone = db.clone();
                 let cloned_token = token.clone();
 
-                let mut connnection = Connection::new(stream, client, cloned_token);
+                let mut connnection = Connection::new(stream, client, cloned_token, &config);
 
                 // Tokio will make each connection concurrent
                 tracker.spawn(async move {
@@ -71,7 +135,10 @@ async fn main() {
     // Wait for everything to finish.
     tracker.wait().await;
 
+    // Every connection task held a clone, give any that somehow outlived the tracker a moment.
     // Level0 is in memory => save contents of level 0 to a level0 folder in database for retrieval on startup
-    let db: Database = unsafe { Arc::try_unwrap(db).unwrap_unchecked() };
-    db.cleanup();
+    db.shutdown(SHUTDOWN_TIMEOUT).await;
+    if let Some(replica) = replica {
+        replica.save_position(&db).await;
+    }
 }
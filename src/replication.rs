@@ -0,0 +1,328 @@
+use std::{fmt::Display, net::SocketAddr, sync::Mutex, time::Duration};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpStream},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::database::{changes::Change, table::block::Command, Database};
+
+// Wait between losing the primary and connecting again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// Tries at saving the position on shutdown before giving up on it
+const SAVE_ATTEMPTS: u32 = 5;
+
+/// Why a replica stopped following its primary
+#[derive(Debug)]
+pub enum ReplicationError {
+    Io(io::Error),
+    Gap(u64), // changes the primary no longer has, or never published
+    PrimaryRestarted,
+}
+
+impl Display for ReplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Gap(missed) => write!(f, "missed {missed} changes, copy the primary again"),
+            Self::PrimaryRestarted => {
+                write!(
+                    f,
+                    "primary restarted since the last change applied, copy it again"
+                )
+            }
+        }
+    }
+}
+
+impl From<io::Error> for ReplicationError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Applies the writes of a primary, in order, through its change feed. Keeps the epoch and
+/// sequence number of the last one applied, to pick up after it on reconnect and, once saved, on
+/// restart.
+pub struct Replica {
+    primary: SocketAddr,
+    position: Mutex<(u64, u64)>, // epoch 0 until the first connection
+}
+
+impl Replica {
+    pub fn new(primary: SocketAddr, db: &Database) -> Self {
+        Self {
+            primary,
+            position: Mutex::new(db.read_replica_position().unwrap_or((0, 0))),
+        }
+    }
+
+    pub fn primary(&self) -> SocketAddr {
+        self.primary
+    }
+
+    /// Follows the primary until the server shuts down, connecting again whenever the connection
+    /// drops. Stops for good if changes went missing, applying what comes after them would leave
+    /// the replica with something the primary never had.
+    pub async fn follow(&self, db: &Database, token: CancellationToken) {
+        while !token.is_cancelled() {
+            match self.stream(db, &token).await {
+                Ok(()) => {}
+                Err(ReplicationError::Io(err)) => {
+                    eprintln!("Lost primary {}: {err}, reconnecting", self.primary);
+                }
+                Err(err) => {
+                    eprintln!("Stopped replicating from {}: {err}", self.primary);
+                    return;
+                }
+            }
+            tokio::select! {
+                _ = time::sleep(RECONNECT_DELAY) => {}
+                _ = token.cancelled() => {}
+            }
+        }
+    }
+
+    /// For restarts to pick up after. Only called after the memory level is saved, so the position
+    /// never gets ahead of the data. A failed save is tried again a few times, after that a restart
+    /// picks up after the position saved before, applying what came since a second time.
+    pub async fn save_position(&self, db: &Database) {
+        let (epoch, sequence) = *self.position.lock().unwrap();
+        if epoch == 0 {
+            return;
+        }
+        for attempt in 1..=SAVE_ATTEMPTS {
+            match db.save_replica_position(epoch, sequence) {
+                Ok(()) => return,
+                Err(err) if attempt < SAVE_ATTEMPTS => {
+                    eprintln!("Could not save the replica position: {err}, retrying");
+                    time::sleep(RECONNECT_DELAY).await;
+                }
+                Err(err) => eprintln!("Gave up saving the replica position: {err}"),
+            }
+        }
+    }
+
+    /// Subscribes from the change after the last one applied and applies them as they come. A
+    /// change is never cut short by shutdown, so the position always matches what was applied.
+    async fn stream(
+        &self,
+        db: &Database,
+        token: &CancellationToken,
+    ) -> Result<(), ReplicationError> {
+        let (epoch, applied) = *self.position.lock().unwrap();
+        let stream = TcpStream::connect(self.primary).await?;
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        write.write_u8(b'u').await?;
+        write.write_u64(applied + 1).await?;
+        write.flush().await?;
+
+        let mut frame = vec![];
+        let greeting = read_frame(&mut reader, &mut frame).await?;
+        let Some(primary_epoch) = greeting
+            .strip_prefix("OK ")
+            .and_then(|primary_epoch| primary_epoch.parse().ok())
+        else {
+            return Err(invalid_frame(greeting));
+        };
+        if epoch != 0 && epoch != primary_epoch {
+            return Err(ReplicationError::PrimaryRestarted);
+        }
+        if epoch == 0 {
+            *self.position.lock().unwrap() = (primary_epoch, applied);
+        }
+
+        loop {
+            let text = tokio::select! {
+                text = read_frame(&mut reader, &mut frame) => text?,
+                _ = token.cancelled() => return Ok(()),
+            };
+            // the primary shutting down
+            if text.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            if let Some(missed) = text.strip_prefix("GAP ") {
+                return Err(ReplicationError::Gap(missed.parse().unwrap_or(0)));
+            }
+            let change: Change = text.parse().map_err(|_| invalid_frame(text))?;
+
+            let applied = self.position.lock().unwrap().1;
+            if change.sequence <= applied {
+                continue;
+            }
+            if change.sequence != applied + 1 {
+                // numbered but never published, the primary's skip list writes
+                return Err(ReplicationError::Gap(change.sequence - applied - 1));
+            }
+            apply(db, change.command).await;
+            self.position.lock().unwrap().1 = change.sequence;
+        }
+    }
+}
+
+/// Next 0x00-terminated frame, without the delimiter. Fails if the connection ends before one.
+async fn read_frame<'a>(
+    reader: &mut BufReader<OwnedReadHalf>,
+    frame: &'a mut Vec<u8>,
+) -> io::Result<&'a str> {
+    frame.clear();
+    reader.read_until(0x00, frame).await?;
+    if frame.pop() != Some(0x00) {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    std::str::from_utf8(frame).map_err(|_| io::ErrorKind::InvalidData.into())
+}
+
+fn invalid_frame(text: &str) -> ReplicationError {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected {text:?}")).into()
+}
+
+/// Writes the change the way the primary did, skipping the checks clients' writes go through
+async fn apply(db: &Database, command: Command) {
+    match command {
+        Command::Put(key, val) => db.insert(key, val).await,
+        Command::Delete(key) => db.delete(key).await,
+        Command::DeleteRange(min_key, max_key) => db.delete_range(min_key, max_key).await,
+        Command::Merge(key, delta) => db.merge(key, delta).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::SocketAddr,
+        path::Path,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{net::TcpListener, task::JoinHandle};
+    use tokio_util::sync::CancellationToken;
+
+    use super::Replica;
+    use crate::{
+        command::{CommandError, ERROR_FRAME},
+        config::Config,
+        connection::{
+            tests::{in_memory_config, put, Client},
+            Connection,
+        },
+        database::{tests::scratch_directory, Database},
+    };
+
+    /// Serves `db` to every connection that comes, the way the server does
+    async fn serve(db: Arc<Database>, config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, client) = listener.accept().await.unwrap();
+                let mut connection =
+                    Connection::new(stream, client, CancellationToken::new(), &config);
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let _ = connection.handle(db).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn replica_config(directory: &Path, primary: SocketAddr) -> Config {
+        let args = [
+            "--data-dir",
+            directory.to_str().unwrap(),
+            "--replica-of",
+            &primary.to_string(),
+        ];
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// A replica of `primary` following it in the background until `token` is cancelled
+    fn start_replica(
+        config: &Config,
+        token: &CancellationToken,
+    ) -> (Arc<Database>, Arc<Replica>, JoinHandle<()>) {
+        let db = Arc::new(Database::new(config.clone()));
+        let replica = Arc::new(Replica::new(config.replica_of.unwrap(), &db));
+        let (db_clone, replica_clone, token) = (db.clone(), replica.clone(), token.clone());
+        let following = tokio::spawn(async move { replica_clone.follow(&db_clone, token).await });
+        (db, replica, following)
+    }
+
+    /// Waits for the replica to get to the write of `val` to `key`
+    async fn wait_for(db: &Database, key: i32, val: i32) {
+        let start = Instant::now();
+        while db.get(key).await.0 != Some(val) {
+            assert!(start.elapsed() < Duration::from_secs(5), "never got {key}");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    async fn assert_same(primary: &Database, replica: &Database) {
+        for key in -5..1_005 {
+            assert_eq!(replica.get(key).await.0, primary.get(key).await.0, "{key}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replicas_resume_after_a_restart_without_gaps_or_repeats() {
+        // with a log every write is published, skip list memory level or not
+        let primary_config = in_memory_config(&["--wal"]);
+        let primary = Arc::new(Database::new(primary_config.clone()));
+        for key in 0..100 {
+            primary.insert(key, key).await;
+        }
+        let addr = serve(primary.clone(), primary_config).await;
+        let directory = scratch_directory("replica-restart");
+        let config = replica_config(&directory, addr);
+
+        let token = CancellationToken::new();
+        let (db, replica, following) = start_replica(&config, &token);
+        for _ in 0..3 {
+            primary.merge(5, 1).await;
+        }
+        primary.delete(10).await;
+        primary.delete_range(20, 29).await;
+        primary.insert(1_000, 1).await;
+        wait_for(&db, 1_000, 1).await;
+        assert_same(&primary, &db).await;
+        assert_eq!(db.get(5).await.0, Some(8));
+
+        // clients only get to read from it
+        let mut client = Client::connect(&db, &config).await;
+        let response = client.request(&put(1, 2)).await;
+        assert_eq!(
+            response.as_bytes()[..2],
+            [ERROR_FRAME, CommandError::Replica.code()]
+        );
+        drop(client);
+
+        // shut down the way the server does, while the primary takes more writes
+        token.cancel();
+        following.await.unwrap();
+        db.cleanup().await;
+        replica.save_position(&db).await;
+        drop((db, replica));
+        for key in 100..200 {
+            primary.insert(key, -key).await;
+        }
+        primary.merge(5, 1).await;
+        primary.insert(1_001, 1).await;
+
+        let token = CancellationToken::new();
+        let (db, _replica, following) = start_replica(&config, &token);
+        wait_for(&db, 1_001, 1).await;
+        assert_same(&primary, &db).await;
+        // each merge applied once
+        assert_eq!(db.get(5).await.0, Some(9));
+        token.cancel();
+        following.await.unwrap();
+        drop(db);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}